@@ -1,5 +1,5 @@
 use clap::Parser;
-use std::{collections::HashMap, path::PathBuf};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 pub struct Args {
@@ -19,10 +19,22 @@ pub struct Args {
     #[arg(long, value_name = "FILEPATH", default_value = "/etc/systable.json")]
     pub sys_table: PathBuf,
 
+    /// JSON file of custom bands added to, or replacing, the bands derived from the System Table
+    #[arg(long, value_name = "FILEPATH")]
+    pub bands: Option<PathBuf>,
+
+    /// Write System Tables reassembled from received Systable HFNPDUs to this file and load them between sessions
+    #[arg(long, value_name = "FILEPATH")]
+    pub systable_update: Option<PathBuf>,
+
     /// When enabled, hfdl-autopilot connects to a swarm leader to ensure no duplicated bands
     #[arg(long, default_value_t = false)]
     pub swarm: bool,
 
+    /// Accept swarm members and lease them bands (swarm mode OFF). Requires --api-token
+    #[arg(long, default_value_t = false, conflicts_with = "swarm")]
+    pub swarm_leader: bool,
+
     /// Host to connect to (swarm mode ON) or listen on (swarm mode OFF)
     #[arg(long, value_name = "HOST", default_value = "127.0.0.1")]
     pub host: String,
@@ -31,6 +43,14 @@ pub struct Args {
     #[arg(long, value_name = "PORT", default_value_t = 7270)]
     pub port: u16,
 
+    /// Name reported to the swarm leader (swarm mode ON)
+    #[arg(long, value_name = "NAME", requires = "swarm")]
+    pub swarm_name: Option<String>,
+
+    /// Seconds between heartbeats sent to the swarm leader. Set on the leader, members that miss 3 heartbeats lose their band lease
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub swarm_heartbeat: u64,
+
     /// Timeout in seconds before SPDU timeout and active frequencies are considered stale
     #[arg(long, value_name = "SECONDS", default_value_t = 900)]
     pub spdu_timeout: u64,
@@ -39,10 +59,58 @@ pub struct Args {
     #[arg(long, value_name = "SECONDS", default_value_t = 1800)]
     pub ac_timeout: u64,
 
+    /// Size in seconds of the windows frames are counted in for /api/history
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    pub history_bucket: u64,
+
+    /// Days of frame counts kept for /api/history
+    #[arg(long, value_name = "DAYS", default_value_t = 7)]
+    pub history_retention: u64,
+
     /// Seconds to wait after killing dumphfdl. Useful for letting SDRplay drivers perform clean up after a session ends
     #[arg(long, value_name = "SECONDS", default_value_t = 0)]
     pub end_session_wait: u64,
 
+    /// Seconds to wait for dumphfdl to exit after SIGTERM during shutdown before killing it
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub shutdown_timeout: u64,
+
+    /// Write a JSON snapshot of the session, ground station, frequency and flight state to this file on shutdown
+    #[arg(long, value_name = "FILEPATH")]
+    pub state_dump: Option<PathBuf>,
+
+    /// SQLite database state is saved to and restored from across restarts, along with session and ground station activity history
+    #[arg(long, value_name = "FILEPATH", conflicts_with = "replay")]
+    pub store: Option<PathBuf>,
+
+    /// Consecutive dumphfdl failures tolerated before giving up (0 restarts forever)
+    #[arg(long, value_name = "COUNT", default_value_t = 10)]
+    pub max_child_failures: u32,
+
+    /// Initial delay in seconds before restarting a failed dumphfdl, doubled on each consecutive failure
+    #[arg(long, value_name = "SECONDS", default_value_t = 2)]
+    pub restart_backoff_min: u64,
+
+    /// Maximum delay in seconds before restarting a failed dumphfdl
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    pub restart_backoff_max: u64,
+
+    /// Random jitter applied to restart delays as a fraction of the delay (0.0 - 1.0)
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.2)]
+    pub restart_backoff_jitter: f64,
+
+    /// Seconds a dumphfdl session must run before consecutive failures are forgotten
+    #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+    pub healthy_session: u64,
+
+    /// Log level used when relaying dumphfdl stderr output (off, error, warn, info, debug, trace)
+    #[arg(long, value_name = "LEVEL", default_value = "warn")]
+    pub stderr_level: String,
+
+    /// Number of dumphfdl stderr lines kept per session
+    #[arg(long, value_name = "LINES", default_value_t = 64)]
+    pub stderr_lines: usize,
+
     /// Timeout in seconds to wait before switching HF bands
     #[arg(short, long, value_name = "SECONDS", default_value_t = 150)]
     pub timeout: u32,
@@ -55,32 +123,58 @@ pub struct Args {
     )]
     pub chooser: String,
 
-    pub additional_args: Vec<String>,
-}
+    /// Receiver location as LAT,LON in decimal degrees, used by location aware choosers
+    #[arg(long, value_name = "LAT,LON", allow_hyphen_values = true)]
+    pub location: Option<String>,
+
+    /// SDR the sample rate is picked for (airspyhf, rspdx, rtlsdr or soapy)
+    #[arg(long, value_name = "PROFILE", default_value = "soapy")]
+    pub sdr: String,
+
+    /// Highest sample rate (Hz) to use, below the SDR profile's own limits. Bands needing more are narrowed to their most active frequencies
+    #[arg(long, value_name = "HZ")]
+    pub max_sample_rate: Option<u32>,
+
+    /// Pass dumphfdl an explicit --centerfreq halfway between the frequencies listened to
+    #[arg(long)]
+    pub center_freq: bool,
 
-impl Args {
-    pub fn chooser_params(&self) -> (&str, HashMap<&str, &str>) {
-        let mut props: HashMap<&str, &str> = HashMap::new();
+    /// Manage a receiver: NAME[;chooser=SPEC][;sdr=PROFILE][;max-sample-rate=HZ][;args=DUMPHFDL ARGS]. May be repeated. Missing settings default to --chooser, --sdr, --max-sample-rate and the trailing dumphfdl arguments
+    #[arg(long, value_name = "SPEC", conflicts_with_all = ["attach", "replay"])]
+    pub receiver: Vec<String>,
 
-        let delim = match self.chooser.find(":") {
-            Some(val) => val,
-            None => return (&self.chooser, props),
-        };
+    /// Bearer token required by the control and swarm APIs; the control API is disabled without one. Swarm members send it to the leader
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "HFDL_API_TOKEN",
+        hide_env_values = true
+    )]
+    pub api_token: Option<String>,
+
+    /// Ingest JSON from a dumphfdl managed elsewhere instead of running it (tcp://HOST:PORT, tcp-listen://HOST:PORT or udp://HOST:PORT)
+    #[arg(long, value_name = "URL", conflicts_with = "replay")]
+    pub attach: Option<String>,
 
-        let name = &self.chooser[..delim];
+    /// Command invoked with the chosen band's frequencies when attached to an external dumphfdl
+    #[arg(long, value_name = "FILEPATH", requires = "attach")]
+    pub retune_cmd: Option<PathBuf>,
 
-        for kv in self.chooser[(delim + 1)..].split(",") {
-            let delim = match kv.find("=") {
-                Some(val) => val,
-                None => {
-                    props.insert(kv, "");
-                    continue;
-                }
-            };
+    /// Re-publish decoded frames to stdout, tcp://HOST:PORT, udp://HOST:PORT or file://PATH[?max-size=SIZE][&rotate=hourly|daily]. May be repeated (default: stdout)
+    #[arg(long, value_name = "URL")]
+    pub output: Vec<String>,
 
-            props.insert(&kv[..delim], &kv[(delim + 1)..]);
-        }
+    /// Frames queued per output before further frames are dropped for it
+    #[arg(long, value_name = "FRAMES", default_value_t = 1024)]
+    pub output_queue: usize,
 
-        (name, props)
-    }
+    /// Replay recorded dumphfdl JSON output (optionally gzipped) instead of running dumphfdl. May be repeated
+    #[arg(long, value_name = "FILEPATH")]
+    pub replay: Vec<PathBuf>,
+
+    /// Replay speed as a multiple of real time (1, 10, ...) or "max"
+    #[arg(long, value_name = "SPEED", default_value = "1")]
+    pub replay_speed: String,
+
+    pub additional_args: Vec<String>,
 }
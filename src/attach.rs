@@ -0,0 +1,442 @@
+use crate::chooser::{self, ChooserPlugin};
+use crate::config::Config;
+use crate::control::{Action, Control};
+use crate::pipeline::Outcome;
+use crate::retune::RetuneHook;
+use crate::sink::Outputs;
+use crate::state::SharedState;
+use crate::supervisor::Supervisor;
+use crate::{pipeline, retune, shutdown};
+use log::*;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::time;
+
+/// Where an externally managed dumphfdl publishes its decoded JSON output
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    /// Connect to a TCP server relaying dumphfdl JSON
+    TcpConnect(String),
+
+    /// Accept connections from dumphfdl's decoded:json:tcp output
+    TcpListen(String),
+
+    /// Receive datagrams from dumphfdl's decoded:json:udp output
+    Udp(String),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let delim = match s.find("://") {
+            Some(val) => val,
+            None => return Err(format!("Missing scheme in attach endpoint: {}", s)),
+        };
+
+        let address = s[(delim + 3)..].to_string();
+        if address.rsplit_once(':').is_none() {
+            return Err(format!("Missing port in attach endpoint: {}", s));
+        }
+
+        match &s[..delim] {
+            "tcp" => Ok(Endpoint::TcpConnect(address)),
+            "tcp-listen" => Ok(Endpoint::TcpListen(address)),
+            "udp" => Ok(Endpoint::Udp(address)),
+            scheme => Err(format!(
+                "Unsupported attach scheme '{}': expected tcp, tcp-listen or udp",
+                scheme
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::TcpConnect(address) => write!(f, "tcp://{}", address),
+            Endpoint::TcpListen(address) => write!(f, "tcp-listen://{}", address),
+            Endpoint::Udp(address) => write!(f, "udp://{}", address),
+        }
+    }
+}
+
+enum Source {
+    Tcp(Lines<BufReader<TcpStream>>),
+    Udp(UdpSocket, VecDeque<String>),
+}
+
+impl Source {
+    async fn open(endpoint: &Endpoint, listener: &mut Option<TcpListener>) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::TcpConnect(address) => Ok(Source::Tcp(
+                BufReader::new(TcpStream::connect(address).await?).lines(),
+            )),
+            Endpoint::TcpListen(address) => {
+                if listener.is_none() {
+                    *listener = Some(TcpListener::bind(address).await?);
+                }
+
+                let (stream, peer) = listener.as_ref().unwrap().accept().await?;
+                info!("ATTACH: accepted dumphfdl connection from {}", peer);
+
+                Ok(Source::Tcp(BufReader::new(stream).lines()))
+            }
+            Endpoint::Udp(address) => Ok(Source::Udp(
+                UdpSocket::bind(address).await?,
+                VecDeque::new(),
+            )),
+        }
+    }
+
+    /// Returns the next line of JSON output or None when the stream has been closed. Cancel safe, so a partially
+    /// read line is kept when polled from select! or a timeout
+    async fn next_line(&mut self) -> io::Result<Option<String>> {
+        match self {
+            Source::Tcp(lines) => lines.next_line().await,
+            Source::Udp(socket, pending) => {
+                let mut buf = vec![0u8; 65536];
+
+                while pending.is_empty() {
+                    let size = socket.recv(&mut buf).await?;
+                    pending.extend(
+                        String::from_utf8_lossy(&buf[..size])
+                            .lines()
+                            .filter(|x| !x.trim().is_empty())
+                            .map(|x| x.to_string()),
+                    );
+                }
+
+                Ok(pending.pop_front())
+            }
+        }
+    }
+}
+
+async fn choose(
+    config: &Config,
+    plugin: &mut dyn ChooserPlugin,
+    control: &mut Control,
+    shared_state: &mut SharedState,
+    hook: &mut dyn RetuneHook,
+) -> bool {
+    let band = match control.choose(config, plugin).await {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Failed to choose a frequency band to listen to: {}", e);
+            return false;
+        }
+    };
+
+    let tuning = match shared_state.tune(&config.sdr(), &band) {
+        Some(val) => val,
+        None => {
+            error!("Bandwidth calculation failed: {:?}", band);
+            return false;
+        }
+    };
+
+    let band_id = shared_state.band_of(&band).unwrap_or(0);
+    shared_state.update_current_band(band_id, &tuning.freqs);
+    hook.retune(band_id, &tuning);
+
+    true
+}
+
+/// Ingests frames from a dumphfdl managed elsewhere. The chooser runs in advisory mode and band changes are
+/// delegated to the configured retune hook
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    config: &Config,
+    endpoint: &Endpoint,
+    shared_state: &mut SharedState,
+    plugin: &mut dyn ChooserPlugin,
+    control: &mut Control,
+    supervisor: &mut Supervisor,
+    outputs: &Outputs,
+    name: &str,
+    shutdown_rx: &mut watch::Receiver<bool>,
+) -> io::Result<Outcome> {
+    let mut hook = retune::get(config);
+    let mut listener: Option<TcpListener> = None;
+
+    let timeout = Duration::from_secs(config.timeout as u64);
+
+    info!("ATTACH: ingesting dumphfdl output from {}", endpoint);
+
+    if !choose(config, plugin, control, shared_state, hook.as_mut()).await {
+        return Ok(Outcome::Exit);
+    }
+
+    while !shutdown::is_requested(shutdown_rx) {
+        let mut source = tokio::select! {
+            _ = shutdown::requested(shutdown_rx) => break,
+            source = Source::open(endpoint, &mut listener) => source,
+        };
+
+        let connected_at = Instant::now();
+
+        match source {
+            Ok(ref mut source) => {
+                let mut last_cleanup = Instant::now();
+
+                loop {
+                    let results = tokio::select! {
+                        _ = shutdown::requested(shutdown_rx) => break,
+                        event = control.recv() => {
+                            let band_id = shared_state.session.read().unwrap().band();
+                            match control.handle(event, config, shared_state, band_id) {
+                                Action::Continue => {}
+                                Action::EndSession(reason) => {
+                                    shared_state.on_switch(name, reason);
+                                    choose(config, plugin, control, shared_state, hook.as_mut()).await;
+                                }
+                                Action::SwapChooser(spec) => return Ok(Outcome::SwapChooser(spec)),
+                            }
+                            continue;
+                        }
+                        results = time::timeout(timeout, source.next_line()) => results,
+                    };
+
+                    match results {
+                        Ok(Ok(Some(msg))) => {
+                            let frame = match pipeline::decode(&msg) {
+                                Some(val) => val,
+                                None => continue,
+                            };
+
+                            outputs.publish(msg.trim());
+                            shared_state.stream.on_frame(msg.trim(), &frame);
+
+                            if pipeline::process(&frame, shared_state, plugin)
+                                && !control.is_pinned()
+                            {
+                                info!("{} elects to change bands after last HFDL frame.", name);
+                                shared_state.on_switch(name, "frame");
+                                choose(config, plugin, control, shared_state, hook.as_mut()).await;
+                            }
+
+                            if last_cleanup.elapsed().as_secs() >= config.ac_timeout {
+                                shared_state.clean_up();
+                                last_cleanup = Instant::now();
+                            }
+
+                            if let Some(info) = shared_state.systable_update() {
+                                return Ok(Outcome::Reload(info));
+                            }
+                        }
+                        Ok(Ok(None)) => {
+                            error!("ATTACH: {} closed the stream", endpoint);
+                            break;
+                        }
+                        Ok(Err(e)) => {
+                            error!("ATTACH: read error from {}: {}", endpoint, e);
+                            break;
+                        }
+                        Err(_) => {
+                            if chooser::on_timeout(plugin).await && !control.is_pinned() {
+                                info!(
+                                    "Been {}s since last message on band. {} elects to change bands.",
+                                    config.timeout, name
+                                );
+                                shared_state.on_switch(name, "timeout");
+                                choose(config, plugin, control, shared_state, hook.as_mut()).await;
+                            }
+                        }
+                    }
+                }
+            }
+            Err(ref e) => error!("ATTACH: unable to open {}: {}", endpoint, e),
+        }
+
+        if shutdown::is_requested(shutdown_rx) {
+            break;
+        }
+
+        let backoff = match supervisor.on_failure(connected_at.elapsed()) {
+            Some(val) => val,
+            None => {
+                error!(
+                    "ATTACH: giving up on {} after {} consecutive failures",
+                    endpoint,
+                    supervisor.failures()
+                );
+                break;
+            }
+        };
+
+        info!(
+            "ATTACH: reconnecting in {:.1} seconds ({})",
+            backoff.as_secs_f64(),
+            supervisor.attempt()
+        );
+        tokio::select! {
+            _ = shutdown::requested(shutdown_rx) => break,
+            _ = time::sleep(backoff) => {},
+        }
+    }
+
+    Ok(Outcome::Exit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chooser::{self, parse_spec};
+    use crate::{config, control};
+    use tokio::io::AsyncWriteExt;
+
+    fn frame(freq: u32) -> String {
+        format!(
+            r#"{{"hfdl": {{"t": {{"sec": 0, "usec": 0}}, "freq": {}, "bit_rate": 1800, "sig_level": -20.0}}}}"#,
+            freq
+        )
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        assert!(matches!(
+            "tcp://localhost:5555".parse::<Endpoint>(),
+            Ok(Endpoint::TcpConnect(x)) if x == "localhost:5555"
+        ));
+        assert!(matches!(
+            "tcp-listen://0.0.0.0:5555".parse::<Endpoint>(),
+            Ok(Endpoint::TcpListen(x)) if x == "0.0.0.0:5555"
+        ));
+        assert!(matches!(
+            "udp://[::]:5555".parse::<Endpoint>(),
+            Ok(Endpoint::Udp(x)) if x == "[::]:5555"
+        ));
+        assert_eq!(
+            "udp://[::]:5555".parse::<Endpoint>().unwrap().to_string(),
+            "udp://[::]:5555"
+        );
+
+        assert!("localhost:5555".parse::<Endpoint>().is_err());
+        assert!("tcp://localhost".parse::<Endpoint>().is_err());
+        assert!("http://localhost:5555".parse::<Endpoint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn splits_udp_datagrams_into_lines() {
+        let endpoint = Endpoint::Udp("127.0.0.1:0".to_string());
+        let mut source = Source::open(&endpoint, &mut None).await.unwrap();
+        let address = match source {
+            Source::Udp(ref socket, _) => socket.local_addr().unwrap(),
+            Source::Tcp(_) => unreachable!(),
+        };
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .send_to(
+                format!("{}\n\n{}\n", frame(1), frame(2)).as_bytes(),
+                address,
+            )
+            .await
+            .unwrap();
+        sender.send_to(frame(3).as_bytes(), address).await.unwrap();
+
+        for freq in 1..=3 {
+            let line = time::timeout(Duration::from_secs(1), source.next_line())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(line, Some(frame(freq)));
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_partial_tcp_lines_across_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint::TcpConnect(listener.local_addr().unwrap().to_string());
+        let mut listener_slot = None;
+
+        let (source, accepted) = tokio::join!(
+            Source::open(&endpoint, &mut listener_slot),
+            listener.accept()
+        );
+        let mut source = source.unwrap();
+        let (mut stream, _) = accepted.unwrap();
+
+        let line = frame(1);
+        let (head, tail) = line.split_at(20);
+
+        stream.write_all(head.as_bytes()).await.unwrap();
+        assert!(
+            time::timeout(Duration::from_millis(100), source.next_line())
+                .await
+                .is_err()
+        );
+
+        stream
+            .write_all(format!("{}\n", tail).as_bytes())
+            .await
+            .unwrap();
+        drop(stream);
+        assert_eq!(source.next_line().await.unwrap(), Some(line));
+        assert_eq!(source.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reconnects_until_giving_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint::TcpConnect(listener.local_addr().unwrap().to_string());
+
+        // Every connection carries a single frame before the stream is closed
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                stream
+                    .write_all(format!("{}\n", frame(8927000)).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let config = config::test_config(&[
+            "--attach",
+            &endpoint.to_string(),
+            "--max-child-failures",
+            "3",
+            "--restart-backoff-min",
+            "0",
+        ]);
+        let mut shared_state = SharedState::new(&config, "rx");
+        let (_handle, mut controls) = control::channel(&config);
+        let mut supervisor = Supervisor::new(&config);
+        let outputs = Outputs::new(&[], 16);
+
+        let band = config.info.bands.keys().min().unwrap().to_string();
+        let spec = format!("single:band={}", band);
+        let spec = parse_spec(&spec);
+        let mut plugin = chooser::get(&spec, &config, &shared_state.chooser_context()).unwrap();
+
+        let (_tx, mut shutdown_rx) = watch::channel(false);
+        let outcome = time::timeout(
+            Duration::from_secs(10),
+            run(
+                &config,
+                &endpoint,
+                &mut shared_state,
+                plugin.as_mut(),
+                &mut controls[0],
+                &mut supervisor,
+                &outputs,
+                "single",
+                &mut shutdown_rx,
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert!(matches!(outcome, Outcome::Exit));
+        assert_eq!(supervisor.failures(), 3);
+        assert_eq!(*shared_state.freq_stats.get(&8927000).unwrap(), 3);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::state::StderrLog;
+use actix_web::web::Data;
+use log::*;
+use std::io;
+use std::process::ExitStatus;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, ChildStderr};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// Relays dumphfdl stderr line by line into the session stderr log and the application log
+pub fn capture_stderr(
+    stderr: ChildStderr,
+    level: LevelFilter,
+    stderr_log: Data<RwLock<StderrLog>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Some(level) = level.to_level() {
+                        log!(level, "dumphfdl: {}", line.trim_end());
+                    }
+
+                    stderr_log.write().unwrap().push(line);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Read error on dumphfdl stderr: {}", e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Asks dumphfdl to exit with SIGTERM, escalating to SIGKILL if it has not exited after the grace period
+pub async fn terminate(proc: &mut Child, grace: Duration) -> io::Result<ExitStatus> {
+    if let Some(pid) = proc.id() {
+        // SAFETY: pid belongs to a child we spawned and have not reaped yet
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+
+        if let Ok(status) = time::timeout(grace, proc.wait()).await {
+            return status;
+        }
+
+        warn!(
+            "dumphfdl did not exit within {}s of SIGTERM, killing it",
+            grace.as_secs()
+        );
+    }
+
+    proc.kill().await?;
+    proc.wait().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Stdio;
+    use tokio::process::Command;
+
+    #[tokio::test]
+    async fn keeps_the_last_stderr_lines() {
+        let mut proc = Command::new("sh")
+            .args(["-c", "echo one >&2; echo two >&2; echo three >&2"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let stderr_log = Data::new(RwLock::new(StderrLog::new("rx", 2)));
+        capture_stderr(
+            proc.stderr.take().unwrap(),
+            LevelFilter::Off,
+            stderr_log.clone(),
+        )
+        .await
+        .unwrap();
+        proc.wait().await.unwrap();
+
+        assert_eq!(stderr_log.read().unwrap().lines(), &["two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn terminates_with_sigterm_then_sigkill() {
+        let mut proc = Command::new("sleep").arg("30").spawn().unwrap();
+        let status = terminate(&mut proc, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+
+        // Ignores SIGTERM, so it is killed once the grace period is over
+        let mut proc = Command::new("sh")
+            .args(["-c", "trap '' TERM; exec sleep 30"])
+            .spawn()
+            .unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        let status = terminate(&mut proc, Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    }
+}
@@ -0,0 +1,423 @@
+use crate::chooser::{self, ChooserContext, ChooserPlugin, Prepare, Spec};
+use crate::config::{Config, FrequencyBandMap};
+use crate::hfdl::Frame;
+use crate::utils::parse_time;
+use chrono::Timelike;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use log::*;
+use std::time::Instant;
+
+pub const NAME: &str = "composite";
+
+/// How many times a child is asked again when it picks an avoided band
+const AVOID_RETRIES: usize = 3;
+
+/// When a child chooser is asked to pick bands
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    /// Between two local times, in minutes since midnight
+    Window(u32, u32),
+    /// Outside of every window
+    Default,
+    /// When the scheduled child fails to choose
+    Fallback,
+}
+
+/// Frames that keep the current band alive
+enum Keep {
+    Frames,
+    Positions,
+}
+
+impl Keep {
+    fn matches(&self, frame: &Frame) -> bool {
+        match self {
+            Keep::Frames => frame.hfdl.lpdu.is_some(),
+            Keep::Positions => frame
+                .hfdl
+                .lpdu
+                .as_ref()
+                .and_then(|x| x.hfnpdu.as_ref())
+                .is_some_and(|x| x.pos.is_some()),
+        }
+    }
+}
+
+struct Child<'a> {
+    label: &'a str,
+    name: &'a str,
+    role: Role,
+    plugin: Box<dyn ChooserPlugin + 'a>,
+}
+
+/// Parses a HH:MM-HH:MM window into minutes since midnight
+fn parse_window(key: &str) -> Option<(u32, u32)> {
+    let mut minutes: Vec<u32> = vec![];
+
+    for time in key.split('-') {
+        let time: Vec<&str> = time.split(':').collect();
+        if time.len() != 2 {
+            return None;
+        }
+
+        let (h, m) = parse_time(&time)?;
+        minutes.push(h as u32 * 60 + m as u32);
+    }
+
+    match minutes[..] {
+        [start, end] => Some((start, end)),
+        _ => None,
+    }
+}
+
+fn in_window(start: u32, end: u32, now: u32) -> bool {
+    if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+/// First minute of the day outside of every window, if any
+fn uncovered(windows: &[(u32, u32)]) -> Option<u32> {
+    (0..24 * 60).find(|now| {
+        !windows
+            .iter()
+            .any(|(start, end)| in_window(*start, *end, *now))
+    })
+}
+
+pub struct CompositeChooserPlugin<'a> {
+    bands: &'a FrequencyBandMap,
+    children: Vec<Child<'a>>,
+
+    avoid: Vec<u32>,
+    keep: Option<Keep>,
+    keep_for: u64,
+    kept_at: Option<Instant>,
+
+    /// Child that picked the current band, and the child that was scheduled at the time
+    active: usize,
+    scheduled: Option<usize>,
+    current_band: u32,
+}
+
+impl<'a> CompositeChooserPlugin<'a> {
+    pub fn new(
+        config: &'a Config,
+        spec: &'a Spec<'a>,
+        context: &ChooserContext,
+    ) -> Result<Self, String> {
+        let mut children: Vec<Child<'a>> = vec![];
+
+        for (key, child) in spec.children.iter() {
+            let role = match *key {
+                "default" => Role::Default,
+                "fallback" => Role::Fallback,
+                _ => match parse_window(key) {
+                    Some((start, end)) => Role::Window(start, end),
+                    None => return Err(format!("'{}' is not a valid child chooser slot", key)),
+                },
+            };
+
+            let plugin = chooser::get(child, config, context)
+                .map_err(|e| format!("{} ({}): {}", key, child.name, e))?;
+
+            children.push(Child {
+                label: key,
+                name: child.name,
+                role,
+                plugin,
+            });
+        }
+
+        if !children.iter().any(|x| x.role == Role::Default) {
+            let windows: Vec<(u32, u32)> = children
+                .iter()
+                .filter_map(|x| match x.role {
+                    Role::Window(start, end) => Some((start, end)),
+                    _ => None,
+                })
+                .collect();
+
+            if windows.is_empty() {
+                return Err(
+                    "Requires a 'default' or HH:MM-HH:MM child chooser, e.g. default=[rotate]"
+                        .to_string(),
+                );
+            }
+            if let Some(now) = uncovered(&windows) {
+                return Err(format!(
+                    "Windows leave {:02}:{:02} uncovered: requires a 'default' child chooser, e.g. default=[rotate]",
+                    now / 60,
+                    now % 60
+                ));
+            }
+        }
+
+        children.sort_by_key(|x| match x.role {
+            Role::Window(start, _) => (0, start),
+            Role::Default => (1, 0),
+            Role::Fallback => (2, 0),
+        });
+
+        let mut avoid: Vec<u32> = vec![];
+        for band in props_list(spec, "avoid") {
+            match band.parse::<u32>() {
+                Ok(val) if config.info.bands.contains_key(&val) => avoid.push(val),
+                _ => return Err(format!("'avoid' has an invalid band: {}", band)),
+            }
+        }
+
+        let keep = match spec.props.get("keep") {
+            Some(&"frames") => Some(Keep::Frames),
+            Some(&"positions") => Some(Keep::Positions),
+            Some(val) => return Err(format!("'keep' has an invalid value: {}", val)),
+            None => None,
+        };
+        let keep_for: u64 = match spec.props.get("keep_for") {
+            Some(val) => val
+                .parse()
+                .map_err(|_| format!("'keep_for' has an invalid value: {}", val))?,
+            None => 600,
+        };
+
+        info!(
+            "Composite settings: children=[{}] avoid={:?} keep={} keep_for={}s",
+            children
+                .iter()
+                .map(|x| format!("{}={}", x.label, x.name))
+                .collect::<Vec<String>>()
+                .join(" "),
+            avoid,
+            spec.props.get("keep").unwrap_or(&"none"),
+            keep_for
+        );
+
+        Ok(CompositeChooserPlugin {
+            bands: &config.info.bands,
+            children,
+
+            avoid,
+            keep,
+            keep_for,
+            kept_at: None,
+
+            active: 0,
+            scheduled: None,
+            current_band: 0,
+        })
+    }
+
+    /// Child scheduled to pick bands right now: the first window the local time falls in, otherwise the default
+    fn schedule(&self) -> Option<usize> {
+        let now = chrono::offset::Local::now();
+        let now = now.hour() * 60 + now.minute();
+
+        self.children
+            .iter()
+            .position(|x| match x.role {
+                Role::Window(start, end) => in_window(start, end, now),
+                _ => false,
+            })
+            .or(self.children.iter().position(|x| x.role == Role::Default))
+    }
+
+    /// Asks a child for a band, asking again when it picks an avoided band
+    fn pick(&mut self, idx: usize) -> Result<u32, String> {
+        let child = &mut self.children[idx];
+
+        for _ in 0..AVOID_RETRIES {
+            let freqs = child.plugin.choose()?.clone();
+            let band = self
+                .bands
+                .iter()
+                .find(|(_, x)| **x == freqs)
+                .map(|(band, _)| *band)
+                .ok_or(format!("Picked unknown frequencies {:?}", freqs))?;
+
+            if !self.avoid.contains(&band) {
+                info!(
+                    "[composite] {} ({}) chose band {}",
+                    child.label, child.name, band
+                );
+                return Ok(band);
+            }
+
+            info!(
+                "[composite] {} ({}) chose avoided band {}, asking again",
+                child.label, child.name, band
+            );
+        }
+
+        Err(format!("Kept choosing avoided bands {:?}", self.avoid))
+    }
+
+    fn kept_alive(&self) -> bool {
+        self.kept_at
+            .is_some_and(|x| x.elapsed().as_secs() < self.keep_for)
+    }
+
+    /// Lets a child's wish to switch bands through unless the current band is kept alive
+    fn elects_to_switch(&self, idx: usize, switch: bool, event: &str) -> bool {
+        if !switch {
+            return false;
+        }
+
+        let child = &self.children[idx];
+        if self.kept_alive() {
+            debug!(
+                "[composite] {} ({}) elects to switch bands on {}, vetoed: band {} is kept alive",
+                child.label, child.name, event, self.current_band
+            );
+            return false;
+        }
+
+        info!(
+            "[composite] {} ({}) elects to switch bands on {}",
+            child.label, child.name, event
+        );
+        true
+    }
+}
+
+/// Splits a BAND/BAND/... property into its items
+fn props_list<'a>(spec: &'a Spec, key: &str) -> Vec<&'a str> {
+    spec.props
+        .get(key)
+        .map(|x| x.split('/').filter(|x| !x.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+impl<'a> ChooserPlugin for CompositeChooserPlugin<'a> {
+    fn prepare(&mut self, event: Prepare) -> LocalBoxFuture<'_, ()> {
+        async move {
+            // The fallback may be asked as well when the scheduled child fails to choose
+            let children = match event {
+                Prepare::Choose => vec![
+                    self.schedule(),
+                    self.children.iter().position(|x| x.role == Role::Fallback),
+                ],
+                Prepare::Timeout => vec![Some(self.active)],
+            };
+
+            for idx in children.into_iter().flatten() {
+                self.children[idx].plugin.prepare(event).await;
+            }
+        }
+        .boxed_local()
+    }
+
+    fn choose(&mut self) -> Result<&'a Vec<u32>, String> {
+        let scheduled = self.schedule();
+        let fallback = self.children.iter().position(|x| x.role == Role::Fallback);
+
+        for idx in scheduled.into_iter().chain(fallback) {
+            match self.pick(idx) {
+                Ok(band) => {
+                    self.active = idx;
+                    self.scheduled = scheduled;
+                    self.current_band = band;
+                    self.kept_at = None;
+
+                    return self
+                        .bands
+                        .get(&band)
+                        .ok_or(format!("Invalid band: {}", band));
+                }
+                Err(e) => {
+                    let child = &self.children[idx];
+                    warn!(
+                        "[composite] {} ({}) failed to choose: {}",
+                        child.label, child.name, e
+                    );
+                }
+            }
+        }
+
+        Err("No child chooser could pick a band".to_string())
+    }
+
+    fn on_recv_frame(&mut self, frame: &Frame) -> bool {
+        if self.keep.as_ref().is_some_and(|x| x.matches(frame)) {
+            self.kept_at = Some(Instant::now());
+        }
+
+        let switch = self.children[self.active].plugin.on_recv_frame(frame);
+
+        let scheduled = self.schedule();
+        if scheduled != self.scheduled {
+            if let Some(idx) = scheduled {
+                return self.elects_to_switch(idx, true, "schedule change");
+            }
+        }
+
+        self.elects_to_switch(self.active, switch, "frame")
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        let switch = self.children[self.active].plugin.on_timeout();
+        self.elects_to_switch(self.active, switch, "timeout")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chooser::parse_spec;
+    use crate::config;
+    use crate::state::SharedState;
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(parse_window("07:00-19:30"), Some((420, 1170)));
+        assert_eq!(parse_window("22:00-06:00"), Some((1320, 360)));
+        assert_eq!(parse_window("07:00"), None);
+        assert_eq!(parse_window("07:00-19:00-21:00"), None);
+        assert_eq!(parse_window("7-19"), None);
+        assert_eq!(parse_window("25:00-26:00"), None);
+        assert_eq!(parse_window("default"), None);
+    }
+
+    #[test]
+    fn windows_wrap_around_midnight() {
+        assert!(in_window(420, 1140, 420));
+        assert!(in_window(420, 1140, 1139));
+        assert!(!in_window(420, 1140, 1140));
+        assert!(!in_window(420, 1140, 0));
+
+        assert!(in_window(1320, 360, 1320));
+        assert!(in_window(1320, 360, 0));
+        assert!(in_window(1320, 360, 359));
+        assert!(!in_window(1320, 360, 360));
+        assert!(!in_window(1320, 360, 720));
+
+        assert!(!in_window(600, 600, 600));
+    }
+
+    #[test]
+    fn windows_without_default_cover_the_day() {
+        assert_eq!(uncovered(&[(0, 720), (720, 0)]), None);
+        assert_eq!(uncovered(&[(1320, 360), (360, 1320)]), None);
+        assert_eq!(uncovered(&[(420, 1140)]), Some(0));
+        assert_eq!(uncovered(&[(0, 720), (780, 0)]), Some(720));
+
+        let config = config::test_config(&[]);
+        let context = SharedState::new(&config, "rx").chooser_context();
+
+        let spec = parse_spec("composite:07:00-19:00=[rotate]");
+        let err = CompositeChooserPlugin::new(&config, &spec, &context)
+            .err()
+            .unwrap();
+        assert!(err.contains("00:00 uncovered"), "{}", err);
+
+        let spec = parse_spec("composite:07:00-19:00=[rotate],fallback=[rotate]");
+        assert!(CompositeChooserPlugin::new(&config, &spec, &context).is_err());
+
+        let spec = parse_spec("composite:07:00-19:00=[rotate],default=[rotate]");
+        assert!(CompositeChooserPlugin::new(&config, &spec, &context).is_ok());
+
+        let spec = parse_spec("composite:07:00-19:00=[rotate],19:00-07:00=[rotate]");
+        assert!(CompositeChooserPlugin::new(&config, &spec, &context).is_ok());
+    }
+}
@@ -0,0 +1,404 @@
+use crate::chooser::ChooserPlugin;
+use crate::config::FrequencyBandMap;
+use crate::hfdl::{Frame, FrameKind};
+use actix_web::web::Data;
+use chrono::{offset, DateTime, Timelike, Utc};
+use log::*;
+use rand::rngs::ThreadRng;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use std::time::Instant;
+
+pub const NAME: &str = "learn";
+
+/// What is known of a band at a given UTC hour. Rewards are weighted frames per minute of listening
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Arm {
+    pub pulls: u64,
+    pub mean: f64,
+
+    /// Sum of squared differences from the mean, for the variance
+    m2: f64,
+
+    pub last_reward: f64,
+    pub last_pulled: Option<DateTime<Utc>>,
+}
+
+impl Arm {
+    fn add(&mut self, reward: f64) {
+        self.pulls += 1;
+        let delta = reward - self.mean;
+        self.mean += delta / self.pulls as f64;
+        self.m2 += delta * (reward - self.mean);
+        self.last_reward = reward;
+        self.last_pulled = Some(offset::Utc::now());
+    }
+
+    fn std_dev(&self) -> Option<f64> {
+        if self.pulls < 2 {
+            None
+        } else {
+            Some((self.m2 / (self.pulls - 1) as f64).sqrt())
+        }
+    }
+}
+
+/// Arms per UTC hour and band, shared by every receiver running the learning chooser
+pub type LearnedArms = BTreeMap<u32, BTreeMap<u32, Arm>>;
+
+#[derive(Default)]
+pub struct LearnTable {
+    hours: RwLock<LearnedArms>,
+}
+
+impl LearnTable {
+    pub fn new() -> Self {
+        LearnTable::default()
+    }
+
+    fn reward(&self, hour: u32, band: u32, reward: f64) {
+        self.hours
+            .write()
+            .unwrap()
+            .entry(hour)
+            .or_default()
+            .entry(band)
+            .or_default()
+            .add(reward);
+    }
+
+    fn arms(&self, hour: u32) -> BTreeMap<u32, Arm> {
+        self.hours
+            .read()
+            .unwrap()
+            .get(&hour)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn snapshot(&self) -> LearnedArms {
+        self.hours.read().unwrap().clone()
+    }
+
+    /// Replaces the table with one loaded from the store
+    pub fn load(&self, hours: LearnedArms) {
+        *self.hours.write().unwrap() = hours;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Policy {
+    Ucb,
+    Thompson,
+}
+
+/// How much each kind of frame is worth
+#[derive(Debug)]
+struct Weights {
+    spdu: f64,
+    lpdu: f64,
+    hfnpdu: f64,
+    acars: f64,
+    position: f64,
+}
+
+impl Weights {
+    fn of(&self, frame: &Frame) -> f64 {
+        let position = frame
+            .hfdl
+            .lpdu
+            .as_ref()
+            .and_then(|x| x.hfnpdu.as_ref())
+            .is_some_and(|x| x.pos.is_some());
+
+        match frame.kind() {
+            Some(FrameKind::SPDU) => self.spdu,
+            Some(FrameKind::LPDU) => self.lpdu,
+            Some(FrameKind::HFNPDU) if position => self.position,
+            Some(FrameKind::HFNPDU) => self.hfnpdu,
+            Some(FrameKind::ACARS) if position => self.position.max(self.acars),
+            Some(FrameKind::ACARS) => self.acars,
+            None => 0.0,
+        }
+    }
+}
+
+struct Pull {
+    band: u32,
+    hour: u32,
+    started: Instant,
+    score: f64,
+
+    /// Set when frames show another band is being listened to (control API, swarm lease), so the pull isn't
+    /// credited to the band that was chosen
+    foreign: bool,
+}
+
+pub struct LearnChooserPlugin<'a> {
+    bands: &'a FrequencyBandMap,
+    table: Data<LearnTable>,
+
+    rng: ThreadRng,
+
+    policy: Policy,
+    explore: f64,
+    min_dwell: u64,
+    dwell: u64,
+    weights: Weights,
+
+    pull: Option<Pull>,
+}
+
+fn prop<T: std::str::FromStr>(
+    props: &HashMap<&str, &str>,
+    key: &str,
+    default: T,
+) -> Result<T, String> {
+    match props.get(key) {
+        Some(val) => val
+            .parse()
+            .map_err(|_| format!("'{}' has an invalid value: {}", key, val)),
+        None => Ok(default),
+    }
+}
+
+impl<'a> LearnChooserPlugin<'a> {
+    pub fn new(
+        bands: &'a FrequencyBandMap,
+        props: &'a HashMap<&'a str, &'a str>,
+        table: Data<LearnTable>,
+    ) -> Result<Self, String> {
+        let policy = match *props.get("policy").unwrap_or(&"ucb") {
+            "ucb" => Policy::Ucb,
+            "thompson" => Policy::Thompson,
+            x => return Err(format!("Unknown policy (ucb or thompson): {}", x)),
+        };
+        let explore: f64 = prop(props, "explore", 1.0)?;
+        let min_dwell: u64 = prop(props, "min_dwell", 300)?;
+        let dwell: u64 = prop(props, "dwell", 900)?;
+        if dwell < min_dwell {
+            return Err(format!(
+                "'dwell' ({}s) is shorter than 'min_dwell' ({}s)",
+                dwell, min_dwell
+            ));
+        }
+
+        let weights = Weights {
+            spdu: prop(props, "spdu_weight", 0.0)?,
+            lpdu: prop(props, "lpdu_weight", 1.0)?,
+            hfnpdu: prop(props, "hfnpdu_weight", 1.0)?,
+            acars: prop(props, "acars_weight", 1.0)?,
+            position: prop(props, "pos_weight", 1.0)?,
+        };
+
+        info!(
+            "Learn settings: policy={:?} explore={} min_dwell={}s dwell={}s weights={:?}",
+            policy, explore, min_dwell, dwell, weights
+        );
+
+        Ok(LearnChooserPlugin {
+            bands,
+            table,
+
+            rng: rand::thread_rng(),
+
+            policy,
+            explore,
+            min_dwell,
+            dwell,
+            weights,
+
+            pull: None,
+        })
+    }
+
+    fn elapsed(&self) -> u64 {
+        self.pull
+            .as_ref()
+            .map(|x| x.started.elapsed().as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Credits the band just listened to. Pulls shorter than `min_dwell` are too noisy to learn from
+    fn settle(&mut self) {
+        let pull = match self.pull.take() {
+            Some(val) => val,
+            None => return,
+        };
+
+        let secs = pull.started.elapsed().as_secs();
+        if pull.foreign || secs < self.min_dwell || secs == 0 {
+            return;
+        }
+
+        let reward = pull.score * 60.0 / secs as f64;
+        info!(
+            "[learn] band {} at {:02}:00 UTC scored {:.2} frames/min over {}s",
+            pull.band, pull.hour, reward, secs
+        );
+        self.table.reward(pull.hour, pull.band, reward);
+    }
+
+    fn sample_normal(&mut self) -> f64 {
+        // Box-Muller
+        let u1: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+        let u2: f64 = self.rng.gen();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    fn pick(&mut self, hour: u32) -> u32 {
+        let arms = self.table.arms(hour);
+
+        let mut untried: Vec<u32> = self
+            .bands
+            .keys()
+            .filter(|x| arms.get(x).is_none_or(|arm| arm.pulls == 0))
+            .copied()
+            .collect();
+        if !untried.is_empty() {
+            untried.sort_unstable();
+            return *untried.choose(&mut self.rng).unwrap();
+        }
+
+        // Exploration is scaled to the best mean so it doesn't depend on how busy HFDL is overall
+        let scale = arms.values().map(|x| x.mean).fold(1.0, f64::max);
+        let total: u64 = arms.values().map(|x| x.pulls).sum();
+
+        let mut best: Option<(f64, u32)> = None;
+        let mut bands: Vec<u32> = self.bands.keys().copied().collect();
+        bands.sort_unstable();
+        for band in bands {
+            let arm = &arms[&band];
+            let score = match self.policy {
+                Policy::Ucb => {
+                    arm.mean
+                        + self.explore * scale * ((total as f64).ln() / arm.pulls as f64).sqrt()
+                }
+                Policy::Thompson => {
+                    let std_dev = arm.std_dev().unwrap_or(scale).max(f64::EPSILON);
+                    arm.mean
+                        + self.explore * self.sample_normal() * std_dev / (arm.pulls as f64).sqrt()
+                }
+            };
+
+            if best.is_none_or(|(x, _)| score > x) {
+                best = Some((score, band));
+            }
+        }
+
+        best.map(|x| x.1).unwrap_or(0)
+    }
+}
+
+impl<'a> ChooserPlugin for LearnChooserPlugin<'a> {
+    fn choose(&mut self) -> Result<&'a Vec<u32>, String> {
+        self.settle();
+
+        let hour = offset::Utc::now().hour();
+        let band = self.pick(hour);
+
+        self.pull = Some(Pull {
+            band,
+            hour,
+            started: Instant::now(),
+            score: 0.0,
+            foreign: false,
+        });
+
+        self.bands
+            .get(&band)
+            .ok_or(format!("Invalid band: {}", band))
+    }
+
+    fn on_recv_frame(&mut self, frame: &Frame) -> bool {
+        let weight = self.weights.of(frame);
+        if let Some(ref mut pull) = self.pull {
+            let freq = frame.hfdl.freq / 1000;
+            if self
+                .bands
+                .get(&pull.band)
+                .is_some_and(|x| x.contains(&freq))
+            {
+                pull.score += weight;
+            } else {
+                pull.foreign = true;
+            }
+        }
+
+        let elapsed = self.elapsed();
+        if elapsed >= self.dwell {
+            info!(
+                "[learn] dwelled {}s (dwell={}s). Chooser elects to pick a band again.",
+                elapsed, self.dwell
+            );
+            return true;
+        }
+
+        false
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        self.elapsed() >= self.min_dwell
+    }
+}
+
+impl<'a> Drop for LearnChooserPlugin<'a> {
+    fn drop(&mut self) {
+        self.settle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arm_tracks_mean_and_sample_deviation() {
+        let rewards = [4.0, 7.0, 13.0, 16.0];
+        let mut arm = Arm::default();
+        assert_eq!(arm.std_dev(), None);
+
+        arm.add(rewards[0]);
+        assert_eq!(arm.mean, 4.0);
+        assert_eq!(arm.std_dev(), None);
+
+        for reward in &rewards[1..] {
+            arm.add(*reward);
+        }
+
+        // Mean 10, squared deviations 36 + 9 + 9 + 36 over 3 degrees of freedom
+        assert_eq!(arm.pulls, 4);
+        assert!((arm.mean - 10.0).abs() < 1e-9);
+        assert!((arm.std_dev().unwrap() - 30f64.sqrt()).abs() < 1e-9);
+        assert_eq!(arm.last_reward, 16.0);
+    }
+
+    #[test]
+    fn arm_is_stable_around_a_large_mean() {
+        let mut arm = Arm::default();
+        for reward in [1e9 + 4.0, 1e9 + 7.0, 1e9 + 13.0, 1e9 + 16.0] {
+            arm.add(reward);
+        }
+
+        assert!((arm.std_dev().unwrap() - 30f64.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn table_keeps_arms_per_hour_and_band() {
+        let table = LearnTable::new();
+        table.reward(3, 13, 2.0);
+        table.reward(3, 13, 4.0);
+        table.reward(3, 17, 1.0);
+        table.reward(4, 13, 8.0);
+
+        let arms = table.arms(3);
+        assert_eq!(arms.len(), 2);
+        assert_eq!(arms[&13].pulls, 2);
+        assert_eq!(arms[&13].mean, 3.0);
+        assert_eq!(table.arms(4)[&13].mean, 8.0);
+        assert!(table.arms(5).is_empty());
+    }
+}
@@ -1,11 +1,21 @@
 use crate::config::Config;
-use crate::state::GroundStationMap;
+use crate::hfdl::Frame;
+use crate::state::{FrequencyStats, GroundStationMap, PositionReportsByFlightMap};
 use actix_web::web::Data;
-use serde_json::Value;
+use futures_util::future::{self, FutureExt, LocalBoxFuture};
 use std::collections::HashMap;
 
+pub use learn::{LearnTable, LearnedArms};
+
+mod composite;
+mod learn;
+mod propagation;
+mod region;
+mod remote;
+mod rescore;
 mod rotate;
 mod schedule;
+mod script;
 mod single;
 mod tracker;
 
@@ -18,33 +28,218 @@ macro_rules! init_plugin {
     };
 }
 
+/// Shared state choosers may read, handed down to nested choosers
+#[derive(Clone)]
+pub struct ChooserContext {
+    pub gs_info: Data<GroundStationMap>,
+    pub flights: Data<PositionReportsByFlightMap>,
+    pub learned: Data<LearnTable>,
+    pub freq_stats: Data<FrequencyStats>,
+}
+
+/// What a chooser is about to be asked, so it can prepare for it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prepare {
+    Choose,
+    Timeout,
+}
+
 pub trait ChooserPlugin {
+    /// Invoked before choose and on_timeout, to fetch what they depend on without blocking other receivers
+    fn prepare(&mut self, _event: Prepare) -> LocalBoxFuture<'_, ()> {
+        future::ready(()).boxed_local()
+    }
+
     /// Invoked to calculate next band to listen to
     fn choose(&mut self) -> Result<&Vec<u32>, String>;
 
     /// Invoked when a new HFDL frame is received. Returns boolean indicating whether listening bands should change
-    fn on_recv_frame(&mut self, frame: &Value) -> bool;
+    fn on_recv_frame(&mut self, frame: &Frame) -> bool;
 
     /// Invoked during listening timeout threshold. Returns boolean indicating whether listening bands should change
     fn on_timeout(&mut self) -> bool;
 }
 
-pub fn get<'a, 'b>(
-    name: &'a str,
+/// Asks a chooser for the next band to listen to
+pub async fn choose(plugin: &mut dyn ChooserPlugin) -> Result<Vec<u32>, String> {
+    plugin.prepare(Prepare::Choose).await;
+    plugin.choose().map(|x| x.to_owned())
+}
+
+/// Asks a chooser whether to change bands after the listening timeout
+pub async fn on_timeout(plugin: &mut dyn ChooserPlugin) -> bool {
+    plugin.prepare(Prepare::Timeout).await;
+    plugin.on_timeout()
+}
+
+/// A parsed PLUGIN_NAME[:KEY=VALUE,...] chooser spec. Values wrapped in square brackets are nested chooser specs
+#[derive(Debug)]
+pub struct Spec<'a> {
+    pub name: &'a str,
+    pub props: HashMap<&'a str, &'a str>,
+    pub children: HashMap<&'a str, Spec<'a>>,
+}
+
+/// Splits properties on commas outside of square brackets
+fn split_props(raw: &str) -> Vec<&str> {
+    let mut items: Vec<&str> = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in raw.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&raw[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&raw[start..]);
+
+    items
+}
+
+/// Splits a PLUGIN_NAME[:KEY=VALUE,...] chooser spec into its name, properties and nested specs
+pub fn parse_spec(spec: &str) -> Spec<'_> {
+    let mut props: HashMap<&str, &str> = HashMap::new();
+    let mut children: HashMap<&str, Spec> = HashMap::new();
+
+    let delim = match spec.find(':') {
+        Some(val) => val,
+        None => {
+            return Spec {
+                name: spec,
+                props,
+                children,
+            }
+        }
+    };
+
+    let name = &spec[..delim];
+
+    for kv in split_props(&spec[(delim + 1)..]) {
+        let delim = match kv.find('=') {
+            Some(val) => val,
+            None => {
+                props.insert(kv, "");
+                continue;
+            }
+        };
+
+        let (key, val) = (&kv[..delim], &kv[(delim + 1)..]);
+        match val.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            Some(nested) => {
+                props.insert(key, nested);
+                children.insert(key, parse_spec(nested));
+            }
+            None => {
+                props.insert(key, val);
+            }
+        }
+    }
+
+    Spec {
+        name,
+        props,
+        children,
+    }
+}
+
+pub fn get<'b>(
+    spec: &'b Spec,
     config: &'b Config,
-    props: &'b HashMap<&str, &str>,
-    gs_info: Data<GroundStationMap>,
+    context: &ChooserContext,
 ) -> Result<Box<dyn ChooserPlugin + 'b>, String> {
-    let chooser: Box<dyn ChooserPlugin> = match name {
+    let props = &spec.props;
+    let chooser: Box<dyn ChooserPlugin> = match spec.name {
+        composite::NAME => init_plugin!(composite::CompositeChooserPlugin::new(
+            config, spec, context
+        )),
+        learn::NAME => init_plugin!(learn::LearnChooserPlugin::new(
+            &config.info.bands,
+            props,
+            context.learned.clone()
+        )),
+        propagation::NAME => {
+            init_plugin!(propagation::PropagationChooserPlugin::new(config, props))
+        }
+        region::NAME => init_plugin!(region::RegionChooserPlugin::new(
+            config,
+            props,
+            context.gs_info.clone(),
+            context.flights.clone()
+        )),
+        remote::NAME => init_plugin!(remote::RemoteChooserPlugin::new(config, spec, context)),
         rotate::NAME => init_plugin!(rotate::RotateChooserPlugin::new(&config.info.bands, props)),
         schedule::NAME => init_plugin!(schedule::ScheduleChooserPlugin::new(
             &config.info.bands,
             props
         )),
+        script::NAME => init_plugin!(script::ScriptChooserPlugin::new(
+            config,
+            props,
+            context.gs_info.clone(),
+            context.freq_stats.clone()
+        )),
         single::NAME => init_plugin!(single::SingleChooserPlugin::new(&config.info.bands, props)),
-        tracker::NAME => init_plugin!(tracker::TrackerChooserPlugin::new(config, props, gs_info)),
-        _ => return Err(format!("{} is not a valid chooser plugin", name)),
+        tracker::NAME => init_plugin!(tracker::TrackerChooserPlugin::new(
+            config,
+            props,
+            context.gs_info.clone()
+        )),
+        _ => return Err(format!("{} is not a valid chooser plugin", spec.name)),
     };
 
     Ok(chooser)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_props_outside_of_brackets() {
+        assert_eq!(split_props("a=1,b=2"), vec!["a=1", "b=2"]);
+        assert_eq!(
+            split_props("default=[rotate:type=inc,interval=5],keep=frames"),
+            vec!["default=[rotate:type=inc,interval=5]", "keep=frames"]
+        );
+        assert_eq!(
+            split_props("a=[b:c=[d:e=1,f=2],g=3],h"),
+            vec!["a=[b:c=[d:e=1,f=2],g=3]", "h"]
+        );
+        assert_eq!(split_props(""), vec![""]);
+    }
+
+    #[test]
+    fn parses_nested_specs() {
+        let spec = parse_spec("rotate");
+        assert_eq!(spec.name, "rotate");
+        assert!(spec.props.is_empty() && spec.children.is_empty());
+
+        let spec = parse_spec("single:band=13,verbose");
+        assert_eq!(spec.name, "single");
+        assert_eq!(spec.props["band"], "13");
+        assert_eq!(spec.props["verbose"], "");
+        assert!(spec.children.is_empty());
+
+        let spec = parse_spec(
+            "composite:07:00-19:00=[schedule:7:00=21,13:00=17],default=[composite:default=[rotate:type=inc]],avoid=2/3",
+        );
+        assert_eq!(spec.name, "composite");
+        assert_eq!(spec.props["avoid"], "2/3");
+        assert_eq!(spec.children.len(), 2);
+
+        let window = &spec.children["07:00-19:00"];
+        assert_eq!(window.name, "schedule");
+        assert_eq!(window.props["7:00"], "21");
+        assert_eq!(window.props["13:00"], "17");
+
+        let nested = &spec.children["default"].children["default"];
+        assert_eq!(nested.name, "rotate");
+        assert_eq!(nested.props["type"], "inc");
+    }
+}
@@ -0,0 +1,241 @@
+use crate::chooser::rescore::Rescorer;
+use crate::chooser::ChooserPlugin;
+use crate::config::{Config, FrequencyBandMap, GroundStationMap};
+use crate::geo;
+use crate::hfdl::Frame;
+use chrono::{offset, DateTime, Utc};
+use log::*;
+use std::collections::HashMap;
+
+pub const NAME: &str = "propagation";
+
+/// Sun elevation (degrees) below which the ionosphere is considered fully in night conditions
+const NIGHT_ELEVATION: f64 = -12.0;
+
+/// Sun elevation (degrees) above which the ionosphere is considered fully in day conditions
+const DAY_ELEVATION: f64 = 30.0;
+
+/// How far along from night (0.0) to day (1.0) the ionosphere is under a sun at `elevation`
+fn daylight(elevation: f64) -> f64 {
+    let x = ((elevation - NIGHT_ELEVATION) / (DAY_ELEVATION - NIGHT_ELEVATION)).clamp(0.0, 1.0);
+    x * x * (3.0 - 2.0 * x)
+}
+
+/// Usable frequency window (MHz) of a path, from the sun at its midpoint and at both ends. This is a rough
+/// heuristic: F2 critical frequency climbs from about 3 MHz at night to 10 MHz at midday, the MUF grows with path
+/// length up to 3 times that, and D layer absorption raises the LUF while either end is in daylight
+fn usable_window(distance: f64, midpoint: f64, ends: f64) -> (f64, f64) {
+    let fo_f2 = 3.0 + 7.0 * daylight(midpoint);
+    let muf = fo_f2 * (1.0 + 2.0 * (distance / 3000.0).min(1.0));
+    let luf = 2.0 + 6.0 * daylight(ends) * (distance / 3000.0).min(1.0);
+
+    (luf, muf.max(luf))
+}
+
+/// How well a frequency (MHz) should carry over a path, peaking at 85% of the MUF
+fn frequency_score(freq: f64, luf: f64, muf: f64) -> f64 {
+    if freq < luf || freq > muf {
+        return 0.0;
+    }
+
+    let optimum = (0.85 * muf).max(luf);
+    if freq <= optimum {
+        1.0 - 0.5 * (optimum - freq) / (optimum - luf).max(f64::EPSILON)
+    } else {
+        1.0 - 0.5 * (freq - optimum) / (muf - optimum).max(f64::EPSILON)
+    }
+}
+
+pub struct PropagationChooserPlugin<'a> {
+    bands: &'a FrequencyBandMap,
+    stations: &'a GroundStationMap,
+    location: (f64, f64),
+
+    range: f64,
+    rescorer: Rescorer,
+}
+
+impl<'a> PropagationChooserPlugin<'a> {
+    pub fn new(config: &'a Config, props: &'a HashMap<&'a str, &'a str>) -> Result<Self, String> {
+        let location = config
+            .location
+            .ok_or("Requires the receiver location (--location LAT,LON)".to_string())?;
+
+        let range: f64 = match props.get("range") {
+            Some(val) => val
+                .parse()
+                .map_err(|_| format!("'range' has an invalid value: {}", val))?,
+            None => 8000.0,
+        };
+        let interval: u64 = match props.get("interval") {
+            Some(val) => val
+                .parse()
+                .map_err(|_| format!("'interval' has an invalid value: {}", val))?,
+            None => 900,
+        };
+
+        info!(
+            "Propagation settings: location={:?} range={}km interval={}s",
+            location, range, interval
+        );
+
+        Ok(PropagationChooserPlugin {
+            bands: &config.info.bands,
+            stations: &config.info.stations,
+            location,
+
+            range,
+            rescorer: Rescorer::new(NAME, interval),
+        })
+    }
+
+    /// Sums how well each ground station in range should be heard on a band's frequencies
+    fn score(&self, at: DateTime<Utc>) -> Vec<(u32, f64)> {
+        let (lat, lon) = self.location;
+        let rx_sun = geo::solar_elevation(lat, lon, at);
+
+        let mut scores: HashMap<u32, f64> = self.bands.keys().map(|x| (*x, 0.0)).collect();
+        for gs in self.stations.values() {
+            let distance = geo::distance_km(lat, lon, gs.lat, gs.lon);
+            if distance > self.range {
+                continue;
+            }
+
+            let (mid_lat, mid_lon) = geo::midpoint(lat, lon, gs.lat, gs.lon);
+            let mid_sun = geo::solar_elevation(mid_lat, mid_lon, at);
+            let ends_sun = rx_sun.max(geo::solar_elevation(gs.lat, gs.lon, at));
+            let (luf, muf) = usable_window(distance, mid_sun, ends_sun);
+
+            for (band, freqs) in self.bands.iter() {
+                let best = freqs
+                    .iter()
+                    .filter(|x| match gs.freqs.is_empty() {
+                        true => gs.assigned.contains(band),
+                        false => gs.freqs.contains(x),
+                    })
+                    .map(|x| frequency_score(*x as f64 / 1000.0, luf, muf))
+                    .fold(0.0, f64::max);
+
+                *scores.get_mut(band).unwrap() += best;
+            }
+        }
+
+        let mut scores: Vec<(u32, f64)> = scores.into_iter().collect();
+        scores.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores
+    }
+
+    /// Checks whether another band has become the better pick since the last time bands were scored
+    fn better_band_available(&mut self) -> bool {
+        if !self.rescorer.due() {
+            return false;
+        }
+
+        let current_band = self.rescorer.current_band;
+        match self.score(offset::Utc::now()).first() {
+            Some((band, _)) if *band != current_band => {
+                info!(
+                    "[propagation] band {} now expected to propagate better than band {}. Chooser elects to switch bands.",
+                    band, current_band
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'a> ChooserPlugin for PropagationChooserPlugin<'a> {
+    fn choose(&mut self) -> Result<&'a Vec<u32>, String> {
+        let scores = self.score(offset::Utc::now());
+        let band = self
+            .rescorer
+            .pick(&scores)
+            .ok_or("No bands to choose from".to_string())?;
+
+        self.bands
+            .get(&band)
+            .ok_or(format!("Invalid band: {}", band))
+    }
+
+    fn on_recv_frame(&mut self, _frame: &Frame) -> bool {
+        self.better_band_available()
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        self.rescorer.on_quiet();
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use chrono::TimeZone;
+
+    type Scores = Vec<(u32, f64)>;
+
+    /// Scores from Honolulu at local midnight and noon around the March equinox
+    fn scores(props: &str) -> (Scores, Scores) {
+        let config = config::test_config(&["--location", "21.3,-157.9"]);
+        let props: HashMap<&str, &str> =
+            props.split(',').filter_map(|x| x.split_once('=')).collect();
+        let plugin = PropagationChooserPlugin::new(&config, &props).unwrap();
+
+        (
+            plugin.score(Utc.with_ymd_and_hms(2024, 3, 20, 10, 0, 0).unwrap()),
+            plugin.score(Utc.with_ymd_and_hms(2024, 3, 20, 22, 0, 0).unwrap()),
+        )
+    }
+
+    #[test]
+    fn usable_window_follows_the_sun() {
+        let (night_luf, night_muf) = usable_window(3000.0, -30.0, -30.0);
+        let (day_luf, day_muf) = usable_window(3000.0, 60.0, 60.0);
+        assert_eq!((night_luf, night_muf), (2.0, 9.0));
+        assert_eq!((day_luf, day_muf), (8.0, 30.0));
+
+        // Short paths bend less, lowering the MUF
+        assert!(usable_window(500.0, 60.0, 60.0).1 < day_muf);
+
+        assert_eq!(frequency_score(0.85 * day_muf, day_luf, day_muf), 1.0);
+        assert_eq!(frequency_score(day_luf, day_luf, day_muf), 0.5);
+        assert_eq!(frequency_score(day_muf, day_luf, day_muf), 0.5);
+        assert_eq!(frequency_score(5.5, day_luf, day_muf), 0.0);
+        assert_eq!(frequency_score(21.9, night_luf, night_muf), 0.0);
+    }
+
+    #[test]
+    fn favours_low_bands_at_night_and_high_bands_by_day() {
+        let (night, day) = scores("");
+        assert_eq!(night.len(), 12);
+
+        assert_eq!(night[0].0, 6);
+        assert!(night[..3].iter().all(|x| x.0 <= 8), "{:?}", night);
+        assert!(night.iter().filter(|x| x.0 >= 10).all(|x| x.1 == 0.0));
+
+        assert_eq!(day[0].0, 17);
+        assert!(day.iter().filter(|x| x.0 <= 4).all(|x| x.1 == 0.0));
+        let score = |band| day.iter().find(|x| x.0 == band).unwrap().1;
+        assert!(score(21) > score(5));
+    }
+
+    #[test]
+    fn only_scores_stations_in_range() {
+        let (night, day) = scores("range=10");
+        assert!(night.iter().chain(day.iter()).all(|x| x.1 == 0.0));
+
+        // Molokai alone, too close for anything but its lowest bands to bend back down
+        let (night, day) = scores("range=100");
+        assert!(night.iter().all(|x| x.1 == 0.0));
+        assert_eq!(
+            day.iter()
+                .filter(|x| x.1 > 0.0)
+                .map(|x| x.0)
+                .collect::<Vec<u32>>(),
+            [8, 6, 5, 10]
+        );
+    }
+}
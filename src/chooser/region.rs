@@ -0,0 +1,483 @@
+use crate::chooser::rescore::Rescorer;
+use crate::chooser::ChooserPlugin;
+use crate::config::{Config, FrequencyBandMap};
+use crate::geo;
+use crate::hfdl::Frame;
+use crate::state::{GroundStationMap, PositionReportsByFlightMap};
+use actix_web::web::Data;
+use log::*;
+use std::collections::HashMap;
+
+pub const NAME: &str = "region";
+
+/// Area of interest position reports are counted in
+#[derive(Debug, PartialEq)]
+enum Area {
+    Radius((f64, f64), f64),
+    Polygon(Vec<(f64, f64)>),
+
+    /// South, north, west and east edges. The box crosses the antimeridian when west is greater than east
+    BBox(f64, f64, f64, f64),
+}
+
+impl Area {
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        match self {
+            Area::Radius((c_lat, c_lon), km) => geo::distance_km(*c_lat, *c_lon, lat, lon) <= *km,
+            Area::Polygon(polygon) => geo::in_polygon(lat, lon, polygon),
+            Area::BBox(s, n, w, e) => {
+                (*s..=*n).contains(&lat)
+                    && if w <= e {
+                        (*w..=*e).contains(&lon)
+                    } else {
+                        lon >= *w || lon <= *e
+                    }
+            }
+        }
+    }
+
+    /// Point ground station distances are measured from when the receiver location isn't known
+    fn center(&self) -> (f64, f64) {
+        match self {
+            Area::Radius(center, _) => *center,
+            Area::Polygon(polygon) => {
+                let n = polygon.len() as f64;
+                (
+                    polygon.iter().map(|x| x.0).sum::<f64>() / n,
+                    polygon.iter().map(|x| x.1).sum::<f64>() / n,
+                )
+            }
+            Area::BBox(s, n, w, e) => {
+                let lon = if w <= e {
+                    (w + e) / 2.0
+                } else {
+                    (w + e + 360.0) / 2.0
+                };
+                ((s + n) / 2.0, if lon > 180.0 { lon - 360.0 } else { lon })
+            }
+        }
+    }
+}
+
+/// Parses a LAT:LON/LAT:LON/... list of points
+fn parse_points(key: &str, val: &str) -> Result<Vec<(f64, f64)>, String> {
+    val.split('/')
+        .map(|x| geo::parse_location(&x.replacen(':', ",", 1)))
+        .collect::<Result<Vec<(f64, f64)>, String>>()
+        .map_err(|e| format!("'{}' has an invalid point: {}", key, e))
+}
+
+fn parse_area(
+    props: &HashMap<&str, &str>,
+    location: Option<(f64, f64)>,
+) -> Result<Option<Area>, String> {
+    if let Some(val) = props.get("bbox") {
+        let corners = parse_points("bbox", val)?;
+        if corners.len() != 2 {
+            return Err(format!("'bbox' expects two corners: {}", val));
+        }
+
+        // The west corner comes first so boxes may cross the antimeridian
+        return Ok(Some(Area::BBox(
+            corners[0].0.min(corners[1].0),
+            corners[0].0.max(corners[1].0),
+            corners[0].1,
+            corners[1].1,
+        )));
+    }
+
+    if let Some(val) = props.get("polygon") {
+        let polygon = parse_points("polygon", val)?;
+        if polygon.len() < 3 {
+            return Err(format!("'polygon' expects at least three points: {}", val));
+        }
+
+        return Ok(Some(Area::Polygon(polygon)));
+    }
+
+    if let Some(val) = props.get("radius") {
+        let km: f64 = val
+            .parse()
+            .map_err(|_| format!("'radius' has an invalid value: {}", val))?;
+        let center =
+            location.ok_or("'radius' requires the receiver location (--location LAT,LON)")?;
+        return Ok(Some(Area::Radius(center, km)));
+    }
+
+    Ok(None)
+}
+
+pub struct RegionChooserPlugin<'a> {
+    bands: &'a FrequencyBandMap,
+    gs_info: Data<GroundStationMap>,
+    flights: Data<PositionReportsByFlightMap>,
+
+    origin: (f64, f64),
+    area: Option<Area>,
+    traffic: bool,
+    stations: usize,
+    spdu_timeout: u64,
+    ac_timeout: u64,
+
+    rescorer: Rescorer,
+}
+
+impl<'a> RegionChooserPlugin<'a> {
+    pub fn new(
+        config: &'a Config,
+        props: &'a HashMap<&'a str, &'a str>,
+        gs_info: Data<GroundStationMap>,
+        flights: Data<PositionReportsByFlightMap>,
+    ) -> Result<Self, String> {
+        let area = parse_area(props, config.location)?;
+        let origin =
+            match (config.location, &area) {
+                (Some(location), _) => location,
+                (None, Some(area)) => area.center(),
+                (None, None) => return Err(
+                    "Requires the receiver location (--location LAT,LON) or an area of interest"
+                        .to_string(),
+                ),
+            };
+
+        let traffic = match *props.get("rank").unwrap_or(&"DEFAULT") {
+            "distance" => false,
+            "traffic" if area.is_some() => true,
+            "traffic" => return Err("'rank=traffic' requires an area of interest".to_string()),
+            "DEFAULT" => area.is_some(),
+            val => return Err(format!("'rank' has an invalid value: {}", val)),
+        };
+
+        let stations: usize = match props.get("stations") {
+            Some(val) => val
+                .parse()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or(format!("'stations' has an invalid value: {}", val))?,
+            None => 3,
+        };
+        let interval: u64 = match props.get("interval") {
+            Some(val) => val
+                .parse()
+                .map_err(|_| format!("'interval' has an invalid value: {}", val))?,
+            None => 600,
+        };
+
+        info!(
+            "Region settings: origin={:?} rank={} stations={} interval={}s",
+            origin,
+            if traffic { "traffic" } else { "distance" },
+            stations,
+            interval
+        );
+
+        Ok(RegionChooserPlugin {
+            bands: &config.info.bands,
+            gs_info,
+            flights,
+
+            origin,
+            area,
+            traffic,
+            stations,
+            spdu_timeout: config.spdu_timeout,
+            ac_timeout: config.ac_timeout,
+
+            rescorer: Rescorer::new(NAME, interval),
+        })
+    }
+
+    /// Counts recent position reports inside the area of interest relayed by each ground station
+    fn traffic_by_station(&self, area: &Area) -> HashMap<u8, u64> {
+        let mut counts: HashMap<u8, u64> = HashMap::new();
+
+        for item in self.flights.iter() {
+            if item.last_heard.elapsed().as_secs() > self.ac_timeout {
+                continue;
+            }
+
+            for report in item.positions.iter() {
+                if report.position.len() < 2
+                    || !area.contains(report.position[0], report.position[1])
+                {
+                    continue;
+                }
+
+                for gs in report.propagation.iter() {
+                    *counts.entry(gs.id).or_insert(0) += 1;
+                }
+            }
+        }
+
+        counts
+    }
+
+    /// Ground station IDs, best first: by traffic inside the area of interest when asked to and any was heard,
+    /// otherwise by great-circle distance from the origin
+    fn rank(&self) -> Vec<u8> {
+        let (lat, lon) = self.origin;
+        let mut by_distance: Vec<(u8, f64)> = self
+            .gs_info
+            .iter()
+            .filter(|x| x.position.len() >= 2)
+            .map(|x| {
+                (
+                    *x.key(),
+                    geo::distance_km(lat, lon, x.position[0], x.position[1]),
+                )
+            })
+            .collect();
+        by_distance.sort_unstable_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+
+        if let (true, Some(area)) = (self.traffic, &self.area) {
+            let counts = self.traffic_by_station(area);
+            if !counts.is_empty() {
+                let mut ranked: Vec<u8> = by_distance.into_iter().map(|x| x.0).collect();
+                ranked.sort_by_key(|x| std::cmp::Reverse(*counts.get(x).unwrap_or(&0)));
+                return ranked;
+            }
+        }
+
+        by_distance.into_iter().map(|x| x.0).collect()
+    }
+
+    /// Scores bands by the rank of the top stations active on them, or assigned to them when none were heard
+    /// recently
+    fn score(&self) -> Vec<(u32, u64)> {
+        let mut active: HashMap<u32, u64> = HashMap::new();
+        let mut assigned: HashMap<u32, u64> = HashMap::new();
+
+        for (i, id) in self.rank().into_iter().take(self.stations).enumerate() {
+            let gs = match self.gs_info.get(&id) {
+                Some(val) => val,
+                None => continue,
+            };
+            let weight = (self.stations - i) as u64;
+
+            if gs
+                .last_heard
+                .is_some_and(|x| x.elapsed().as_secs() <= self.spdu_timeout)
+            {
+                for band in gs.active_bands.iter() {
+                    *active.entry(*band).or_insert(0) += weight;
+                }
+            }
+            for band in gs.assigned_bands.iter() {
+                *assigned.entry(*band).or_insert(0) += weight;
+            }
+        }
+
+        let mut scores: Vec<(u32, u64)> = if active.is_empty() { assigned } else { active }
+            .into_iter()
+            .filter(|x| self.bands.contains_key(&x.0))
+            .collect();
+        scores.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores
+    }
+
+    /// Checks whether the current band dropped out of the bands of the top stations since they were last ranked
+    fn current_band_dropped(&mut self) -> bool {
+        if !self.rescorer.due() {
+            return false;
+        }
+
+        let current_band = self.rescorer.current_band;
+        let scores = self.score();
+        if scores.is_empty() || scores.iter().any(|x| x.0 == current_band) {
+            return false;
+        }
+
+        info!(
+            "[region] band {} no longer used by the top ground stations. Chooser elects to switch bands.",
+            current_band
+        );
+        true
+    }
+}
+
+impl<'a> ChooserPlugin for RegionChooserPlugin<'a> {
+    fn choose(&mut self) -> Result<&'a Vec<u32>, String> {
+        let scores = self.score();
+        let band = self.rescorer.pick(&scores).ok_or(
+            "Candidate bands is empty: no bands known for the top ground stations".to_string(),
+        )?;
+
+        self.bands
+            .get(&band)
+            .ok_or(format!("Invalid band: {}", band))
+    }
+
+    fn on_recv_frame(&mut self, _frame: &Frame) -> bool {
+        self.current_band_dropped()
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        self.rescorer.on_quiet();
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::state::{PositionReport, PositionReports, PropagationReport, SharedState};
+    use std::time::Instant;
+
+    fn props(spec: &str) -> HashMap<&str, &str> {
+        spec.split(',').filter_map(|x| x.split_once('=')).collect()
+    }
+
+    /// Flight reporting `positions`, each relayed by the ground stations listed with it
+    fn flight(shared_state: &SharedState, id: &str, positions: &[((f64, f64), &[u8])]) {
+        let positions = positions
+            .iter()
+            .map(|((lat, lon), stations)| PositionReport {
+                position: vec![*lat, *lon],
+                freq: 8927,
+                propagation: stations
+                    .iter()
+                    .map(|x| PropagationReport {
+                        id: *x,
+                        name: String::new(),
+                        location: vec![],
+                        bands: vec![],
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        shared_state.flight_posrpt.insert(
+            id.to_string(),
+            PositionReports {
+                last_heard: Instant::now(),
+                positions,
+            },
+        );
+    }
+
+    fn plugin<'a>(
+        config: &'a Config,
+        props: &'a HashMap<&'a str, &'a str>,
+        shared_state: &SharedState,
+    ) -> RegionChooserPlugin<'a> {
+        RegionChooserPlugin::new(
+            config,
+            props,
+            shared_state.gs_info.clone(),
+            shared_state.flight_posrpt.clone(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_areas() {
+        let parse = |spec: &str, location| parse_area(&props(spec), location);
+
+        assert_eq!(
+            parse("bbox=65:-30/50:-5", None),
+            Ok(Some(Area::BBox(50.0, 65.0, -30.0, -5.0)))
+        );
+        assert_eq!(
+            parse("polygon=50:-30/65:-30/65:-5", None),
+            Ok(Some(Area::Polygon(vec![
+                (50.0, -30.0),
+                (65.0, -30.0),
+                (65.0, -5.0)
+            ])))
+        );
+        assert_eq!(
+            parse("radius=500", Some((53.3, -6.3))),
+            Ok(Some(Area::Radius((53.3, -6.3), 500.0)))
+        );
+        assert_eq!(parse("rank=distance", None), Ok(None));
+
+        for spec in [
+            "bbox=50:-30",
+            "bbox=50:-30/65:-5/70:0",
+            "bbox=50:-30/north",
+            "polygon=50:-30/65:-5",
+            "radius=500",
+            "radius=far",
+        ] {
+            assert!(parse(spec, None).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn bounding_boxes_cross_the_antimeridian() {
+        let area = parse_area(&props("bbox=40:170/60:-160"), None)
+            .unwrap()
+            .unwrap();
+
+        assert!(area.contains(50.0, 175.0));
+        assert!(area.contains(50.0, -170.0));
+        assert!(area.contains(40.0, 180.0));
+        assert!(!area.contains(50.0, 0.0));
+        assert!(!area.contains(50.0, 165.0));
+        assert!(!area.contains(30.0, 175.0));
+        assert_eq!(area.center(), (50.0, -175.0));
+
+        let area = parse_area(&props("bbox=50:-30/65:-5"), None)
+            .unwrap()
+            .unwrap();
+        assert!(area.contains(55.0, -10.0));
+        assert!(!area.contains(55.0, 175.0));
+        assert_eq!(area.center(), (57.5, -17.5));
+    }
+
+    #[test]
+    fn ranks_by_distance_or_traffic() {
+        let config = config::test_config(&["--location", "21,-157"]);
+        let shared_state = SharedState::new(&config, "rx");
+        // Molokai, then San Francisco
+        let distance = props("rank=distance");
+        assert_eq!(
+            plugin(&config, &distance, &shared_state).rank()[..2],
+            [2, 1]
+        );
+
+        // Until any traffic is heard inside the area, stations are ranked by distance
+        let traffic = props("bbox=40:170/60:-160");
+        let by_traffic = plugin(&config, &traffic, &shared_state);
+        assert_eq!(by_traffic.rank()[..2], [2, 1]);
+
+        flight(
+            &shared_state,
+            "UAL1",
+            &[((55.0, 178.0), &[9, 16]), ((52.0, -170.0), &[9])],
+        );
+        // Outside the area
+        flight(&shared_state, "UAL2", &[((21.0, -157.0), &[2, 2, 2])]);
+        assert_eq!(by_traffic.rank()[..3], [9, 16, 2]);
+
+        let area_by_distance = props("bbox=40:170/60:-160,rank=distance");
+        assert_eq!(
+            plugin(&config, &area_by_distance, &shared_state).rank()[..2],
+            [2, 1]
+        );
+    }
+
+    #[test]
+    fn scores_bands_of_the_top_stations() {
+        let config = config::test_config(&["--location", "21,-157"]);
+        let shared_state = SharedState::new(&config, "rx");
+        let props = props("rank=distance,stations=2");
+        let plugin = plugin(&config, &props, &shared_state);
+
+        // Molokai and San Francisco are assigned the same bands, weighing 2 and 1
+        let scores = plugin.score();
+        assert_eq!(scores.len(), 8);
+        assert!(scores.iter().all(|x| x.1 == 3));
+        assert_eq!(scores[0], (5, 3));
+
+        // Bands ground stations were heard on recently take over from the ones they are assigned
+        for (id, bands) in [(2, vec![13]), (1, vec![13, 21])] {
+            let mut gs = shared_state.gs_info.get_mut(&id).unwrap();
+            gs.active_bands = bands;
+            gs.last_heard = Some(Instant::now());
+        }
+        assert_eq!(plugin.score(), [(13, 3), (21, 1)]);
+    }
+}
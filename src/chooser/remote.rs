@@ -0,0 +1,755 @@
+use crate::chooser::{self, ChooserContext, ChooserPlugin, Prepare, Spec};
+use crate::client;
+use crate::config::{self, Config, FrequencyBandMap};
+use crate::hfdl::Frame;
+use crate::state::{FrequencyStats, GroundStationMap};
+use actix_web::web::Data;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use log::*;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+pub const NAME: &str = "remote";
+
+/// Peer band decisions are delegated to
+#[derive(Clone)]
+enum Transport {
+    Http { client: Client, url: Url },
+    Unix(PathBuf),
+}
+
+impl Transport {
+    /// Parses http://HOST[:PORT][/PATH]
+    fn http(url: &str) -> Result<Self, String> {
+        let parsed = Url::parse(url).map_err(|e| format!("'url' is invalid: {}: {}", url, e))?;
+
+        if parsed.scheme() != "http" {
+            return Err(format!("'url' must start with http:// : {}", url));
+        }
+        if parsed.host_str().is_none_or(|x| x.is_empty()) {
+            return Err(format!("'url' has no host: {}", url));
+        }
+
+        Ok(Transport::Http {
+            client: client::new(),
+            url: parsed,
+        })
+    }
+
+    /// Sends a JSON-RPC request, returning the raw reply. `timeout` bounds the whole exchange
+    async fn exchange(&self, request: &Value, timeout: Duration) -> Result<String, String> {
+        match self {
+            Transport::Http { client, url } => {
+                let (status, body) =
+                    client::post_json(client, url.as_str(), None, request, timeout)
+                        .await
+                        .map_err(|e| format!("{}: {}", self, e))?;
+                if status != 200 {
+                    return Err(format!("Peer replied with status {}: {}", status, body));
+                }
+
+                Ok(body)
+            }
+            Transport::Unix(path) => {
+                let exchange = async {
+                    let mut stream = UnixStream::connect(path).await?;
+                    stream
+                        .write_all(format!("{}\n", request).as_bytes())
+                        .await?;
+
+                    let mut line = String::new();
+                    BufReader::new(stream).read_line(&mut line).await?;
+
+                    Ok::<String, io::Error>(line)
+                };
+
+                match time::timeout(timeout, exchange).await {
+                    Ok(Ok(line)) => Ok(line),
+                    Ok(Err(e)) => Err(format!("{}: {}", self, e)),
+                    Err(_) => Err(format!(
+                        "{}: timed out after {}ms",
+                        self,
+                        timeout.as_millis()
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Http { url, .. } => write!(f, "{}", url),
+            Transport::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Frames heard during the current session
+#[derive(Default)]
+struct SessionFrames {
+    total: u64,
+    kinds: HashMap<&'static str, u64>,
+    positions: u64,
+    last_frame: Option<Instant>,
+}
+
+impl SessionFrames {
+    fn record(&mut self, frame: &Frame) {
+        self.total += 1;
+        if let Some(kind) = frame.kind() {
+            *self.kinds.entry(kind.label()).or_insert(0) += 1;
+        }
+        if frame
+            .hfdl
+            .lpdu
+            .as_ref()
+            .and_then(|x| x.hfnpdu.as_ref())
+            .is_some_and(|x| x.pos.is_some())
+        {
+            self.positions += 1;
+        }
+        self.last_frame = Some(Instant::now());
+    }
+}
+
+/// Result of a JSON-RPC reply, or the error the peer returned
+fn result(reply: &str) -> Result<Value, String> {
+    let mut reply: Value =
+        serde_json::from_str(reply).map_err(|e| format!("Invalid reply from peer: {}", e))?;
+
+    if let Some(error) = reply.get("error").filter(|x| !x.is_null()) {
+        return Err(format!("Peer returned an error: {}", error));
+    }
+
+    match reply.get_mut("result") {
+        Some(result) => Ok(result.take()),
+        None => Err("Reply from peer is missing a result".to_string()),
+    }
+}
+
+pub struct RemoteChooserPlugin<'a> {
+    bands: &'a FrequencyBandMap,
+    gs_info: Data<GroundStationMap>,
+    freq_stats: Data<FrequencyStats>,
+    receiver: &'a str,
+
+    transport: Transport,
+    timeout: Duration,
+    delegate_timeout: bool,
+    retry: u64,
+    request_id: u64,
+
+    fallback_name: &'a str,
+    fallback: Box<dyn ChooserPlugin + 'a>,
+    /// Set while the fallback chooser picked the current band
+    fallen_back_at: Option<Instant>,
+    /// Band the peer picked while checking whether it's back
+    pending: Option<u32>,
+    /// Checking whether the peer is back, without holding up frames
+    retrying: Option<JoinHandle<Result<String, String>>>,
+
+    /// Peer's answers fetched by prepare for choose and on_timeout
+    answer: Option<Result<u32, String>>,
+    switch: Option<bool>,
+
+    current_band: u32,
+    session_start: Option<Instant>,
+    frames: SessionFrames,
+}
+
+impl<'a> RemoteChooserPlugin<'a> {
+    pub fn new(
+        config: &'a Config,
+        spec: &'a Spec<'a>,
+        context: &ChooserContext,
+    ) -> Result<Self, String> {
+        let props = &spec.props;
+
+        let transport = match (props.get("url"), props.get("socket")) {
+            (Some(url), None) => Transport::http(url)?,
+            (None, Some(path)) => Transport::Unix(PathBuf::from(path)),
+            _ => return Err("Requires exactly one of 'url' or 'socket'".to_string()),
+        };
+
+        let timeout: u64 = match props.get("timeout_ms") {
+            Some(val) => val
+                .parse()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or(format!("'timeout_ms' has an invalid value: {}", val))?,
+            None => 2000,
+        };
+        let retry: u64 = match props.get("retry") {
+            Some(val) => val
+                .parse()
+                .map_err(|_| format!("'retry' has an invalid value: {}", val))?,
+            None => 300,
+        };
+        let delegate_timeout = match props.get("on_timeout") {
+            Some(&"remote") => true,
+            Some(&"local") | None => false,
+            Some(val) => return Err(format!("'on_timeout' has an invalid value: {}", val)),
+        };
+
+        let fallback_spec = spec
+            .children
+            .get("fallback")
+            .ok_or("Missing 'fallback' chooser, e.g. fallback=[rotate]".to_string())?;
+        let fallback = chooser::get(fallback_spec, config, context)
+            .map_err(|e| format!("fallback ({}): {}", fallback_spec.name, e))?;
+
+        info!(
+            "Remote settings: peer={} timeout={}ms on_timeout={} retry={}s fallback={}",
+            transport,
+            timeout,
+            if delegate_timeout { "remote" } else { "local" },
+            retry,
+            fallback_spec.name
+        );
+
+        Ok(RemoteChooserPlugin {
+            bands: &config.info.bands,
+            gs_info: context.gs_info.clone(),
+            freq_stats: context.freq_stats.clone(),
+            receiver: config
+                .receivers
+                .first()
+                .map(|x| x.name.as_str())
+                .unwrap_or_default(),
+
+            transport,
+            timeout: Duration::from_millis(timeout),
+            delegate_timeout,
+            retry,
+            request_id: 0,
+
+            fallback_name: fallback_spec.name,
+            fallback,
+            fallen_back_at: None,
+            pending: None,
+            retrying: None,
+
+            answer: None,
+            switch: None,
+
+            current_band: 0,
+            session_start: None,
+            frames: SessionFrames::default(),
+        })
+    }
+
+    /// Current session, ground station state and frame statistics sent along with every request
+    fn params(&self) -> Value {
+        let ground_stations: Vec<Value> = self
+            .gs_info
+            .iter()
+            .map(|x| {
+                json!({
+                    "id": x.key(),
+                    "name": x.name,
+                    "position": x.position,
+                    "assigned_bands": x.assigned_bands,
+                    "active_bands": x.active_bands,
+                    "systable_version": x.systable_version,
+                    "age_in_secs": x.last_heard.map(|i| i.elapsed().as_secs()),
+                })
+            })
+            .collect();
+
+        json!({
+            "receiver": self.receiver,
+            "session": {
+                "band": self.current_band,
+                "freqs": self.bands.get(&self.current_band),
+                "fallback": self.fallen_back_at.is_some(),
+                "age_in_secs": self.session_start.map(|x| x.elapsed().as_secs()),
+                "frames": self.frames.total,
+                "frame_kinds": self.frames.kinds,
+                "positions": self.frames.positions,
+                "last_frame_age_in_secs": self.frames.last_frame.map(|x| x.elapsed().as_secs()),
+            },
+            "bands": self.bands,
+            "ground_stations": ground_stations,
+            "freq_stats": &**self.freq_stats,
+        })
+    }
+
+    fn request(&mut self, method: &str) -> Value {
+        self.request_id += 1;
+
+        json!({
+            "jsonrpc": "2.0",
+            "id": self.request_id,
+            "method": method,
+            "params": self.params(),
+        })
+    }
+
+    /// Makes a JSON-RPC call to the peer, returning its result
+    async fn call(&mut self, method: &str) -> Result<Value, String> {
+        let request = self.request(method);
+        let reply = self.transport.exchange(&request, self.timeout).await?;
+
+        result(&reply)
+    }
+
+    /// Band the peer chose
+    fn band(&self, result: Value) -> Result<u32, String> {
+        let band = result["band"]
+            .as_u64()
+            .ok_or(format!("Peer result is missing a band: {}", result))?;
+
+        if let Some(reason) = result["reason"].as_str() {
+            info!("[remote] peer chose band {}: {}", band, reason);
+        } else {
+            info!("[remote] peer chose band {}", band);
+        }
+
+        u32::try_from(band)
+            .ok()
+            .filter(|x| self.bands.contains_key(x))
+            .ok_or(format!("Peer chose an invalid band: {}", band))
+    }
+
+    async fn remote_choose(&mut self) -> Result<u32, String> {
+        let result = self.call("choose").await?;
+        self.band(result)
+    }
+
+    async fn remote_on_timeout(&mut self) -> bool {
+        match self.call("on_timeout").await {
+            Ok(result) => {
+                let switch = result["switch"].as_bool().unwrap_or(true);
+                info!("[remote] peer decided switch={} on timeout", switch);
+                switch
+            }
+            Err(e) => {
+                warn!("[remote] {}. Switching bands on timeout", e);
+                true
+            }
+        }
+    }
+
+    /// Asks the peer for a band in the background, to check whether it's back
+    fn start_retry(&mut self) {
+        let request = self.request("choose");
+        let transport = self.transport.clone();
+        let timeout = self.timeout;
+
+        self.retrying = Some(tokio::spawn(async move {
+            transport.exchange(&request, timeout).await
+        }));
+    }
+
+    /// Band the peer chose when asked in the background, once it answered
+    fn retried(&mut self) -> Option<Result<u32, String>> {
+        if !self.retrying.as_ref()?.is_finished() {
+            return None;
+        }
+
+        let reply = self.retrying.take()?.now_or_never()?;
+        Some(
+            reply
+                .map_err(|e| e.to_string())
+                .and_then(|x| x)
+                .and_then(|x| result(&x))
+                .and_then(|x| self.band(x)),
+        )
+    }
+}
+
+impl<'a> ChooserPlugin for RemoteChooserPlugin<'a> {
+    fn prepare(&mut self, event: Prepare) -> LocalBoxFuture<'_, ()> {
+        async move {
+            match event {
+                Prepare::Choose => {
+                    if let Some(retrying) = self.retrying.take() {
+                        retrying.abort();
+                    }
+                    if self.pending.is_some() {
+                        return;
+                    }
+
+                    let answer = self.remote_choose().await;
+                    if answer.is_err() {
+                        self.fallback.prepare(event).await;
+                    }
+                    self.answer = Some(answer);
+                }
+                Prepare::Timeout => {
+                    if self.fallen_back_at.is_some() {
+                        self.fallback.prepare(event).await;
+                    } else if self.delegate_timeout {
+                        self.switch = Some(self.remote_on_timeout().await);
+                    }
+                }
+            }
+        }
+        .boxed_local()
+    }
+
+    fn choose(&mut self) -> Result<&'a Vec<u32>, String> {
+        let picked = match self.pending.take() {
+            Some(band) => Ok(band),
+            None => self
+                .answer
+                .take()
+                .unwrap_or(Err("Peer wasn't asked".to_string())),
+        };
+
+        let band = match picked {
+            Ok(band) => {
+                self.fallen_back_at = None;
+                band
+            }
+            Err(e) => {
+                warn!(
+                    "[remote] {}. Falling back to {} chooser",
+                    e, self.fallback_name
+                );
+
+                let freqs = self.fallback.choose()?.clone();
+                let band = config::band_of(self.bands, &freqs)
+                    .ok_or(format!("Fallback chose unknown frequencies {:?}", freqs))?;
+
+                info!(
+                    "[remote] fallback ({}) chose band {}",
+                    self.fallback_name, band
+                );
+                self.fallen_back_at = Some(Instant::now());
+                band
+            }
+        };
+
+        self.current_band = band;
+        self.session_start = Some(Instant::now());
+        self.frames = SessionFrames::default();
+
+        self.bands
+            .get(&band)
+            .ok_or(format!("Invalid band: {}", band))
+    }
+
+    fn on_recv_frame(&mut self, frame: &Frame) -> bool {
+        self.frames.record(frame);
+
+        let fallen_back_at = match self.fallen_back_at {
+            Some(val) => val,
+            None => return false,
+        };
+
+        if self.retry > 0
+            && self.retrying.is_none()
+            && fallen_back_at.elapsed().as_secs() >= self.retry
+        {
+            self.fallen_back_at = Some(Instant::now());
+            self.start_retry();
+        }
+
+        match self.retried() {
+            Some(Ok(band)) if band == self.current_band => {
+                info!("[remote] peer is back and keeps band {}", band);
+                self.fallen_back_at = None;
+                return false;
+            }
+            Some(Ok(band)) => {
+                info!("[remote] peer is back. Chooser elects to switch bands.");
+                self.pending = Some(band);
+                return true;
+            }
+            Some(Err(e)) => warn!("[remote] {}. Staying on {} chooser", e, self.fallback_name),
+            None => {}
+        }
+
+        self.fallback.on_recv_frame(frame)
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        if self.fallen_back_at.is_some() {
+            return self.fallback.on_timeout();
+        }
+
+        if !self.delegate_timeout {
+            return true;
+        }
+
+        self.switch.take().unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chooser::parse_spec;
+    use crate::state::SharedState;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+    use tokio::net::UnixListener;
+
+    /// Peer answering every choose with `band`, unless told to fail or take `delay_ms` to answer
+    #[derive(Default)]
+    struct Peer {
+        band: AtomicU32,
+        fail: AtomicBool,
+        delay_ms: AtomicU64,
+        requests: AtomicU32,
+    }
+
+    impl Peer {
+        async fn reply(&self, request: &Value) -> Value {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            time::sleep(Duration::from_millis(self.delay_ms.load(Ordering::Relaxed))).await;
+
+            if self.fail.load(Ordering::Relaxed) {
+                return json!({"jsonrpc": "2.0", "id": request["id"], "error": {"code": -32000, "message": "test"}});
+            }
+
+            let result = match request["method"].as_str() {
+                Some("choose") => {
+                    json!({"band": self.band.load(Ordering::Relaxed), "reason": "test"})
+                }
+                _ => json!({"switch": false}),
+            };
+            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+        }
+    }
+
+    async fn http_reply(peer: Data<Peer>, request: web::Json<Value>) -> HttpResponse {
+        HttpResponse::Ok().json(peer.reply(&request).await)
+    }
+
+    /// Starts a peer on a free port, returning it along with its url
+    fn http_peer() -> (Data<Peer>, String) {
+        let peer = Data::new(Peer::default());
+
+        let app_peer = peer.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_peer.clone())
+                .route("/hfdl", web::post().to(http_reply))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        tokio::spawn(server.run());
+
+        (peer, format!("http://127.0.0.1:{}/hfdl", port))
+    }
+
+    /// Bands the peer and the fallback chooser pick
+    fn bands(config: &Config) -> (u32, u32) {
+        let bands = &config.info.bands;
+        (*bands.keys().min().unwrap(), *bands.keys().max().unwrap())
+    }
+
+    fn spec(peer: &str, fallback: u32, extra: &str) -> String {
+        format!(
+            "remote:{},timeout_ms=300{},fallback=[single:band={}]",
+            peer, extra, fallback
+        )
+    }
+
+    fn frame() -> Frame {
+        crate::pipeline::decode(
+            r#"{"hfdl": {"t": {"sec": 0, "usec": 0}, "freq": 8927000, "bit_rate": 1800, "sig_level": -20.0}}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_http_urls() {
+        match Transport::http("http://localhost:8080/hfdl").unwrap() {
+            Transport::Http { url, .. } => {
+                assert_eq!(url.host_str(), Some("localhost"));
+                assert_eq!(url.port_or_known_default(), Some(8080));
+                assert_eq!(url.path(), "/hfdl");
+            }
+            Transport::Unix(_) => unreachable!(),
+        }
+        match Transport::http("http://[::1]").unwrap() {
+            Transport::Http { url, .. } => {
+                assert_eq!(url.port_or_known_default(), Some(80));
+                assert_eq!(url.path(), "/");
+            }
+            Transport::Unix(_) => unreachable!(),
+        }
+
+        assert!(Transport::http("localhost:8080").is_err());
+        assert!(Transport::http("https://localhost").is_err());
+        assert!(Transport::http("http://").is_err());
+        assert!(Transport::http("http://localhost:port/").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn follows_the_peer() {
+        let config = config::test_config(&[]);
+        let context = SharedState::new(&config, "rx").chooser_context();
+        let (band, fallback) = bands(&config);
+
+        let (peer, url) = http_peer();
+        peer.band.store(band, Ordering::Relaxed);
+
+        let spec = spec(&format!("url={}", url), fallback, ",on_timeout=remote");
+        let spec = parse_spec(&spec);
+        let mut plugin = RemoteChooserPlugin::new(&config, &spec, &context).unwrap();
+
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&band]
+        );
+        assert!(!plugin.on_recv_frame(&frame()));
+        assert!(!chooser::on_timeout(&mut plugin).await);
+        assert_eq!(peer.requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn falls_back_on_errors() {
+        let config = config::test_config(&[]);
+        let context = SharedState::new(&config, "rx").chooser_context();
+        let (band, fallback) = bands(&config);
+
+        let (peer, url) = http_peer();
+        peer.band.store(band, Ordering::Relaxed);
+        peer.fail.store(true, Ordering::Relaxed);
+
+        let spec = spec(&format!("url={}", url), fallback, "");
+        let spec = parse_spec(&spec);
+        let mut plugin = RemoteChooserPlugin::new(&config, &spec, &context).unwrap();
+
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&fallback]
+        );
+        assert!(plugin.fallen_back_at.is_some());
+
+        // A peer answering with a band that doesn't exist is no better
+        peer.fail.store(false, Ordering::Relaxed);
+        peer.band.store(u32::MAX, Ordering::Relaxed);
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&fallback]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn falls_back_on_slow_peers() {
+        let config = config::test_config(&[]);
+        let context = SharedState::new(&config, "rx").chooser_context();
+        let (band, fallback) = bands(&config);
+
+        let (peer, url) = http_peer();
+        peer.band.store(band, Ordering::Relaxed);
+        peer.delay_ms.store(2000, Ordering::Relaxed);
+
+        let spec = spec(&format!("url={}", url), fallback, "");
+        let spec = parse_spec(&spec);
+        let mut plugin = RemoteChooserPlugin::new(&config, &spec, &context).unwrap();
+
+        let started_at = Instant::now();
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&fallback]
+        );
+        assert!(started_at.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn switches_back_once_the_peer_is() {
+        let config = config::test_config(&[]);
+        let context = SharedState::new(&config, "rx").chooser_context();
+        let (band, fallback) = bands(&config);
+
+        let (peer, url) = http_peer();
+        peer.band.store(band, Ordering::Relaxed);
+        peer.fail.store(true, Ordering::Relaxed);
+
+        let spec = spec(&format!("url={}", url), fallback, ",retry=1");
+        let spec = parse_spec(&spec);
+        let mut plugin = RemoteChooserPlugin::new(&config, &spec, &context).unwrap();
+
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&fallback]
+        );
+
+        // Still failing when asked again in the background
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(!plugin.on_recv_frame(&frame()));
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!plugin.on_recv_frame(&frame()));
+        assert_eq!(peer.requests.load(Ordering::Relaxed), 2);
+
+        peer.fail.store(false, Ordering::Relaxed);
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(!plugin.on_recv_frame(&frame()));
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(plugin.on_recv_frame(&frame()));
+
+        // The band the peer picked is used without asking it again
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&band]
+        );
+        assert_eq!(peer.requests.load(Ordering::Relaxed), 3);
+        assert!(plugin.fallen_back_at.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn talks_over_unix_sockets() {
+        let config = config::test_config(&[]);
+        let context = SharedState::new(&config, "rx").chooser_context();
+        let (band, fallback) = bands(&config);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peer.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let peer = Data::new(Peer::default());
+        peer.band.store(band, Ordering::Relaxed);
+
+        let server_peer = peer.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (reader, mut writer) = stream.into_split();
+                let mut line = String::new();
+                BufReader::new(reader).read_line(&mut line).await.unwrap();
+
+                let reply = server_peer
+                    .reply(&serde_json::from_str(&line).unwrap())
+                    .await;
+                writer
+                    .write_all(format!("{}\n", reply).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let spec = spec(&format!("socket={}", path.display()), fallback, "");
+        let spec = parse_spec(&spec);
+        let mut plugin = RemoteChooserPlugin::new(&config, &spec, &context).unwrap();
+
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&band]
+        );
+
+        // A peer that went away is as good as a failing one
+        drop(dir);
+        assert_eq!(
+            chooser::choose(&mut plugin).await.unwrap(),
+            config.info.bands[&fallback]
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use log::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Instant;
+
+/// Band picking shared by choosers that score every band and score them again every `interval` seconds. Bands left
+/// because they went quiet are skipped until bands are scored again
+pub struct Rescorer {
+    name: &'static str,
+    interval: u64,
+
+    pub current_band: u32,
+    scored_at: Option<Instant>,
+    quiet: HashSet<u32>,
+}
+
+impl Rescorer {
+    pub fn new(name: &'static str, interval: u64) -> Self {
+        Rescorer {
+            name,
+            interval,
+
+            current_band: 0,
+            scored_at: None,
+            quiet: HashSet::new(),
+        }
+    }
+
+    /// Whether bands are due to be scored again, in which case quiet bands are given another chance
+    pub fn due(&mut self) -> bool {
+        if self
+            .scored_at
+            .is_some_and(|x| x.elapsed().as_secs() < self.interval)
+        {
+            return false;
+        }
+
+        self.quiet.clear();
+        self.scored_at = Some(Instant::now());
+        true
+    }
+
+    /// Picks the best band that didn't go quiet from scores sorted best first, or the best band when they all did
+    pub fn pick<T: fmt::Display>(&mut self, scores: &[(u32, T)]) -> Option<u32> {
+        info!(
+            "[{}] band scores: {}",
+            self.name,
+            scores
+                .iter()
+                .map(|(band, score)| format!("{}={:.2}", band, score))
+                .collect::<Vec<String>>()
+                .join(" ")
+        );
+
+        let band = match scores.iter().find(|(band, _)| !self.quiet.contains(band)) {
+            Some((band, _)) => *band,
+            None => {
+                self.quiet.clear();
+                scores.first()?.0
+            }
+        };
+
+        if self.scored_at.is_none() {
+            self.scored_at = Some(Instant::now());
+        }
+        self.current_band = band;
+
+        Some(band)
+    }
+
+    /// Invoked when nothing was heard on the current band for a while
+    pub fn on_quiet(&mut self) {
+        self.quiet.insert(self.current_band);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_quiet_bands_until_all_are() {
+        let scores = [(13, 3.0), (17, 2.0), (8, 1.0)];
+        let mut rescorer = Rescorer::new("test", 600);
+
+        assert_eq!(rescorer.pick(&scores), Some(13));
+        rescorer.on_quiet();
+        assert_eq!(rescorer.pick(&scores), Some(17));
+        rescorer.on_quiet();
+        assert_eq!(rescorer.pick(&scores), Some(8));
+        rescorer.on_quiet();
+
+        // Every band went quiet: start over from the best one
+        assert_eq!(rescorer.pick(&scores), Some(13));
+        assert_eq!(rescorer.pick(&scores), Some(13));
+        assert_eq!(rescorer.pick::<f64>(&[]), None);
+    }
+
+    #[test]
+    fn rescoring_forgets_quiet_bands() {
+        let scores = [(13, 3), (17, 2)];
+
+        let mut rescorer = Rescorer::new("test", 600);
+        rescorer.pick(&scores);
+        rescorer.on_quiet();
+        assert!(!rescorer.due());
+        assert_eq!(rescorer.pick(&scores), Some(17));
+
+        let mut rescorer = Rescorer::new("test", 0);
+        rescorer.pick(&scores);
+        rescorer.on_quiet();
+        assert!(rescorer.due());
+        assert_eq!(rescorer.pick(&scores), Some(13));
+    }
+}
@@ -1,11 +1,11 @@
 use crate::utils::{get_band, parse_time};
-use crate::{chooser::ChooserPlugin, config::FrequencyBandMap};
+use crate::{chooser::ChooserPlugin, config::FrequencyBandMap, hfdl::Frame};
 use log::*;
 use rand::rngs::ThreadRng;
 use rand::Rng;
 use std::collections::HashMap;
 
-pub const NAME: &'static str = "rotate";
+pub const NAME: &str = "rotate";
 
 pub struct RotateChooserPlugin<'a> {
     bands: &'a FrequencyBandMap,
@@ -30,7 +30,7 @@ impl<'a> RotateChooserPlugin<'a> {
         bands: &'a FrequencyBandMap,
         props: &'a HashMap<&'a str, &'a str>,
     ) -> Result<Self, String> {
-        let mut band_keys: Vec<&u32> = bands.keys().into_iter().collect();
+        let mut band_keys: Vec<&u32> = bands.keys().collect();
         band_keys.sort_unstable();
 
         let mut start_band: u32 = props.get("start").unwrap_or(&"0").parse().unwrap_or(0);
@@ -64,7 +64,7 @@ impl<'a> RotateChooserPlugin<'a> {
             }
         }
 
-        triggers.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        triggers.sort_unstable_by_key(|x| x.0);
 
         let mut last_prefer = 0;
 
@@ -154,7 +154,7 @@ impl<'a> ChooserPlugin for RotateChooserPlugin<'a> {
             );
 
             let mut new_idx = self.band_idx.unwrap();
-            while self.recently_used.iter().any(|&x| x == new_idx) {
+            while self.recently_used.contains(&new_idx) {
                 new_idx = self.rng.gen_range(0..(self.band_keys.len() - 1));
             }
 
@@ -185,7 +185,7 @@ impl<'a> ChooserPlugin for RotateChooserPlugin<'a> {
             .ok_or(format!("Invalid band: {}", band))
     }
 
-    fn on_recv_frame(&mut self, _frame: &serde_json::Value) -> bool {
+    fn on_recv_frame(&mut self, _frame: &Frame) -> bool {
         self.determine_preferred_band_change()
     }
 
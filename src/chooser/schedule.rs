@@ -1,10 +1,11 @@
 use crate::chooser::ChooserPlugin;
 use crate::config::FrequencyBandMap;
+use crate::hfdl::Frame;
 use crate::utils::{get_band, parse_time};
 use log::*;
 use std::collections::HashMap;
 
-pub const NAME: &'static str = "schedule";
+pub const NAME: &str = "schedule";
 
 pub struct ScheduleChooserPlugin<'a> {
     bands: &'a FrequencyBandMap,
@@ -77,7 +78,7 @@ impl<'a> ChooserPlugin for ScheduleChooserPlugin<'a> {
             .ok_or(format!("Invalid band: {}", band))
     }
 
-    fn on_recv_frame(&mut self, _frame: &serde_json::Value) -> bool {
+    fn on_recv_frame(&mut self, _frame: &Frame) -> bool {
         let band = get_band(&self.triggers).unwrap_or(0);
         band != 0 && self.current_band != band
     }
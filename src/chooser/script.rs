@@ -0,0 +1,366 @@
+use crate::chooser::ChooserPlugin;
+use crate::config::{Config, FrequencyBandMap};
+use crate::hfdl::Frame;
+use crate::state::{FrequencyStats, GroundStationMap};
+use actix_web::web::Data;
+use chrono::{DateTime, Timelike, Utc};
+use log::*;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+
+pub const NAME: &str = "script";
+
+fn ints(items: &[u32]) -> Dynamic {
+    items
+        .iter()
+        .map(|x| (*x as i64).into())
+        .collect::<Array>()
+        .into()
+}
+
+fn floats(items: &[f64]) -> Dynamic {
+    items.iter().map(|x| (*x).into()).collect::<Array>().into()
+}
+
+/// Frame fields handed to the script's on_recv_frame
+fn frame_map(frame: &Frame) -> Map {
+    let mut map = Map::new();
+    map.insert("freq".into(), (frame.hfdl.freq as i64).into());
+    map.insert("sig_level".into(), frame.hfdl.sig_level.into());
+    map.insert("bit_rate".into(), (frame.hfdl.bit_rate as i64).into());
+    map.insert(
+        "kind".into(),
+        frame.kind().map_or(Dynamic::UNIT, |x| x.label().into()),
+    );
+    map.insert(
+        "ground_station".into(),
+        frame
+            .ground_station()
+            .map_or(Dynamic::UNIT, |x| (x as i64).into()),
+    );
+    map.insert("uplink".into(), frame.is_uplink().into());
+    map.insert(
+        "callsign".into(),
+        frame.callsign().map_or(Dynamic::UNIT, |x| x.into()),
+    );
+    map.insert(
+        "position".into(),
+        frame
+            .hfdl
+            .lpdu
+            .as_ref()
+            .and_then(|x| x.hfnpdu.as_ref())
+            .and_then(|x| x.pos.as_ref())
+            .map_or(Dynamic::UNIT, |x| floats(&[x.lat, x.lon])),
+    );
+
+    map
+}
+
+/// `ctx` handed to every script call. Cheap to create: ground stations and frequency stats are only read when the
+/// script first looks at them during a call
+#[derive(Clone)]
+struct Context {
+    bands: Rc<Map>,
+    gs_info: Data<GroundStationMap>,
+    freq_stats: Data<FrequencyStats>,
+
+    current_band: u32,
+    now: DateTime<Utc>,
+
+    stations: Option<Array>,
+    freqs: Option<Map>,
+}
+
+impl Context {
+    fn register(engine: &mut Engine) {
+        engine
+            .register_type_with_name::<Context>("Context")
+            .register_get("bands", |ctx: &mut Context| (*ctx.bands).clone())
+            .register_get("stations", Context::stations)
+            .register_get("freqs", Context::freqs)
+            .register_get("current_band", |ctx: &mut Context| ctx.current_band as i64)
+            .register_get("now", |ctx: &mut Context| ctx.now.timestamp())
+            .register_get("hour", |ctx: &mut Context| ctx.now.hour() as i64)
+            .register_get("minute", |ctx: &mut Context| ctx.now.minute() as i64);
+    }
+
+    fn stations(&mut self) -> Array {
+        let gs_info = &self.gs_info;
+
+        self.stations
+            .get_or_insert_with(|| {
+                gs_info
+                    .iter()
+                    .map(|item| {
+                        let mut gs = Map::new();
+                        gs.insert("id".into(), (*item.key() as i64).into());
+                        gs.insert("name".into(), item.name.clone().into());
+                        gs.insert("position".into(), floats(&item.position));
+                        gs.insert("assigned_bands".into(), ints(&item.assigned_bands));
+                        gs.insert("active_bands".into(), ints(&item.active_bands));
+                        gs.insert(
+                            "age_in_secs".into(),
+                            item.last_heard
+                                .map_or(Dynamic::UNIT, |x| (x.elapsed().as_secs() as i64).into()),
+                        );
+                        gs.into()
+                    })
+                    .collect()
+            })
+            .clone()
+    }
+
+    fn freqs(&mut self) -> Map {
+        let freq_stats = &self.freq_stats;
+
+        self.freqs
+            .get_or_insert_with(|| {
+                freq_stats
+                    .iter()
+                    .map(|item| (item.key().to_string().into(), (*item.value() as i64).into()))
+                    .collect()
+            })
+            .clone()
+    }
+}
+
+pub struct ScriptChooserPlugin<'a> {
+    bands: &'a FrequencyBandMap,
+    band_map: Rc<Map>,
+    gs_info: Data<GroundStationMap>,
+    freq_stats: Data<FrequencyStats>,
+
+    path: &'a str,
+    engine: Engine,
+    ast: AST,
+    has_on_recv_frame: bool,
+    has_on_timeout: bool,
+
+    /// Bound to `this` in every script function, kept across calls
+    state: Dynamic,
+    current_band: u32,
+}
+
+impl<'a> ScriptChooserPlugin<'a> {
+    pub fn new(
+        config: &'a Config,
+        props: &'a HashMap<&'a str, &'a str>,
+        gs_info: Data<GroundStationMap>,
+        freq_stats: Data<FrequencyStats>,
+    ) -> Result<Self, String> {
+        let path = *props
+            .get("path")
+            .ok_or("Missing 'path' property".to_string())?;
+        let max_operations: u64 = match props.get("max_operations") {
+            Some(val) => val
+                .parse()
+                .map_err(|_| format!("'max_operations' has an invalid value: {}", val))?,
+            None => 100000,
+        };
+
+        let mut engine = Engine::new();
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_operations(max_operations)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(65536)
+            .set_max_array_size(65536)
+            .set_max_map_size(65536)
+            .disable_symbol("eval");
+        engine.on_print(|x| info!("[script] {}", x));
+        engine.on_debug(|x, _, pos| debug!("[script] {} {}", pos, x));
+        Context::register(&mut engine);
+
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let ast = engine
+            .compile(source)
+            .map_err(|e| format!("Failed to compile {}: {}", path, e))?;
+
+        let has_fn = |name: &str| ast.iter_functions().any(|x| x.name == name);
+        if !has_fn("choose") {
+            return Err(format!("{} doesn't define fn choose(ctx)", path));
+        }
+        let has_on_recv_frame = has_fn("on_recv_frame");
+        let has_on_timeout = has_fn("on_timeout");
+
+        info!(
+            "Script settings: path={} max_operations={} on_recv_frame={} on_timeout={}",
+            path, max_operations, has_on_recv_frame, has_on_timeout
+        );
+
+        let mut band_map = Map::new();
+        for (band, freqs) in config.info.bands.iter() {
+            band_map.insert(band.to_string().into(), ints(freqs));
+        }
+
+        Ok(ScriptChooserPlugin {
+            bands: &config.info.bands,
+            band_map: Rc::new(band_map),
+            gs_info,
+            freq_stats,
+
+            path,
+            engine,
+            ast,
+            has_on_recv_frame,
+            has_on_timeout,
+
+            state: Map::new().into(),
+            current_band: 0,
+        })
+    }
+
+    fn context(&self) -> Context {
+        Context {
+            bands: self.band_map.clone(),
+            gs_info: self.gs_info.clone(),
+            freq_stats: self.freq_stats.clone(),
+
+            current_band: self.current_band,
+            now: Utc::now(),
+
+            stations: None,
+            freqs: None,
+        }
+    }
+
+    fn call<T: Clone + Send + Sync + 'static>(
+        &mut self,
+        name: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Result<T, String> {
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.state);
+
+        self.engine
+            .call_fn_with_options::<T>(options, &mut Scope::new(), &self.ast, name, args)
+            .map_err(|e| format!("{} in {}: {}", name, self.path, e))
+    }
+
+    fn call_choose(&mut self) -> Result<u32, String> {
+        let ctx = self.context();
+        let band = self.call::<i64>("choose", (ctx,))?;
+
+        u32::try_from(band)
+            .ok()
+            .filter(|x| self.bands.contains_key(x))
+            .ok_or(format!(
+                "choose in {} returned an invalid band: {}",
+                self.path, band
+            ))
+    }
+}
+
+impl<'a> ChooserPlugin for ScriptChooserPlugin<'a> {
+    fn choose(&mut self) -> Result<&'a Vec<u32>, String> {
+        let band = match self.call_choose() {
+            Ok(band) => band,
+            Err(e) => {
+                error!("[script] {}", e);
+
+                let band = match self.current_band {
+                    0 => self.bands.keys().min().copied().ok_or(e)?,
+                    band => band,
+                };
+                warn!("[script] choose failed, falling back to band {}", band);
+                band
+            }
+        };
+
+        self.current_band = band;
+        self.bands
+            .get(&band)
+            .ok_or(format!("Invalid band: {}", band))
+    }
+
+    fn on_recv_frame(&mut self, frame: &Frame) -> bool {
+        if !self.has_on_recv_frame {
+            return false;
+        }
+
+        let ctx = self.context();
+        match self.call::<bool>("on_recv_frame", (ctx, frame_map(frame))) {
+            Ok(switch) => switch,
+            Err(e) => {
+                error!("[script] {}", e);
+                false
+            }
+        }
+    }
+
+    fn on_timeout(&mut self) -> bool {
+        if !self.has_on_timeout {
+            return true;
+        }
+
+        let ctx = self.context();
+        match self.call::<bool>("on_timeout", (ctx,)) {
+            Ok(switch) => switch,
+            Err(e) => {
+                error!("[script] {}", e);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::pipeline;
+    use crate::state::SharedState;
+    use std::io::Write;
+
+    const SCRIPT: &str = r#"
+        fn choose(ctx) {
+            let bands = ctx.bands.keys().map(|x| parse_int(x));
+            bands.sort();
+            bands[bands.len() - 1]
+        }
+
+        fn on_recv_frame(ctx, frame) {
+            this.frames = (this.frames ?? 0) + 1;
+            this.frames == 2 && ctx.stations.len() > 0 && ctx.current_band > 0 && frame.freq == 8927000
+        }
+    "#;
+
+    #[test]
+    fn scripts_read_context_and_frames() {
+        let mut script = tempfile::NamedTempFile::new().unwrap();
+        script.write_all(SCRIPT.as_bytes()).unwrap();
+        let path = script.path().to_str().unwrap().to_string();
+
+        let config = config::test_config(&[]);
+        let state = SharedState::new(&config, "rx");
+        let props = HashMap::from([("path", path.as_str())]);
+        let mut plugin = ScriptChooserPlugin::new(
+            &config,
+            &props,
+            state.gs_info.clone(),
+            state.freq_stats.clone(),
+        )
+        .unwrap();
+
+        let highest = config.info.bands.keys().max().unwrap();
+        assert_eq!(plugin.choose().unwrap(), &config.info.bands[highest]);
+
+        let frame = pipeline::decode(
+            r#"{"hfdl": {"t": {"sec": 0, "usec": 0}, "freq": 8927000, "bit_rate": 1800, "sig_level": -20.0}}"#,
+        )
+        .unwrap();
+        assert!(!plugin.on_recv_frame(&frame));
+        assert!(plugin.on_recv_frame(&frame));
+        assert!(!plugin.on_recv_frame(&frame));
+
+        // Left out of the script
+        assert!(plugin.on_timeout());
+    }
+}
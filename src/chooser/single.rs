@@ -1,7 +1,7 @@
-use crate::{chooser::ChooserPlugin, config::FrequencyBandMap};
+use crate::{chooser::ChooserPlugin, config::FrequencyBandMap, hfdl::Frame};
 use std::collections::HashMap;
 
-pub const NAME: &'static str = "single";
+pub const NAME: &str = "single";
 
 pub struct SingleChooserPlugin<'a> {
     bands: &'a FrequencyBandMap,
@@ -38,7 +38,7 @@ impl<'a> ChooserPlugin for SingleChooserPlugin<'a> {
             .ok_or(format!("Invalid band: {}", band))
     }
 
-    fn on_recv_frame(&mut self, _frame: &serde_json::Value) -> bool {
+    fn on_recv_frame(&mut self, _frame: &Frame) -> bool {
         false
     }
 
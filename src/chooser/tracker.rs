@@ -9,7 +9,7 @@ use rand::seq::SliceRandom;
 use std::collections::HashMap;
 use std::time::Instant;
 
-pub const NAME: &'static str = "tracker";
+pub const NAME: &str = "tracker";
 
 pub struct TrackerChooserPlugin<'a> {
     bands: &'a FrequencyBandMap,
@@ -17,7 +17,8 @@ pub struct TrackerChooserPlugin<'a> {
 
     rng: ThreadRng,
 
-    target_id: u8,
+    /// Target ground station IDs and how much each one counts towards a band's score
+    targets: Vec<(u8, f64)>,
     spdu_timeout: u64,
     last_heard_timeout: u64,
     last_heard: Option<Instant>,
@@ -25,55 +26,70 @@ pub struct TrackerChooserPlugin<'a> {
     current_band: u32,
 }
 
+/// Resolves a ground station ID or name prefix to its ID
+fn resolve_target(gs_info: &GroundStationMap, target_gs: &str) -> Result<u8, String> {
+    if let Ok(id) = target_gs.parse::<u8>() {
+        if gs_info.get(&id).is_none() {
+            return Err(format!("{} is not a valid ground station ID", id));
+        }
+
+        return Ok(id);
+    }
+
+    for item in gs_info.iter() {
+        if item.value().name.starts_with(target_gs) {
+            return Ok(*item.key());
+        }
+    }
+
+    Err(format!(
+        "'{}' doesn't match any ground station name prefixes",
+        target_gs
+    ))
+}
+
 impl<'a> TrackerChooserPlugin<'a> {
     pub fn new(
         config: &'a Config,
         props: &'a HashMap<&'a str, &'a str>,
         gs_info: Data<GroundStationMap>,
     ) -> Result<Self, String> {
-        let target_gs = match props.get("target") {
-            Some(prefix) => *prefix,
+        let target = match props.get("target") {
+            Some(val) => *val,
             None => return Err("Missing 'target' property".to_string()),
         };
 
-        let target_id: u8 = match target_gs.parse::<u8>() {
-            Ok(id) => {
-                if gs_info.get(&id).is_none() {
-                    return Err(format!("{} is not a valid ground station ID", id));
-                }
+        let mut targets: Vec<(u8, f64)> = vec![];
+        for stanza in target.split('/').filter(|x| !x.is_empty()) {
+            let (target_gs, weight) = match stanza.rsplit_once('*') {
+                Some((target_gs, weight)) => match weight.parse::<f64>() {
+                    Ok(val) if val > 0.0 => (target_gs, val),
+                    _ => return Err(format!("'{}' has an invalid target weight", stanza)),
+                },
+                None => (stanza, 1.0),
+            };
 
-                id
+            let id = resolve_target(&gs_info, target_gs)?;
+            if targets.iter().any(|x| x.0 == id) {
+                return Err(format!("Ground station #{} is targeted more than once", id));
             }
-            Err(_) => {
-                let mut id: Option<u8> = None;
-                for item in gs_info.iter() {
-                    if item.value().name.starts_with(target_gs) {
-                        id = Some(*item.key());
-                        break;
-                    }
-                }
 
-                match id {
-                    Some(val) => val,
-                    None => {
-                        return Err(format!(
-                            "'{}' doesn't match any ground station name prefixes",
-                            target_gs
-                        ))
-                    }
-                }
-            }
-        };
+            targets.push((id, weight));
+        }
+
+        if targets.is_empty() {
+            return Err("'target' property doesn't name any ground stations".to_string());
+        }
 
         let last_heard_timeout = props
             .get("last_heard_timeout")
             .unwrap_or(&"DEFAULT")
             .parse()
-            .unwrap_or((config.spdu_timeout / 3) as u64);
+            .unwrap_or(config.spdu_timeout / 3);
 
         info!(
-            "Tracker settings: target_id={} last_heard_timeout={}s",
-            target_id, last_heard_timeout
+            "Tracker settings: targets={:?} last_heard_timeout={}s",
+            targets, last_heard_timeout
         );
 
         Ok(TrackerChooserPlugin {
@@ -82,7 +98,7 @@ impl<'a> TrackerChooserPlugin<'a> {
 
             rng: rand::thread_rng(),
 
-            target_id,
+            targets,
             last_heard_timeout,
             spdu_timeout: config.spdu_timeout,
             last_heard: None,
@@ -92,50 +108,74 @@ impl<'a> TrackerChooserPlugin<'a> {
     }
 
     fn frame_involves_target(&self, entity: &Entity) -> bool {
-        entity.entity_type.eq_ignore_ascii_case("ground station") && entity.id == self.target_id
+        entity.entity_type.eq_ignore_ascii_case("ground station")
+            && self.targets.iter().any(|x| x.0 == entity.id)
+    }
+
+    fn is_fresh(&self, last_heard: Option<Instant>) -> bool {
+        last_heard.is_some_and(|x| x.elapsed().as_secs() <= self.spdu_timeout)
+    }
+
+    /// Sums the weights of the targets freshly heard active on each band, or of the targets assigned to each
+    /// band when none of them have been heard recently
+    fn score(&self) -> (HashMap<u32, f64>, bool) {
+        let mut active: HashMap<u32, f64> = HashMap::new();
+        let mut assigned: HashMap<u32, f64> = HashMap::new();
+
+        for &(id, weight) in self.targets.iter() {
+            let gs = match self.gs_info.get(&id) {
+                Some(val) => val,
+                None => continue,
+            };
+
+            if self.is_fresh(gs.last_heard) {
+                for band in gs.active_bands.iter() {
+                    *active.entry(*band).or_insert(0.0) += weight;
+                }
+            }
+            for band in gs.assigned_bands.iter() {
+                *assigned.entry(*band).or_insert(0.0) += weight;
+            }
+        }
+
+        active.remove(&self.current_band);
+        assigned.remove(&self.current_band);
+
+        if active.is_empty() {
+            (assigned, false)
+        } else {
+            (active, true)
+        }
     }
 }
 
 impl<'a> ChooserPlugin for TrackerChooserPlugin<'a> {
     fn choose(&mut self) -> Result<&'a Vec<u32>, String> {
-        let gs = match self.gs_info.get(&self.target_id) {
-            Some(val) => val,
-            None => return Err(format!("Invalid target GS ID: #{}", self.target_id)),
-        };
+        let (scores, fresh) = self.score();
+        if fresh {
+            info!("Found fresh active bands for targets: {:?}", scores);
+        } else {
+            info!(
+                "No or stale active bands found. Using assigned bands for targets: {:?}",
+                scores
+            );
+        }
 
-        let active_bands: Vec<u32> = gs
-            .active_bands
-            .clone()
+        let mut bands: Vec<(u32, f64)> = scores
             .into_iter()
-            .filter(|&x| x != self.current_band)
+            .filter(|x| self.bands.contains_key(&x.0))
             .collect();
 
-        let mut bands: Vec<u32> = if active_bands.is_empty()
-            || gs.last_heard.unwrap_or(Instant::now()).elapsed().as_secs() > self.spdu_timeout
-        {
-            info!(
-                "No or stale active bands found. Using assigned bands for target: {:?}",
-                gs.assigned_bands
-            );
-            gs.assigned_bands
-                .clone()
-                .into_iter()
-                .filter(|&x| x != self.current_band)
-                .collect()
-        } else {
-            info!("Found fresh active bands for target: {:?}", gs.active_bands);
-            active_bands
-        };
-
         if bands.is_empty() {
             return Err(format!(
-                "Candidate bands is empty: last_heard={:?} spdu_timeout={}",
-                gs.last_heard, self.spdu_timeout
+                "Candidate bands is empty: targets={:?} spdu_timeout={}",
+                self.targets, self.spdu_timeout
             ));
         }
 
         bands.shuffle(&mut self.rng);
-        self.current_band = bands[0];
+        bands.sort_by(|a, b| b.1.total_cmp(&a.1));
+        self.current_band = bands[0].0;
         self.last_heard = None;
 
         self.bands
@@ -143,39 +183,41 @@ impl<'a> ChooserPlugin for TrackerChooserPlugin<'a> {
             .ok_or(format!("Invalid band: {}", self.current_band))
     }
 
-    fn on_recv_frame(&mut self, frame: &serde_json::Value) -> bool {
-        let msg: Frame = match serde_json::from_value(frame.clone()) {
-            Ok(val) => val,
-            Err(e) => {
-                error!("Bad JSON decode of frame: {}", e);
-                return false;
-            }
-        };
-
-        if let Some(lpdu) = msg.hfdl.lpdu {
+    fn on_recv_frame(&mut self, frame: &Frame) -> bool {
+        if let Some(ref lpdu) = frame.hfdl.lpdu {
             if self.frame_involves_target(&lpdu.dst) || self.frame_involves_target(&lpdu.src) {
                 self.last_heard = Some(Instant::now());
             }
-        } else if let Some(spdu) = msg.hfdl.spdu {
+        } else if let Some(ref spdu) = frame.hfdl.spdu {
             if self.frame_involves_target(&spdu.src) {
                 self.last_heard = Some(Instant::now());
             }
         }
 
         if self.last_heard.is_none() {
-            if let Some(gs) = self.gs_info.get(&self.target_id) {
-                if gs.last_heard.is_some()
-                    && gs.last_heard.unwrap().elapsed().as_secs() < self.spdu_timeout
-                    && !gs.active_bands.iter().any(|&x| x == self.current_band)
-                {
-                    info!(
-                        "New active bands for {}. Chooser elects to switch bands (no GS #{} activity seen thus far)",
-                        gs.name,
-                        self.target_id
-                    );
-                    return true;
+            let mut active_elsewhere: Option<String> = None;
+            let mut active_here = false;
+            for &(id, _) in self.targets.iter() {
+                if let Some(gs) = self.gs_info.get(&id) {
+                    if !self.is_fresh(gs.last_heard) {
+                        continue;
+                    }
+
+                    if gs.active_bands.contains(&self.current_band) {
+                        active_here = true;
+                    } else if active_elsewhere.is_none() {
+                        active_elsewhere = Some(gs.name.clone());
+                    }
                 }
             }
+
+            if let (Some(name), false) = (active_elsewhere, active_here) {
+                info!(
+                    "New active bands for {}. Chooser elects to switch bands (no target GS activity seen thus far)",
+                    name
+                );
+                return true;
+            }
         }
 
         let elapsed_secs = self
@@ -186,8 +228,8 @@ impl<'a> ChooserPlugin for TrackerChooserPlugin<'a> {
         let change_bands = elapsed_secs >= self.last_heard_timeout;
         if change_bands {
             info!(
-                "Been {}s (timeouts after {}s) since last target GS #{} frame. Chooser elects to switch bands.",
-                elapsed_secs, self.last_heard_timeout, self.target_id
+                "Been {}s (timeouts after {}s) since last target GS frame. Chooser elects to switch bands.",
+                elapsed_secs, self.last_heard_timeout
             );
         }
 
@@ -198,3 +240,132 @@ impl<'a> ChooserPlugin for TrackerChooserPlugin<'a> {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::state::SharedState;
+    use serde_json::json;
+
+    fn props(spec: &str) -> HashMap<&str, &str> {
+        spec.split(',').filter_map(|x| x.split_once('=')).collect()
+    }
+
+    /// Logon confirm sent by a ground station
+    fn frame_from(gs_id: u8) -> Frame {
+        serde_json::from_value(json!({"hfdl": {
+            "t": {"sec": 0, "usec": 0}, "freq": 17919000, "bit_rate": 1800, "sig_level": -20.0,
+            "lpdu": {
+                "err": false,
+                "src": {"id": gs_id, "type": "Ground station"},
+                "dst": {"id": 12, "type": "Aircraft"},
+                "type": {"id": 159, "name": "Logon confirm"},
+            },
+        }}))
+        .unwrap()
+    }
+
+    fn set_active(shared_state: &SharedState, id: u8, bands: &[u32]) {
+        let mut gs = shared_state.gs_info.get_mut(&id).unwrap();
+        gs.active_bands = bands.to_vec();
+        gs.last_heard = Some(Instant::now());
+    }
+
+    #[test]
+    fn parses_weighted_targets() {
+        let config = config::test_config(&[]);
+        let shared_state = SharedState::new(&config, "rx");
+        let gs_info = &shared_state.gs_info;
+
+        let valid = props("target=1/Barrow*2.5/");
+        let plugin = TrackerChooserPlugin::new(&config, &valid, gs_info.clone()).unwrap();
+        assert_eq!(plugin.targets, [(1, 1.0), (9, 2.5)]);
+
+        for spec in [
+            "last_heard_timeout=60",
+            "target=/",
+            "target=99",
+            "target=Nowhere",
+            "target=1*0",
+            "target=1*-1",
+            "target=1*heavy",
+            "target=1/San Francisco",
+        ] {
+            let invalid = props(spec);
+            assert!(
+                TrackerChooserPlugin::new(&config, &invalid, gs_info.clone()).is_err(),
+                "{}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn picks_the_band_with_the_most_target_weight() {
+        let config = config::test_config(&[]);
+        let shared_state = SharedState::new(&config, "rx");
+        let props = props("target=San Francisco/Barrow*3");
+        let mut plugin =
+            TrackerChooserPlugin::new(&config, &props, shared_state.gs_info.clone()).unwrap();
+
+        // Bands both are assigned outweigh the ones only Barrow is assigned, whatever the shuffle
+        let (scores, fresh) = plugin.score();
+        assert!(!fresh);
+        assert_eq!(scores[&5], 4.0);
+        assert_eq!(scores[&2], 3.0);
+        assert_eq!(scores[&13], 1.0);
+
+        set_active(&shared_state, 1, &[13, 21]);
+        set_active(&shared_state, 9, &[17, 21]);
+        assert_eq!(plugin.choose().unwrap(), &config.info.bands[&21]);
+
+        // The band listened to is left out so the next pick goes elsewhere
+        assert_eq!(plugin.choose().unwrap(), &config.info.bands[&17]);
+        assert_eq!(plugin.choose().unwrap(), &config.info.bands[&21]);
+
+        // Stale activity falls back to the assigned bands
+        shared_state.gs_info.get_mut(&1).unwrap().last_heard = None;
+        shared_state.gs_info.get_mut(&9).unwrap().last_heard = None;
+        let (_, fresh) = plugin.score();
+        assert!(!fresh);
+    }
+
+    #[test]
+    fn stays_while_targets_are_heard_here() {
+        let config = config::test_config(&[]);
+        let shared_state = SharedState::new(&config, "rx");
+        let props = props("target=1/9*2");
+        let mut plugin =
+            TrackerChooserPlugin::new(&config, &props, shared_state.gs_info.clone()).unwrap();
+
+        set_active(&shared_state, 9, &[17]);
+        assert_eq!(plugin.choose().unwrap(), &config.info.bands[&17]);
+        assert!(!plugin.on_recv_frame(&frame_from(2)));
+
+        // Another target active elsewhere doesn't matter while one is active here
+        set_active(&shared_state, 1, &[13]);
+        assert!(!plugin.on_recv_frame(&frame_from(2)));
+
+        // Until the target here moves on before it was heard
+        set_active(&shared_state, 9, &[21]);
+        assert!(plugin.on_recv_frame(&frame_from(2)));
+
+        // Once a target is heard here, activity elsewhere no longer matters
+        assert!(!plugin.on_recv_frame(&frame_from(9)));
+        assert!(!plugin.on_recv_frame(&frame_from(2)));
+    }
+
+    #[test]
+    fn switches_once_targets_go_quiet() {
+        let config = config::test_config(&[]);
+        let shared_state = SharedState::new(&config, "rx");
+        let props = props("target=9,last_heard_timeout=0");
+        let mut plugin =
+            TrackerChooserPlugin::new(&config, &props, shared_state.gs_info.clone()).unwrap();
+
+        plugin.choose().unwrap();
+        assert!(plugin.on_recv_frame(&frame_from(9)));
+        assert!(plugin.on_timeout());
+    }
+}
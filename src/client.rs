@@ -0,0 +1,52 @@
+use reqwest::Client;
+use serde_json::Value;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time;
+
+/// HTTP client for JSON requests to other services: the swarm leader and remote choosers. Connections are pooled per
+/// client, so each peer keeps its own
+pub fn new() -> Client {
+    Client::builder()
+        .pool_idle_timeout(Duration::from_secs(30))
+        .build()
+        .unwrap()
+}
+
+/// Innermost cause of a request failure, which tells more than reqwest's own message
+fn describe(e: &reqwest::Error) -> String {
+    let mut cause: &dyn Error = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+
+    cause.to_string()
+}
+
+/// Posts a JSON body, returning the status code and response body. `timeout` bounds the whole exchange, from name
+/// resolution to the last byte of the body
+pub async fn post_json(
+    client: &Client,
+    url: &str,
+    token: Option<&str>,
+    body: &Value,
+    timeout: Duration,
+) -> Result<(u16, String), String> {
+    let mut request = client.post(url).json(body);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let exchange = async {
+        let response = request.send().await?;
+        let status = response.status().as_u16();
+
+        Ok::<(u16, String), reqwest::Error>((status, response.text().await?))
+    };
+
+    match time::timeout(timeout, exchange).await {
+        Ok(Ok(val)) => Ok(val),
+        Ok(Err(e)) => Err(describe(&e)),
+        Err(_) => Err(format!("timed out after {}ms", timeout.as_millis())),
+    }
+}
@@ -1,43 +1,412 @@
 use crate::args::Args;
+use crate::attach::Endpoint;
+use crate::geo;
+use crate::libconf;
+use crate::sdr::{self, Sdr, SdrProfile};
+use crate::sink::OutputSpec;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fs;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 pub type GroundStationMap = HashMap<String, GroundStation>;
 pub type FrequencyBandMap = HashMap<u32, Vec<u32>>;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GroundStation {
     pub id: u8,
     pub name: String,
     pub lat: f64,
     pub lon: f64,
     pub assigned: Vec<u32>,
+
+    #[serde(default)]
+    pub freqs: Vec<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HFDLInfo {
     pub stations: GroundStationMap,
     pub bands: FrequencyBandMap,
+
+    #[serde(default)]
+    pub version: Option<u32>,
+
+    #[serde(default)]
     pub raw: String,
+
+    /// Original dumphfdl system table, passed to dumphfdl unchanged when set
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+
+    /// Bands defined by --bands, along with their labels
+    #[serde(skip)]
+    pub custom: BTreeMap<u32, Option<String>>,
+}
+
+impl HFDLInfo {
+    /// Adds custom bands, replacing bands with the same ID and the ones they list in `replaces`. Ground stations are
+    /// then assigned every band one of their frequencies belongs to
+    pub fn apply_bands(&mut self, defs: &[BandDefinition]) -> Result<(), String> {
+        // Without the frequencies a ground station uses, assume it may use any frequency of its assigned bands
+        for station in self.stations.values_mut() {
+            if station.freqs.is_empty() {
+                let mut freqs: Vec<u32> = station
+                    .assigned
+                    .iter()
+                    .filter_map(|x| self.bands.get(x))
+                    .flatten()
+                    .copied()
+                    .collect();
+                freqs.sort_unstable();
+                freqs.dedup();
+
+                station.freqs = freqs;
+            }
+        }
+
+        for def in defs.iter() {
+            if let Some(freq) = def
+                .freqs
+                .iter()
+                .find(|x| !self.bands.values().any(|y| y.contains(x)))
+            {
+                return Err(format!(
+                    "Band {} frequency {} is not in the System Table",
+                    def.id, freq
+                ));
+            }
+        }
+
+        let mut bands = self.bands.clone();
+        for def in defs.iter() {
+            for band in def.replaces.iter() {
+                bands.remove(band);
+            }
+        }
+        for def in defs.iter() {
+            let mut freqs = def.freqs.clone();
+            freqs.sort_unstable();
+            freqs.dedup();
+
+            bands.insert(def.id, freqs);
+        }
+
+        if let Some(freq) = self
+            .bands
+            .values()
+            .flatten()
+            .find(|x| !bands.values().any(|y| y.contains(x)))
+        {
+            return Err(format!(
+                "Frequency {} is no longer in any band once custom bands replace System Table bands",
+                freq
+            ));
+        }
+
+        self.bands = bands;
+        self.custom = defs.iter().map(|x| (x.id, x.label.clone())).collect();
+
+        for station in self.stations.values_mut() {
+            let mut assigned: Vec<u32> = self
+                .bands
+                .iter()
+                .filter(|(_, freqs)| freqs.iter().any(|x| station.freqs.contains(x)))
+                .map(|(band, _)| *band)
+                .collect();
+            assigned.sort_unstable();
+
+            station.assigned = assigned;
+        }
+
+        Ok(())
+    }
+}
+
+/// Band a set of frequencies (kHz) belongs to. When custom bands overlap, the smallest band holding all of them wins
+/// so single frequencies are attributed to the narrowest band they're in
+pub fn band_of(bands: &FrequencyBandMap, freqs: &[u32]) -> Option<u32> {
+    bands
+        .iter()
+        .filter(|(_, x)| !freqs.is_empty() && freqs.iter().all(|y| x.contains(y)))
+        .min_by_key(|(band, x)| (x.len(), **band))
+        .map(|(band, _)| *band)
+}
+
+/// Band defined in the --bands file
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BandDefinition {
+    pub id: u32,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub freqs: Vec<u32>,
+
+    /// System Table bands removed in favour of this one, e.g. when splitting a band in two
+    #[serde(default)]
+    pub replaces: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct BandFile {
+    bands: Vec<BandDefinition>,
+}
+
+fn parse_bands(path: &PathBuf) -> Result<Vec<BandDefinition>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read band definitions {:?}: {}", path, e))?;
+    let file: BandFile = serde_json::from_str(&contents)
+        .map_err(|e| format!("Unable to parse band definitions {:?}: {}", path, e))?;
+
+    for (i, def) in file.bands.iter().enumerate() {
+        if def.id == 0 {
+            return Err("Band ID 0 is reserved".to_string());
+        }
+        if file.bands[..i].iter().any(|x| x.id == def.id) {
+            return Err(format!("Duplicate band ID: {}", def.id));
+        }
+        if def.freqs.is_empty() {
+            return Err(format!("Band {} has no frequencies", def.id));
+        }
+    }
+
+    Ok(file.bands)
+}
+
+/// Frequencies within this many kHz of a band's lowest frequency are grouped into the same band
+const BANDWIDTH_LIMIT: i64 = 500;
+
+fn band_for_freq(bands: &BTreeMap<u32, Vec<u32>>, freq: u32) -> Option<u32> {
+    bands
+        .iter()
+        .find(|(_, freqs)| freqs.contains(&freq))
+        .map(|(band, _)| *band)
+}
+
+/// Derives ground stations and frequency bands from a dumphfdl libconf system table
+pub fn info_from_libconf(contents: &str) -> Result<HFDLInfo, String> {
+    let root = libconf::parse(contents)?;
+    let version = root
+        .get("version")
+        .and_then(|x| x.as_i64())
+        .and_then(|x| u32::try_from(x).ok());
+
+    let stations = match root.get("stations").and_then(|x| x.as_slice()) {
+        Some(val) => val,
+        None => return Err("Missing 'stations' list".to_string()),
+    };
+
+    let mut parsed: Vec<(GroundStation, Vec<u32>)> = vec![];
+    for station in stations {
+        let field = |name: &str| {
+            station.get(name).ok_or(format!(
+                "Ground station is missing '{}': {:?}",
+                name, station
+            ))
+        };
+
+        let id = field("id")?
+            .as_i64()
+            .and_then(|x| u8::try_from(x).ok())
+            .ok_or("Ground station 'id' is not a valid ID".to_string())?;
+        let name = field("name")?
+            .as_str()
+            .ok_or(format!("Ground station #{} 'name' is not a string", id))?;
+        let lat = field("lat")?
+            .as_f64()
+            .ok_or(format!("Ground station #{} 'lat' is not a number", id))?;
+        let lon = field("lon")?
+            .as_f64()
+            .ok_or(format!("Ground station #{} 'lon' is not a number", id))?;
+        let mut freqs: Vec<u32> = field("frequencies")?
+            .as_slice()
+            .ok_or(format!(
+                "Ground station #{} 'frequencies' is not a list",
+                id
+            ))?
+            .iter()
+            .map(|x| x.as_f64().map(|x| x as u32))
+            .collect::<Option<Vec<u32>>>()
+            .ok_or(format!(
+                "Ground station #{} 'frequencies' contains a non-number",
+                id
+            ))?;
+        freqs.sort_unstable();
+
+        parsed.push((
+            GroundStation {
+                id,
+                name: name.to_string(),
+                lat,
+                lon,
+                assigned: vec![],
+                freqs: freqs.clone(),
+            },
+            freqs,
+        ));
+    }
+
+    let mut bands: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for (_, freqs) in parsed.iter() {
+        for &freq in freqs.iter() {
+            let existing = bands
+                .iter()
+                .find(|(_, x)| (freq as i64 - x[0] as i64).abs() <= BANDWIDTH_LIMIT)
+                .map(|(band, _)| *band);
+
+            match existing {
+                Some(band) => {
+                    let freqs = bands.get_mut(&band).unwrap();
+                    if !freqs.contains(&freq) {
+                        freqs.push(freq);
+                        freqs.sort_unstable();
+                    }
+                }
+                None => {
+                    bands.insert(freq / 1000, vec![freq]);
+                }
+            }
+        }
+    }
+
+    let mut gs_map = GroundStationMap::new();
+    for (mut station, freqs) in parsed.into_iter() {
+        let mut assigned: Vec<u32> = freqs
+            .iter()
+            .filter_map(|&x| band_for_freq(&bands, x))
+            .collect();
+        assigned.sort_unstable();
+        assigned.dedup();
+
+        station.assigned = assigned;
+        gs_map.insert(station.name.clone(), station);
+    }
+
+    Ok(HFDLInfo {
+        stations: gs_map,
+        bands: bands.into_iter().collect(),
+        version,
+        raw: contents.to_string(),
+        source: None,
+        custom: BTreeMap::new(),
+    })
+}
+
+/// Name given to the receiver configured by --chooser and the trailing dumphfdl arguments when --receiver isn't used
+pub const DEFAULT_RECEIVER: &str = "default";
+
+/// SDR managed by its own dumphfdl and chooser
+#[derive(Debug, Clone)]
+pub struct ReceiverSpec {
+    pub name: String,
+    pub chooser: String,
+    pub sdr: SdrProfile,
+    pub max_sample_rate: Option<u32>,
+    pub additional_args: Vec<String>,
+}
+
+impl ReceiverSpec {
+    /// Parses NAME[;chooser=SPEC][;sdr=PROFILE][;max-sample-rate=HZ][;args=DUMPHFDL ARGS], defaulting to the global
+    /// settings
+    fn parse(spec: &str, args: &Args, sdr: SdrProfile) -> Result<Self, String> {
+        let mut parts = spec.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+        {
+            return Err(format!(
+                "Receiver name must only contain letters, digits, '-' and '_': {}",
+                spec
+            ));
+        }
+
+        let mut receiver = ReceiverSpec {
+            name: name.to_string(),
+            chooser: args.chooser.clone(),
+            sdr,
+            max_sample_rate: args.max_sample_rate,
+            additional_args: args.additional_args.clone(),
+        };
+
+        for part in parts.filter(|x| !x.trim().is_empty()) {
+            let (key, val) = part.split_once('=').ok_or(format!(
+                "Receiver {} setting is not KEY=VALUE: {}",
+                name, part
+            ))?;
+
+            match key.trim() {
+                "chooser" => receiver.chooser = val.trim().to_string(),
+                "sdr" => receiver.sdr = SdrProfile::from_str(val.trim())?,
+                "max-sample-rate" => {
+                    receiver.max_sample_rate = Some(val.trim().parse().map_err(|_| {
+                        format!("Receiver {} max-sample-rate is not a number: {}", name, val)
+                    })?)
+                }
+                "args" => {
+                    receiver.additional_args =
+                        val.split_whitespace().map(|x| x.to_string()).collect()
+                }
+                _ => return Err(format!("Unknown receiver {} setting: {}", name, key)),
+            }
+        }
+
+        Ok(receiver)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     pub bin: PathBuf,
     pub timeout: u32,
     pub spdu_timeout: u64,
     pub ac_timeout: u64,
+    pub history_bucket: u64,
+    pub history_retention: u64,
     pub end_session_wait: u64,
+    pub shutdown_timeout: u64,
+    pub state_dump: Option<PathBuf>,
+    pub store: Option<PathBuf>,
     pub additional_args: Vec<String>,
+    pub sdr: SdrProfile,
+    pub max_sample_rate: Option<u32>,
+    pub center_freq: bool,
+    pub location: Option<(f64, f64)>,
+    pub receivers: Vec<ReceiverSpec>,
+    pub bands: Vec<BandDefinition>,
+
+    pub stderr_level: LevelFilter,
+    pub stderr_lines: usize,
 
     pub swarm: bool,
+    pub swarm_leader: bool,
     pub host: String,
     pub port: u16,
+    pub swarm_name: Option<String>,
+    pub swarm_heartbeat: u64,
+    pub api_token: Option<String>,
+
+    pub systable_update: Option<PathBuf>,
+
+    pub attach: Option<Endpoint>,
+    pub retune_cmd: Option<PathBuf>,
+
+    pub outputs: Vec<OutputSpec>,
+    pub output_queue: usize,
+
+    pub replay: Vec<PathBuf>,
+    pub replay_speed: Option<f64>,
 
     pub max_bad_child_reads: u32,
+    pub restart_backoff_min: u64,
+    pub restart_backoff_max: u64,
+    pub restart_backoff_jitter: f64,
+    pub healthy_session: u64,
 
     pub info: HFDLInfo,
 }
@@ -49,12 +418,41 @@ impl Config {
             Err(e) => return Err(format!("Unable to read dumphfdl system table: {}", e)),
         };
 
-        serde_json::from_str(&contents)
-            .map_err(|e| format!("Unable to deserialize dumphfdl system table: {}", e))
+        let is_json =
+            path.extension().is_some_and(|x| x == "json") || contents.trim_start().starts_with('{');
+        if is_json {
+            let mut info: HFDLInfo = serde_json::from_str(&contents)
+                .map_err(|e| format!("Unable to deserialize dumphfdl system table: {}", e))?;
+            // Tables converted from libconf keep the original, which knows each ground station's frequencies
+            if let Ok(native) = info_from_libconf(&info.raw) {
+                info.version = info.version.or(native.version);
+                for station in info.stations.values_mut() {
+                    if let Some(val) = native.stations.get(&station.name) {
+                        if station.freqs.is_empty() {
+                            station.freqs = val.freqs.clone();
+                        }
+                    }
+                }
+            }
+
+            return Ok(info);
+        }
+
+        let mut info = info_from_libconf(&contents)
+            .map_err(|e| format!("Unable to parse dumphfdl system table: {}", e))?;
+        info.source = Some(path.to_owned());
+
+        Ok(info)
     }
 
     pub fn from_args(args: &Args) -> Result<Config, String> {
-        if !args.bin.exists() || !args.bin.is_file() {
+        let attach = match args.attach {
+            Some(ref url) => Some(Endpoint::from_str(url)?),
+            None => None,
+        };
+
+        if args.replay.is_empty() && attach.is_none() && (!args.bin.exists() || !args.bin.is_file())
+        {
             return Err(format!(
                 "dumphfdl binary path does not exist or is not a file: {:?}",
                 args.bin
@@ -67,25 +465,220 @@ impl Config {
             ));
         }
 
-        let info = Config::parse_systable(&args.sys_table)?;
+        if let Some(ref cmd) = args.retune_cmd {
+            if !cmd.is_file() {
+                return Err(format!(
+                    "Retune command does not exist or is not a file: {:?}",
+                    cmd
+                ));
+            }
+        }
+
+        for path in args.replay.iter() {
+            if !path.is_file() {
+                return Err(format!(
+                    "Replay file does not exist or is not a file: {:?}",
+                    path
+                ));
+            }
+        }
+
+        let replay_speed = if args.replay_speed.eq_ignore_ascii_case("max") {
+            None
+        } else {
+            match args.replay_speed.trim_end_matches('x').parse::<f64>() {
+                Ok(speed) if speed > 0.0 => Some(speed),
+                _ => {
+                    return Err(format!(
+                        "Replay speed must be a positive number or 'max': {}",
+                        args.replay_speed
+                    ))
+                }
+            }
+        };
+
+        let mut outputs = args
+            .output
+            .iter()
+            .map(|x| OutputSpec::from_str(x))
+            .collect::<Result<Vec<OutputSpec>, String>>()?;
+        if outputs.is_empty() {
+            outputs.push(OutputSpec::Stdout);
+        }
+        if args.output_queue == 0 {
+            return Err("Output queue size must be at least 1".to_string());
+        }
+
+        let stderr_level = LevelFilter::from_str(&args.stderr_level)
+            .map_err(|_| format!("Invalid dumphfdl stderr log level: {}", args.stderr_level))?;
+
+        if !(0.0..=1.0).contains(&args.restart_backoff_jitter) {
+            return Err(format!(
+                "Restart backoff jitter must be between 0.0 and 1.0: {}",
+                args.restart_backoff_jitter
+            ));
+        }
+        if args.restart_backoff_min > args.restart_backoff_max {
+            return Err(format!(
+                "Minimum restart backoff ({}s) exceeds maximum restart backoff ({}s)",
+                args.restart_backoff_min, args.restart_backoff_max
+            ));
+        }
+
+        if args.history_bucket == 0 || args.history_bucket > 86400 {
+            return Err(format!(
+                "History bucket must be between 1 and 86400 seconds: {}",
+                args.history_bucket
+            ));
+        }
+        if args.history_retention == 0 {
+            return Err("History retention must be at least 1 day".to_string());
+        }
+
+        if args.swarm_heartbeat == 0 {
+            return Err("Swarm heartbeat interval must be at least 1 second".to_string());
+        }
+        if args.swarm_leader && args.api_token.as_ref().is_none_or(|x| x.is_empty()) {
+            return Err(
+                "Swarm leader requires an API token for members to authenticate with".to_string(),
+            );
+        }
+
+        let sdr = SdrProfile::from_str(&args.sdr)?;
+
+        let location = args
+            .location
+            .as_deref()
+            .map(geo::parse_location)
+            .transpose()?;
+
+        let mut receivers = args
+            .receiver
+            .iter()
+            .map(|x| ReceiverSpec::parse(x, args, sdr))
+            .collect::<Result<Vec<ReceiverSpec>, String>>()?;
+        if receivers.is_empty() {
+            receivers.push(ReceiverSpec {
+                name: DEFAULT_RECEIVER.to_string(),
+                chooser: args.chooser.clone(),
+                sdr,
+                max_sample_rate: args.max_sample_rate,
+                additional_args: args.additional_args.clone(),
+            });
+        }
+        for (i, receiver) in receivers.iter().enumerate() {
+            if receivers[..i].iter().any(|x| x.name == receiver.name) {
+                return Err(format!("Duplicate receiver name: {}", receiver.name));
+            }
+            if Sdr::new(receiver.sdr, receiver.max_sample_rate)
+                .max_span()
+                .is_none()
+            {
+                return Err(format!(
+                    "Receiver {} max sample rate is below every sample rate {} supports: {:?}",
+                    receiver.name,
+                    receiver.sdr,
+                    receiver.sdr.sample_rates()
+                ));
+            }
+        }
+
+        let bands = match args.bands {
+            Some(ref path) => parse_bands(path)?,
+            None => vec![],
+        };
+        let max_span = receivers
+            .iter()
+            .filter_map(|x| Sdr::new(x.sdr, x.max_sample_rate).max_span())
+            .max()
+            .unwrap_or(0);
+        for def in bands.iter() {
+            if sdr::span(&def.freqs) > max_span {
+                return Err(format!(
+                    "Band {} spans {} kHz but receivers can listen to at most {} kHz",
+                    def.id,
+                    sdr::span(&def.freqs),
+                    max_span
+                ));
+            }
+        }
+
+        let mut info = Config::parse_systable(&args.sys_table)?;
+        info.apply_bands(&bands)?;
+        if receivers.len() > info.bands.len() {
+            return Err(format!(
+                "{} receivers configured but the System Table only has {} bands",
+                receivers.len(),
+                info.bands.len()
+            ));
+        }
 
         Ok(Config {
             bin: args.bin.to_owned(),
             timeout: args.timeout,
             spdu_timeout: args.spdu_timeout,
             ac_timeout: args.ac_timeout,
+            history_bucket: args.history_bucket,
+            history_retention: args.history_retention * 86400,
             end_session_wait: args.end_session_wait,
+            shutdown_timeout: args.shutdown_timeout,
+            state_dump: args.state_dump.to_owned(),
+            store: args.store.to_owned(),
             additional_args: args.additional_args.to_owned(),
+            sdr,
+            max_sample_rate: args.max_sample_rate,
+            center_freq: args.center_freq,
+            location,
+            receivers,
+            bands,
+
+            stderr_level,
+            stderr_lines: args.stderr_lines,
 
             swarm: args.swarm,
+            swarm_leader: args.swarm_leader,
             host: args.host.to_owned(),
             port: args.port,
+            swarm_name: args.swarm_name.to_owned(),
+            swarm_heartbeat: args.swarm_heartbeat,
+            api_token: args.api_token.clone().filter(|x| !x.is_empty()),
+
+            systable_update: args.systable_update.to_owned(),
+
+            attach,
+            retune_cmd: args.retune_cmd.to_owned(),
 
-            max_bad_child_reads: 1,
+            outputs,
+            output_queue: args.output_queue,
+
+            replay: args.replay.to_owned(),
+            replay_speed,
+
+            max_bad_child_reads: args.max_child_failures,
+            restart_backoff_min: args.restart_backoff_min,
+            restart_backoff_max: args.restart_backoff_max,
+            restart_backoff_jitter: args.restart_backoff_jitter,
+            healthy_session: args.healthy_session,
 
             info,
         })
     }
+
+    /// Configuration seen by a single receiver's sessions
+    pub fn for_receiver(&self, receiver: &ReceiverSpec) -> Config {
+        let mut config = self.clone();
+        config.additional_args = receiver.additional_args.clone();
+        config.sdr = receiver.sdr;
+        config.max_sample_rate = receiver.max_sample_rate;
+        config.receivers = vec![receiver.clone()];
+
+        config
+    }
+
+    /// SDR the sessions of a receiver configuration are tuned for
+    pub fn sdr(&self) -> Sdr {
+        Sdr::new(self.sdr, self.max_sample_rate)
+    }
 }
 
 impl fmt::Display for Config {
@@ -104,3 +697,254 @@ impl fmt::Display for Config {
         )
     }
 }
+
+/// Configuration for tests, built from the testing/ fixtures and the given extra arguments
+#[cfg(test)]
+pub fn test_config(extra: &[&str]) -> Config {
+    use clap::Parser;
+
+    let mut args = vec![
+        "hfdl-autopilot",
+        "--bin",
+        "testing/dumphfdl",
+        "--sys-table",
+        "testing/systable.json",
+    ];
+    args.extend(extra);
+
+    Config::from_args(&Args::parse_from(args)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Output of the former tools/convert_table.py for the System Table kept in its `raw` field
+    const CONVERTED: &str = include_str!("../testing/systable.json");
+
+    #[test]
+    fn libconf_matches_converted_table() {
+        let converted: HFDLInfo = serde_json::from_str(CONVERTED).unwrap();
+        let native = info_from_libconf(&converted.raw).unwrap();
+
+        assert_eq!(native.version, Some(51));
+        assert_eq!(native.bands, converted.bands);
+
+        assert_eq!(native.stations.len(), converted.stations.len());
+        for (name, expected) in converted.stations.iter() {
+            let station = native.stations.get(name).unwrap();
+            let mut assigned = expected.assigned.clone();
+            assigned.sort_unstable();
+
+            assert_eq!(station.id, expected.id);
+            assert_eq!(station.lat, expected.lat);
+            assert_eq!(station.lon, expected.lon);
+            assert_eq!(station.assigned, assigned, "{}", name);
+        }
+    }
+
+    #[test]
+    fn groups_frequencies_within_bandwidth_limit() {
+        let info = info_from_libconf(
+            "stations = ( { id = 1; name = \"A\"; lat = 0.0; lon = 0.0; frequencies = ( 2941.0, 3441.0, 3442.0, 3900.0 ); } );",
+        )
+        .unwrap();
+
+        assert_eq!(info.bands.get(&2), Some(&vec![2941, 3441]));
+        assert_eq!(info.bands.get(&3), Some(&vec![3442, 3900]));
+        assert_eq!(info.stations.get("A").unwrap().assigned, vec![2, 3]);
+    }
+
+    #[test]
+    fn rejects_incomplete_stations() {
+        assert!(info_from_libconf("version = 1;").is_err());
+        assert!(info_from_libconf("stations = ( { id = 1; name = \"A\"; lat = 0.0; } );").is_err());
+        assert!(info_from_libconf(
+            "stations = ( { id = 300; name = \"A\"; lat = 0.0; lon = 0.0; frequencies = ( 1.0 ); } );"
+        )
+        .is_err());
+    }
+
+    fn band(id: u32, freqs: &[u32], replaces: &[u32]) -> BandDefinition {
+        BandDefinition {
+            id,
+            label: None,
+            freqs: freqs.to_vec(),
+            replaces: replaces.to_vec(),
+        }
+    }
+
+    fn assigned(info: &HFDLInfo, name: &str) -> Vec<u32> {
+        info.stations.get(name).unwrap().assigned.clone()
+    }
+
+    #[test]
+    fn assigns_custom_bands_by_station_frequency() {
+        // Band 8 split in two: San Francisco only uses 8927 while Molokai uses 8912 and 8936
+        let split = [
+            band(
+                100,
+                &[8825, 8834, 8843, 8885, 8886, 8894, 8912, 8921, 8927],
+                &[8],
+            ),
+            band(101, &[8936, 8939, 8942, 8948, 8957, 8977], &[8]),
+        ];
+        let native =
+            info_from_libconf(&serde_json::from_str::<HFDLInfo>(CONVERTED).unwrap().raw).unwrap();
+
+        let mut converted =
+            Config::parse_systable(&PathBuf::from("testing/systable.json")).unwrap();
+        for mut info in [native, converted.clone()] {
+            info.apply_bands(&split).unwrap();
+
+            assert!(!info.bands.contains_key(&8));
+            assert_eq!(info.custom.len(), 2);
+            assert_eq!(
+                assigned(&info, "San Francisco, California"),
+                [5, 6, 10, 11, 13, 17, 21, 100]
+            );
+            assert_eq!(
+                assigned(&info, "Molokai, Hawaii"),
+                [5, 6, 10, 11, 13, 17, 21, 100, 101]
+            );
+        }
+
+        // Without the original table, ground stations get every band overlapping their assigned bands
+        converted.raw.clear();
+        for station in converted.stations.values_mut() {
+            station.freqs.clear();
+        }
+        converted.apply_bands(&split).unwrap();
+        assert_eq!(
+            assigned(&converted, "San Francisco, California"),
+            [5, 6, 10, 11, 13, 17, 21, 100, 101]
+        );
+        assert_eq!(
+            assigned(&converted, "Canarias, Spain"),
+            [6, 11, 13, 17, 21, 100, 101]
+        );
+    }
+
+    #[test]
+    fn overlapping_custom_bands_keep_the_system_table_bands() {
+        let mut info = Config::parse_systable(&PathBuf::from("testing/systable.json")).unwrap();
+        info.apply_bands(&[band(100, &[8912, 8927], &[])]).unwrap();
+
+        assert_eq!(info.bands.len(), 13);
+        assert_eq!(info.bands[&100], [8912, 8927]);
+        assert_eq!(band_of(&info.bands, &[8927]), Some(100));
+        assert_eq!(band_of(&info.bands, &[8927, 8977]), Some(8));
+        assert_eq!(
+            assigned(&info, "Reykjavik, Iceland"),
+            [3, 5, 6, 8, 11, 15, 17]
+        );
+        assert!(assigned(&info, "Agana, Guam").contains(&100));
+    }
+
+    #[test]
+    fn rejects_custom_bands_not_covering_the_system_table() {
+        let mut info = Config::parse_systable(&PathBuf::from("testing/systable.json")).unwrap();
+
+        assert!(info.apply_bands(&[band(100, &[8927, 9999], &[])]).is_err());
+        // 8977 would no longer be listened to
+        assert!(info.apply_bands(&[band(100, &[8912, 8927], &[8])]).is_err());
+        assert!(info.bands.contains_key(&8));
+        assert!(info.custom.is_empty());
+    }
+
+    #[test]
+    fn parses_band_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let parse = |contents: &str| {
+            let path = dir.path().join("bands.json");
+            fs::write(&path, contents).unwrap();
+            parse_bands(&path)
+        };
+
+        let bands = parse(
+            r#"{"bands": [{"id": 100, "label": "8 MHz low", "freqs": [8927, 8912], "replaces": [8]}, {"id": 101, "freqs": [8977]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(bands.len(), 2);
+        assert_eq!(bands[0].label.as_deref(), Some("8 MHz low"));
+        assert_eq!(bands[0].replaces, [8]);
+        assert_eq!(bands[1].label, None);
+        assert!(bands[1].replaces.is_empty());
+
+        for contents in [
+            r#"{"bands": [{"id": 0, "freqs": [8927]}]}"#,
+            r#"{"bands": [{"id": 100, "freqs": [8927]}, {"id": 100, "freqs": [8977]}]}"#,
+            r#"{"bands": [{"id": 100, "freqs": []}]}"#,
+            r#"{"bands": [{"id": 100, "freqs": [8927], "gain": 40}]}"#,
+            r#"{"bands": {"id": 100}}"#,
+        ] {
+            assert!(parse(contents).is_err(), "{}", contents);
+        }
+        assert!(parse_bands(&dir.path().join("missing.json")).is_err());
+    }
+
+    fn args() -> Args {
+        Args::parse_from([
+            "hfdl-autopilot",
+            "--chooser",
+            "rotate:type=inc",
+            "--max-sample-rate",
+            "912000",
+            "--",
+            "--soapysdr",
+            "driver=airspyhf",
+        ])
+    }
+
+    #[test]
+    fn receiver_defaults_to_global_settings() {
+        let receiver = ReceiverSpec::parse("rx1", &args(), SdrProfile::AirspyHf).unwrap();
+
+        assert_eq!(receiver.name, "rx1");
+        assert_eq!(receiver.chooser, "rotate:type=inc");
+        assert_eq!(receiver.sdr, SdrProfile::AirspyHf);
+        assert_eq!(receiver.max_sample_rate, Some(912000));
+        assert_eq!(
+            receiver.additional_args,
+            vec!["--soapysdr", "driver=airspyhf"]
+        );
+    }
+
+    #[test]
+    fn receiver_overrides_global_settings() {
+        let receiver = ReceiverSpec::parse(
+            "rx_2;chooser=single:band=13; sdr=rtlsdr ;max-sample-rate=2048000;args=--rtlsdr 0;",
+            &args(),
+            SdrProfile::AirspyHf,
+        )
+        .unwrap();
+
+        assert_eq!(receiver.name, "rx_2");
+        assert_eq!(receiver.chooser, "single:band=13");
+        assert_eq!(receiver.sdr, SdrProfile::RtlSdr);
+        assert_eq!(receiver.max_sample_rate, Some(2048000));
+        assert_eq!(receiver.additional_args, vec!["--rtlsdr", "0"]);
+    }
+
+    #[test]
+    fn rejects_invalid_receivers() {
+        let args = args();
+
+        for spec in [
+            "",
+            ";sdr=rtlsdr",
+            "rx 1",
+            "rx1;sdr",
+            "rx1;sdr=hackrf",
+            "rx1;max-sample-rate=fast",
+            "rx1;gain=40",
+        ] {
+            assert!(
+                ReceiverSpec::parse(spec, &args, SdrProfile::AirspyHf).is_err(),
+                "{}",
+                spec
+            );
+        }
+    }
+}
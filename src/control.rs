@@ -0,0 +1,486 @@
+use crate::chooser;
+use crate::chooser::ChooserPlugin;
+use crate::config::{self, Config};
+use crate::state::SharedState;
+use crate::swarm::Coordinator;
+use crate::utils;
+use chrono::{offset, DateTime, Utc};
+use log::*;
+use std::future;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+
+/// Runtime requests submitted through the control API
+#[derive(Debug)]
+pub enum Command {
+    /// Leave the current band now, for the given band or whatever the chooser picks next. Clears any pin
+    Switch(Option<u32>),
+
+    /// Stay on a band for a while, ignoring the chooser
+    Pin(u32, Duration),
+
+    /// Clear a pin and let the chooser pick the next band
+    Resume,
+
+    /// Replace the active chooser with a new name:key=value spec
+    Chooser(String),
+
+    /// Move to the band the swarm leader leased instead of the current one
+    Reassign(u32),
+}
+
+pub type Reply = Result<String, String>;
+
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Reply>,
+}
+
+pub enum Event {
+    Request(Request),
+    PinExpired,
+}
+
+/// What the read loop should do after a control event has been handled
+pub enum Action {
+    Continue,
+
+    /// Move on to a new band, with the reason reported by metrics
+    EndSession(&'static str),
+
+    SwapChooser(String),
+}
+
+/// Sending halves of the receivers' command channels, shared with the web server
+pub struct ControlHandle {
+    receivers: Vec<(String, mpsc::Sender<Request>)>,
+    token: Option<String>,
+}
+
+impl ControlHandle {
+    /// Checks a bearer token in constant time. Always fails when no token is configured
+    pub fn authorize(&self, token: &str) -> bool {
+        utils::token_matches(&self.token, token)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Hands a command to a receiver's read loop. The receiver may be omitted when there is only one
+    pub async fn submit(&self, receiver: Option<&str>, command: Command) -> Reply {
+        let tx = match receiver {
+            Some(name) => match self.receivers.iter().find(|x| x.0 == name) {
+                Some(val) => &val.1,
+                None => return Err(format!("Unknown receiver: {}", name)),
+            },
+            None if self.receivers.len() == 1 => &self.receivers[0].1,
+            None => {
+                return Err(format!(
+                    "Specify a receiver: {}",
+                    self.receivers
+                        .iter()
+                        .map(|x| x.0.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ))
+            }
+        };
+        let (reply, rx) = oneshot::channel();
+
+        if tx.send(Request { command, reply }).await.is_err() {
+            return Err("Not accepting control requests in this mode".to_string());
+        }
+
+        match time::timeout(Duration::from_secs(10), rx).await {
+            Ok(Ok(val)) => val,
+            _ => Err("Timed out waiting for the control request to be handled".to_string()),
+        }
+    }
+}
+
+/// Receiving half of the command channel along with the overrides it has put in place
+pub struct Control {
+    rx: mpsc::Receiver<Request>,
+
+    next: Option<Vec<u32>>,
+    pin: Option<(u32, Instant)>,
+
+    swarm: Option<Coordinator>,
+}
+
+/// Creates a command channel for every configured receiver, returning their receiving halves in configuration order
+pub fn channel(config: &Config) -> (ControlHandle, Vec<Control>) {
+    let mut receivers = vec![];
+    let mut controls = vec![];
+
+    for receiver in config.receivers.iter() {
+        let (tx, rx) = mpsc::channel(8);
+
+        receivers.push((receiver.name.clone(), tx));
+        controls.push(Control {
+            rx,
+            next: None,
+            pin: None,
+
+            swarm: None,
+        });
+    }
+
+    (
+        ControlHandle {
+            receivers,
+            token: config.api_token.clone(),
+        },
+        controls,
+    )
+}
+
+impl Control {
+    /// Waits for the next control request or for an active pin to run out
+    pub async fn recv(&mut self) -> Event {
+        let deadline = self.pin.map(|x| x.1);
+        let expiry = async move {
+            match deadline {
+                Some(val) => time::sleep_until(val).await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            Some(request) = self.rx.recv() => Event::Request(request),
+            _ = expiry => Event::PinExpired,
+        }
+    }
+
+    /// Leases every band picked from now on so receivers never share a band
+    pub fn coordinate(&mut self, swarm: Coordinator) {
+        self.swarm = Some(swarm);
+    }
+
+    /// Stops accepting requests, for modes that never poll the channel
+    pub fn close(&mut self) {
+        self.rx.close();
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pin.is_some()
+    }
+
+    pub fn clear_pin(&mut self, shared_state: &mut SharedState) {
+        self.pin = None;
+        shared_state.update_pin(None);
+    }
+
+    /// Band to listen to next: a requested band first, then a pinned band, otherwise the chooser's pick. The band is
+    /// then leased, locally or from the swarm leader, which may hand out another one
+    pub async fn choose(
+        &mut self,
+        config: &Config,
+        plugin: &mut dyn ChooserPlugin,
+    ) -> Result<Vec<u32>, String> {
+        let freqs = self.pick(config, plugin).await?;
+
+        let swarm = match self.swarm {
+            Some(ref val) => val,
+            None => return Ok(freqs),
+        };
+        let band = match config::band_of(&config.info.bands, &freqs) {
+            Some(val) => val,
+            None => return Ok(freqs),
+        };
+
+        match swarm.lease(band).await {
+            Ok(lease) if !lease.reassigned => Ok(freqs),
+            Ok(lease) => match config.info.bands.get(&lease.band) {
+                Some(val) => {
+                    info!(
+                        "LEASE: band {} is unavailable, listening to band {} instead",
+                        band, lease.band
+                    );
+                    Ok(val.to_owned())
+                }
+                None => {
+                    warn!(
+                        "LEASE: leased band {} is not in the System Table, staying on band {}",
+                        lease.band, band
+                    );
+                    Ok(freqs)
+                }
+            },
+            Err(e) => {
+                warn!("LEASE: unable to lease band {}: {}", band, e);
+                Ok(freqs)
+            }
+        }
+    }
+
+    async fn pick(
+        &mut self,
+        config: &Config,
+        plugin: &mut dyn ChooserPlugin,
+    ) -> Result<Vec<u32>, String> {
+        if let Some(freqs) = self.next.take() {
+            return Ok(freqs);
+        }
+
+        if let Some((band, _)) = self.pin {
+            if let Some(freqs) = config.info.bands.get(&band) {
+                return Ok(freqs.to_owned());
+            }
+        }
+
+        chooser::choose(plugin).await
+    }
+
+    pub fn handle(
+        &mut self,
+        event: Event,
+        config: &Config,
+        shared_state: &mut SharedState,
+        current_band: u32,
+    ) -> Action {
+        let request = match event {
+            Event::Request(val) => val,
+            Event::PinExpired => {
+                info!("CONTROL: pin on band {} expired", current_band);
+                self.clear_pin(shared_state);
+                return Action::EndSession("pin-expired");
+            }
+        };
+
+        info!("CONTROL: {:?}", request.command);
+
+        let (reply, action) = match request.command {
+            Command::Switch(band) => match band {
+                Some(band) => match config.info.bands.get(&band) {
+                    Some(freqs) => {
+                        self.next = Some(freqs.to_owned());
+                        self.clear_pin(shared_state);
+                        (
+                            Ok(format!("Switching to band {}", band)),
+                            Action::EndSession("control"),
+                        )
+                    }
+                    None => (Err(format!("Unknown band: {}", band)), Action::Continue),
+                },
+                None => {
+                    self.clear_pin(shared_state);
+                    (
+                        Ok("Switching bands".to_string()),
+                        Action::EndSession("control"),
+                    )
+                }
+            },
+            Command::Pin(band, duration) => {
+                if !config.info.bands.contains_key(&band) {
+                    (Err(format!("Unknown band: {}", band)), Action::Continue)
+                } else {
+                    let until: DateTime<Utc> = offset::Utc::now()
+                        + chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::zero());
+
+                    self.pin = Some((band, Instant::now() + duration));
+                    shared_state.update_pin(Some((band, until)));
+
+                    (
+                        Ok(format!("Band {} pinned until {}", band, until)),
+                        if band == current_band {
+                            Action::Continue
+                        } else {
+                            Action::EndSession("control")
+                        },
+                    )
+                }
+            }
+            Command::Resume => {
+                if self.is_pinned() {
+                    self.clear_pin(shared_state);
+                    (
+                        Ok("Resuming automatic band selection".to_string()),
+                        Action::EndSession("control"),
+                    )
+                } else {
+                    (
+                        Ok("Automatic band selection already active".to_string()),
+                        Action::Continue,
+                    )
+                }
+            }
+            Command::Reassign(band) => match config.info.bands.get(&band) {
+                Some(freqs) => {
+                    self.next = Some(freqs.to_owned());
+                    self.clear_pin(shared_state);
+                    (
+                        Ok(format!("Moving to band {}", band)),
+                        Action::EndSession("swarm"),
+                    )
+                }
+                None => (Err(format!("Unknown band: {}", band)), Action::Continue),
+            },
+            Command::Chooser(spec) => {
+                let parsed = chooser::parse_spec(&spec);
+                let results = chooser::get(&parsed, config, &shared_state.chooser_context())
+                    .map(|_| ())
+                    .map_err(|e| format!("PLUGIN INIT[{}]: {}", parsed.name, e));
+
+                match results {
+                    Ok(_) => (
+                        Ok(format!("Switching to chooser {}", spec)),
+                        Action::SwapChooser(spec),
+                    ),
+                    Err(e) => (Err(e), Action::Continue),
+                }
+            }
+        };
+
+        match reply {
+            Ok(ref msg) => info!("CONTROL: {}", msg),
+            Err(ref e) => warn!("CONTROL: {}", e),
+        }
+        let _ = request.reply.send(reply);
+
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn request(command: Command) -> (Event, oneshot::Receiver<Reply>) {
+        let (reply, rx) = oneshot::channel();
+        (Event::Request(Request { command, reply }), rx)
+    }
+
+    fn pinned_band(shared_state: &SharedState) -> serde_json::Value {
+        serde_json::to_value(&*shared_state.session.read().unwrap()).unwrap()["pinned_band"].clone()
+    }
+
+    #[tokio::test]
+    async fn handles_commands() {
+        let config = config::test_config(&[]);
+        let mut shared_state = SharedState::new(&config, "rx");
+        let (_, mut controls) = channel(&config);
+        let control = &mut controls[0];
+        let mut handle = |command| {
+            let (event, mut rx) = request(command);
+            let action = control.handle(event, &config, &mut shared_state, 8);
+            (action, rx.try_recv().unwrap())
+        };
+
+        let (action, reply) = handle(Command::Switch(Some(13)));
+        assert!(matches!(action, Action::EndSession("control")));
+        assert_eq!(reply, Ok("Switching to band 13".to_string()));
+
+        let (action, reply) = handle(Command::Switch(Some(99)));
+        assert!(matches!(action, Action::Continue));
+        assert_eq!(reply, Err("Unknown band: 99".to_string()));
+
+        let (action, _) = handle(Command::Switch(None));
+        assert!(matches!(action, Action::EndSession("control")));
+
+        // Pinning the band listened to doesn't interrupt the session
+        let (action, reply) = handle(Command::Pin(8, Duration::from_secs(60)));
+        assert!(matches!(action, Action::Continue));
+        assert!(reply.unwrap().starts_with("Band 8 pinned until"));
+
+        let (action, _) = handle(Command::Pin(6, Duration::from_secs(60)));
+        assert!(matches!(action, Action::EndSession("control")));
+
+        let (action, reply) = handle(Command::Pin(99, Duration::from_secs(60)));
+        assert!(matches!(action, Action::Continue));
+        assert!(reply.is_err());
+
+        let (action, _) = handle(Command::Resume);
+        assert!(matches!(action, Action::EndSession("control")));
+        let (action, reply) = handle(Command::Resume);
+        assert!(matches!(action, Action::Continue));
+        assert_eq!(
+            reply,
+            Ok("Automatic band selection already active".to_string())
+        );
+
+        let (action, reply) = handle(Command::Reassign(10));
+        assert!(matches!(action, Action::EndSession("swarm")));
+        assert_eq!(reply, Ok("Moving to band 10".to_string()));
+        assert!(handle(Command::Reassign(99)).1.is_err());
+
+        let (action, _) = handle(Command::Chooser("rotate:type=inc".to_string()));
+        assert!(matches!(action, Action::SwapChooser(ref x) if x == "rotate:type=inc"));
+        let (action, reply) = handle(Command::Chooser("nonexistent".to_string()));
+        assert!(matches!(action, Action::Continue));
+        assert!(reply.is_err());
+    }
+
+    #[tokio::test]
+    async fn overrides_the_chooser_pick() {
+        let config = config::test_config(&["--chooser", "single:band=8"]);
+        let mut shared_state = SharedState::new(&config, "rx");
+        let (_, mut controls) = channel(&config);
+        let control = &mut controls[0];
+        let spec = chooser::parse_spec(&config.receivers[0].chooser);
+        let mut plugin = chooser::get(&spec, &config, &shared_state.chooser_context()).unwrap();
+
+        // A requested band is only used once
+        let (event, _rx) = request(Command::Switch(Some(13)));
+        control.handle(event, &config, &mut shared_state, 8);
+        assert_eq!(
+            control.choose(&config, plugin.as_mut()).await,
+            Ok(config.info.bands[&13].clone())
+        );
+        assert_eq!(
+            control.choose(&config, plugin.as_mut()).await,
+            Ok(config.info.bands[&8].clone())
+        );
+
+        // A pinned band is used until the pin runs out
+        let (event, _rx) = request(Command::Pin(6, Duration::from_millis(50)));
+        control.handle(event, &config, &mut shared_state, 8);
+        assert_eq!(pinned_band(&shared_state), 6);
+        for _ in 0..2 {
+            assert_eq!(
+                control.choose(&config, plugin.as_mut()).await,
+                Ok(config.info.bands[&6].clone())
+            );
+        }
+
+        let event = control.recv().await;
+        assert!(matches!(event, Event::PinExpired));
+        let action = control.handle(event, &config, &mut shared_state, 6);
+        assert!(matches!(action, Action::EndSession("pin-expired")));
+        assert!(!control.is_pinned());
+        assert!(pinned_band(&shared_state).is_null());
+    }
+
+    #[tokio::test]
+    async fn submits_to_the_named_receiver() {
+        let config = config::test_config(&["--receiver", "rx1", "--receiver", "rx2"]);
+        let (handle, mut controls) = channel(&config);
+
+        let unknown = handle.submit(Some("rx3"), Command::Resume).await;
+        assert_eq!(unknown, Err("Unknown receiver: rx3".to_string()));
+        let ambiguous = handle.submit(None, Command::Resume).await;
+        assert_eq!(ambiguous, Err("Specify a receiver: rx1, rx2".to_string()));
+
+        let submit = handle.submit(Some("rx2"), Command::Switch(None));
+        let serve = async {
+            match controls[1].recv().await {
+                Event::Request(request) => {
+                    assert!(matches!(request.command, Command::Switch(None)));
+                    let _ = request.reply.send(Ok("done".to_string()));
+                }
+                Event::PinExpired => panic!("no pin was set"),
+            }
+        };
+        let (reply, _) = tokio::join!(submit, serve);
+        assert_eq!(reply, Ok("done".to_string()));
+
+        controls[0].close();
+        assert_eq!(
+            handle.submit(Some("rx1"), Command::Resume).await,
+            Err("Not accepting control requests in this mode".to_string())
+        );
+    }
+}
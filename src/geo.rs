@@ -0,0 +1,177 @@
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+/// Mean Earth radius in kilometres
+const EARTH_RADIUS: f64 = 6371.0;
+
+/// Great-circle distance in kilometres between two points given in degrees
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let dphi = (lat2 - lat1).to_radians();
+    let dlambda = (lon2 - lon1).to_radians();
+
+    let a = (dphi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (dlambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().atan2((1.0 - a).sqrt())
+}
+
+/// Point halfway along the great circle between two points, in degrees
+pub fn midpoint(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> (f64, f64) {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let lambda1 = lon1.to_radians();
+    let dlambda = (lon2 - lon1).to_radians();
+
+    let bx = phi2.cos() * dlambda.cos();
+    let by = phi2.cos() * dlambda.sin();
+    let phi = (phi1.sin() + phi2.sin()).atan2(((phi1.cos() + bx).powi(2) + by.powi(2)).sqrt());
+    let lambda = lambda1 + by.atan2(phi1.cos() + bx);
+
+    (
+        phi.to_degrees(),
+        (lambda.to_degrees() + 540.0).rem_euclid(360.0) - 180.0,
+    )
+}
+
+/// Elevation of the sun above the horizon in degrees, within about a degree
+pub fn solar_elevation(lat: f64, lon: f64, at: DateTime<Utc>) -> f64 {
+    let day = at.ordinal() as f64;
+    let minutes = (at.hour() * 60 + at.minute()) as f64 + at.second() as f64 / 60.0;
+
+    let declination = -23.44_f64.to_radians() * (360.0 / 365.0 * (day + 10.0)).to_radians().cos();
+
+    let b = (360.0 / 365.0 * (day - 81.0)).to_radians();
+    let equation_of_time = 9.87 * (2.0 * b).sin() - 7.53 * b.cos() - 1.5 * b.sin();
+
+    let solar_minutes = minutes + 4.0 * lon + equation_of_time;
+    let hour_angle = (solar_minutes / 4.0 - 180.0).to_radians();
+
+    let phi = lat.to_radians();
+    (phi.sin() * declination.sin() + phi.cos() * declination.cos() * hour_angle.cos())
+        .clamp(-1.0, 1.0)
+        .asin()
+        .to_degrees()
+}
+
+/// Parses a LAT,LON pair in degrees
+pub fn parse_location(val: &str) -> Result<(f64, f64), String> {
+    let (lat, lon) = val
+        .split_once(',')
+        .ok_or(format!("Expected LAT,LON: {}", val))?;
+    let lat: f64 = lat
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid latitude: {}", lat))?;
+    let lon: f64 = lon
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid longitude: {}", lon))?;
+
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("Location out of range: {},{}", lat, lon));
+    }
+
+    Ok((lat, lon))
+}
+
+/// Checks whether a point lies inside a polygon of LAT,LON vertices, treating edges as straight lines on a plate
+/// carrée projection
+pub fn in_polygon(lat: f64, lon: f64, polygon: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+
+    let mut j = polygon.len().wrapping_sub(1);
+    for (i, &(lat_i, lon_i)) in polygon.iter().enumerate() {
+        let (lat_j, lon_j) = polygon[j];
+        if (lat_i > lat) != (lat_j > lat)
+            && lon < (lon_j - lon_i) * (lat - lat_i) / (lat_j - lat_i) + lon_i
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn measures_great_circle_distances() {
+        assert_near(distance_km(51.5074, -0.1278, 48.8566, 2.3522), 343.5, 1.0);
+        assert_near(
+            distance_km(90.0, 0.0, -90.0, 0.0),
+            EARTH_RADIUS * std::f64::consts::PI,
+            0.01,
+        );
+        assert_near(distance_km(0.0, 179.5, 0.0, -179.5), 111.2, 0.1);
+        assert_eq!(distance_km(52.7, -8.9, 52.7, -8.9), 0.0);
+    }
+
+    #[test]
+    fn finds_midpoints() {
+        let (lat, lon) = midpoint(0.0, 0.0, 0.0, 90.0);
+        assert_near(lat, 0.0, 1e-9);
+        assert_near(lon, 45.0, 1e-9);
+
+        // Across the antimeridian
+        let (lat, lon) = midpoint(10.0, 170.0, 10.0, -170.0);
+        assert!(lat > 10.0);
+        assert_near(lon.abs(), 180.0, 1e-9);
+    }
+
+    #[test]
+    fn estimates_solar_elevation() {
+        let equinox_noon = Utc.with_ymd_and_hms(2024, 3, 20, 12, 0, 0).unwrap();
+        assert!(solar_elevation(0.0, 0.0, equinox_noon) > 85.0);
+        assert!(solar_elevation(0.0, 180.0, equinox_noon) < -85.0);
+
+        let solstice = Utc.with_ymd_and_hms(2024, 6, 21, 12, 0, 0).unwrap();
+        assert_near(solar_elevation(90.0, 0.0, solstice), 23.44, 1.0);
+        assert_near(solar_elevation(-90.0, 0.0, solstice), -23.44, 1.0);
+    }
+
+    #[test]
+    fn parses_locations() {
+        assert_eq!(parse_location(" 52.7, -8.9 "), Ok((52.7, -8.9)));
+        assert_eq!(parse_location("-90,180"), Ok((-90.0, 180.0)));
+
+        for invalid in ["52.7", "north,west", "52.7,", "91,0", "0,-181"] {
+            assert!(parse_location(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn tests_points_against_polygons() {
+        let square = [(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)];
+        assert!(in_polygon(5.0, 5.0, &square));
+        assert!(!in_polygon(15.0, 5.0, &square));
+        assert!(!in_polygon(5.0, -0.1, &square));
+
+        // Concave: a U open to the north
+        let u = [
+            (0.0, 0.0),
+            (10.0, 0.0),
+            (10.0, 3.0),
+            (2.0, 3.0),
+            (2.0, 7.0),
+            (10.0, 7.0),
+            (10.0, 10.0),
+            (0.0, 10.0),
+        ];
+        assert!(in_polygon(5.0, 1.0, &u));
+        assert!(!in_polygon(5.0, 5.0, &u));
+        assert!(in_polygon(1.0, 5.0, &u));
+
+        assert!(!in_polygon(0.0, 0.0, &[]));
+    }
+}
@@ -1,5 +1,8 @@
+#![allow(clippy::upper_case_acronyms)]
+
 use serde::Deserialize;
 use std::fmt;
+use std::str::FromStr;
 
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
@@ -15,6 +18,12 @@ pub struct Time {
     pub usec: u64,
 }
 
+impl Time {
+    pub fn as_secs_f64(&self) -> f64 {
+        self.sec as f64 + (self.usec as f64 / 1000000.0)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct PDUType {
@@ -90,6 +99,20 @@ impl fmt::Display for SystablePartial {
     }
 }
 
+/// Ground station entry of a decoded System Table carried by Systable HFNPDUs
+#[allow(dead_code)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct SystableStation {
+    #[serde(alias = "gs_id")]
+    pub id: u8,
+    pub name: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+
+    #[serde(alias = "frequencies")]
+    pub freqs: Vec<f64>,
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct PerfDataFreq {
@@ -111,9 +134,12 @@ pub struct HFNPDU {
     pub acars: Option<ACARS>,
     pub freq_data: Option<Vec<FrequencyData>>,
 
-    pub version: Option<u8>,
+    pub version: Option<u16>,
     pub systable_partial: Option<SystablePartial>,
 
+    #[serde(alias = "gs_data")]
+    pub stations: Option<Vec<SystableStation>>,
+
     pub frequency: Option<PerfDataFreq>,
 
     pub request_data: Option<u16>,
@@ -256,6 +282,7 @@ impl LPDU {
 pub struct SPDU {
     pub err: bool,
     pub src: Entity,
+    pub systable_version: Option<u32>,
     pub gs_status: Vec<GroundStation>,
 }
 
@@ -273,6 +300,7 @@ impl SPDU {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct HFDL {
     pub t: Time,
@@ -298,3 +326,93 @@ impl HFDL {
 pub struct Frame {
     pub hfdl: HFDL,
 }
+
+/// Coarse classification of a frame by its innermost PDU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    SPDU,
+    LPDU,
+    HFNPDU,
+    ACARS,
+}
+
+impl FrameKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            FrameKind::SPDU => "spdu",
+            FrameKind::LPDU => "lpdu",
+            FrameKind::HFNPDU => "hfnpdu",
+            FrameKind::ACARS => "acars",
+        }
+    }
+}
+
+impl FromStr for FrameKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "spdu" => Ok(FrameKind::SPDU),
+            "lpdu" => Ok(FrameKind::LPDU),
+            "hfnpdu" => Ok(FrameKind::HFNPDU),
+            "acars" => Ok(FrameKind::ACARS),
+            _ => Err(format!("Unknown frame kind: {}", s)),
+        }
+    }
+}
+
+fn ground_station(entity: &Entity) -> Option<u8> {
+    if entity.entity_type.eq_ignore_ascii_case("ground station") {
+        Some(entity.id)
+    } else {
+        None
+    }
+}
+
+impl Frame {
+    pub fn kind(&self) -> Option<FrameKind> {
+        if self.hfdl.spdu.is_some() {
+            return Some(FrameKind::SPDU);
+        }
+
+        self.hfdl.lpdu.as_ref().map(|lpdu| match lpdu.hfnpdu {
+            Some(ref hfnpdu) if hfnpdu.acars.is_some() => FrameKind::ACARS,
+            Some(_) => FrameKind::HFNPDU,
+            None => FrameKind::LPDU,
+        })
+    }
+
+    /// ID of the ground station sending or receiving this frame
+    pub fn ground_station(&self) -> Option<u8> {
+        if let Some(ref spdu) = self.hfdl.spdu {
+            Some(spdu.src.id)
+        } else if let Some(ref lpdu) = self.hfdl.lpdu {
+            ground_station(&lpdu.src).or(ground_station(&lpdu.dst))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the frame was sent by a ground station (uplink) rather than an aircraft (downlink)
+    pub fn is_uplink(&self) -> bool {
+        if self.hfdl.spdu.is_some() {
+            true
+        } else if let Some(ref lpdu) = self.hfdl.lpdu {
+            ground_station(&lpdu.src).is_some()
+        } else {
+            false
+        }
+    }
+
+    /// Flight ID from performance data or flight number from ACARS, if any
+    pub fn callsign(&self) -> Option<&str> {
+        let hfnpdu = self.hfdl.lpdu.as_ref()?.hfnpdu.as_ref()?;
+
+        hfnpdu
+            .flight_id
+            .as_deref()
+            .or(hfnpdu.acars.as_ref().and_then(|x| x.flight.as_deref()))
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+    }
+}
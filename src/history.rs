@@ -0,0 +1,369 @@
+use chrono::{offset, DateTime, TimeZone, Utc};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// What frames are counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Series {
+    Band,
+    Freq,
+    Gs,
+}
+
+impl Series {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Series::Band => "band",
+            Series::Freq => "freq",
+            Series::Gs => "gs",
+        }
+    }
+}
+
+impl FromStr for Series {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "band" => Ok(Series::Band),
+            "freq" => Ok(Series::Freq),
+            "gs" => Ok(Series::Gs),
+            _ => Err(format!("Unknown series (band, freq or gs): {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Series {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Frames sent by ground stations (uplink) and by aircraft (downlink)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counts {
+    pub up: u64,
+    pub down: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.up += other.up;
+        self.down += other.down;
+    }
+}
+
+/// Counts of one series over a window starting at `at`
+#[derive(Debug, Clone, Serialize)]
+pub struct Point {
+    pub at: DateTime<Utc>,
+
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+/// Parameters of a history request, built from its query string. `from` and `to` take RFC 3339 dates or UNIX
+/// timestamps and default to the last 24 hours
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryQuery {
+    #[serde(deserialize_with = "from_str")]
+    pub series: Series,
+    pub id: Option<u32>,
+
+    #[serde(default, deserialize_with = "time")]
+    from: Option<DateTime<Utc>>,
+
+    #[serde(default, deserialize_with = "time")]
+    to: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub resolution: u64,
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    T::from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+fn time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // A literal '+' in the offset comes through as a space unless it was escaped
+    let val = String::deserialize(deserializer)?.replace(' ', "+");
+
+    if let Ok(timestamp) = val.parse::<i64>() {
+        return Utc
+            .timestamp_opt(timestamp, 0)
+            .single()
+            .map(Some)
+            .ok_or(de::Error::custom(format!("invalid timestamp: {}", val)));
+    }
+
+    DateTime::parse_from_rfc3339(&val)
+        .map(|x| Some(x.with_timezone(&Utc)))
+        .map_err(|e| de::Error::custom(format!("invalid date {}: {}", val, e)))
+}
+
+impl HistoryQuery {
+    /// Requested time range, defaulting to the 24 hours up to `to` or now
+    pub fn range(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+        let to = self.to.unwrap_or_else(offset::Utc::now);
+        let from = self.from.unwrap_or(to - chrono::Duration::hours(24));
+        if from > to {
+            return Err(format!("from ({}) is after to ({})", from, to));
+        }
+
+        Ok((from, to))
+    }
+}
+
+type Bucket = HashMap<(Series, u32), Counts>;
+
+/// Frame counts per band, frequency (kHz) and ground station in fixed windows, kept for `retention` seconds
+pub struct History {
+    bucket: i64,
+    retention: i64,
+    buckets: Mutex<BTreeMap<i64, Bucket>>,
+}
+
+impl History {
+    pub fn new(bucket: u64, retention: u64) -> Self {
+        History {
+            bucket: bucket as i64,
+            retention: retention as i64,
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn start_of(&self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.bucket)
+    }
+
+    /// Counts a frame heard now
+    pub fn record(&self, band: Option<u32>, freq: u32, gs_id: Option<u8>, uplink: bool) {
+        let now = offset::Utc::now().timestamp();
+        let counts = Counts {
+            up: uplink as u64,
+            down: !uplink as u64,
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let oldest = self.start_of(now - self.retention);
+        while buckets.first_key_value().is_some_and(|(x, _)| *x < oldest) {
+            buckets.pop_first();
+        }
+
+        let bucket = buckets.entry(self.start_of(now)).or_default();
+        let series = [
+            band.map(|x| (Series::Band, x)),
+            Some((Series::Freq, freq)),
+            gs_id.map(|x| (Series::Gs, x as u32)),
+        ];
+        for key in series.into_iter().flatten() {
+            bucket.entry(key).or_default().add(&counts);
+        }
+    }
+
+    /// Adds counts loaded from the store, skipping windows past retention
+    pub fn load(&self, at: i64, series: Series, id: u32, counts: Counts) {
+        if at < self.start_of(offset::Utc::now().timestamp() - self.retention) {
+            return;
+        }
+
+        self.buckets
+            .lock()
+            .unwrap()
+            .entry(self.start_of(at))
+            .or_default()
+            .entry((series, id))
+            .or_default()
+            .add(&counts);
+    }
+
+    /// Windows starting at or after `since`, flattened for storage
+    pub fn since(&self, since: i64) -> Vec<(i64, Series, u32, Counts)> {
+        self.buckets
+            .lock()
+            .unwrap()
+            .range(since..)
+            .flat_map(|(at, bucket)| {
+                bucket
+                    .iter()
+                    .map(|((series, id), counts)| (*at, *series, *id, *counts))
+            })
+            .collect()
+    }
+
+    /// Oldest window start still retained
+    pub fn oldest(&self) -> i64 {
+        self.start_of(offset::Utc::now().timestamp() - self.retention)
+    }
+
+    /// Rounds a requested resolution (seconds) up to a multiple of the bucket size, no coarser than the retention
+    pub fn resolution(&self, requested: u64) -> u64 {
+        let bucket = self.bucket as u64;
+        requested
+            .clamp(bucket, (self.retention as u64).max(bucket))
+            .div_ceil(bucket)
+            * bucket
+    }
+
+    /// Counts of a series between `from` and `to`, summed into windows of `resolution` seconds. Windows without
+    /// frames are left out
+    pub fn query(
+        &self,
+        series: Series,
+        id: Option<u32>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        resolution: u64,
+    ) -> BTreeMap<u32, Vec<Point>> {
+        let resolution = self.resolution(resolution) as i64;
+
+        let mut windows: BTreeMap<u32, BTreeMap<i64, Counts>> = BTreeMap::new();
+        for (at, bucket) in self
+            .buckets
+            .lock()
+            .unwrap()
+            .range(self.start_of(from.timestamp())..=to.timestamp())
+        {
+            let window = at - at.rem_euclid(resolution);
+            for ((x, y), counts) in bucket.iter() {
+                if *x == series && id.is_none_or(|id| id == *y) {
+                    windows
+                        .entry(*y)
+                        .or_default()
+                        .entry(window)
+                        .or_default()
+                        .add(counts);
+                }
+            }
+        }
+
+        windows
+            .into_iter()
+            .map(|(id, points)| {
+                (
+                    id,
+                    points
+                        .into_iter()
+                        .filter_map(|(at, counts)| {
+                            Utc.timestamp_opt(at, 0)
+                                .single()
+                                .map(|at| Point { at, counts })
+                        })
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Query;
+
+    fn query(query: &str) -> Result<HistoryQuery, String> {
+        Query::<HistoryQuery>::from_query(query)
+            .map(|x| x.into_inner())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parses_history_queries() {
+        let parsed = query(
+            "series=BAND&id=13&from=2024-05-01T00:00:00%2B02:00&to=1714600000&resolution=3600",
+        )
+        .unwrap();
+        let (from, to) = parsed.range().unwrap();
+
+        assert_eq!(parsed.series, Series::Band);
+        assert_eq!(parsed.id, Some(13));
+        assert_eq!(parsed.resolution, 3600);
+        assert_eq!(from, Utc.with_ymd_and_hms(2024, 4, 30, 22, 0, 0).unwrap());
+        assert_eq!(to.timestamp(), 1714600000);
+
+        // An unescaped '+' in the offset is decoded as a space
+        let parsed =
+            query("series=gs&from=2024-05-01T00:00:00+02:00&to=2024-05-02T00:00:00Z").unwrap();
+        assert_eq!(parsed.range().unwrap().0, from);
+    }
+
+    #[test]
+    fn defaults_to_the_last_day() {
+        let parsed = query("series=freq").unwrap();
+        let (from, to) = parsed.range().unwrap();
+
+        assert_eq!(parsed.id, None);
+        assert_eq!(parsed.resolution, 0);
+        assert_eq!(to - from, chrono::Duration::hours(24));
+    }
+
+    #[test]
+    fn rejects_invalid_history_queries() {
+        for invalid in [
+            "",
+            "series=station",
+            "series=band&id=-1",
+            "series=band&from=yesterday",
+            "series=band&resolution=1h",
+            "series=band&limit=10",
+        ] {
+            assert!(query(invalid).is_err(), "{}", invalid);
+        }
+
+        assert!(query("series=band&from=1714600000&to=1714500000")
+            .unwrap()
+            .range()
+            .is_err());
+    }
+
+    #[test]
+    fn resolution_is_a_multiple_of_the_bucket_within_retention() {
+        let history = History::new(300, 7 * 86400);
+
+        assert_eq!(history.resolution(0), 300);
+        assert_eq!(history.resolution(301), 600);
+        assert_eq!(history.resolution(3600), 3600);
+        assert_eq!(history.resolution(u64::MAX), 7 * 86400);
+    }
+
+    #[test]
+    fn sums_counts_into_windows() {
+        let history = History::new(300, 86400);
+        history.record(Some(13), 13276, Some(7), true);
+        history.record(Some(13), 13276, Some(7), false);
+        history.record(Some(17), 17919, Some(1), false);
+        let now = offset::Utc::now();
+
+        let data = history.query(
+            Series::Band,
+            None,
+            now - chrono::Duration::hours(1),
+            now,
+            u64::MAX,
+        );
+        assert_eq!(data.keys().copied().collect::<Vec<u32>>(), vec![13, 17]);
+        assert_eq!(data[&13][0].counts, Counts { up: 1, down: 1 });
+
+        let data = history.query(
+            Series::Gs,
+            Some(1),
+            now - chrono::Duration::hours(1),
+            now,
+            0,
+        );
+        assert_eq!(data[&1][0].counts, Counts { up: 0, down: 1 });
+    }
+}
@@ -1,12 +1,27 @@
 use actix_web::http::header::ContentType;
-use actix_web::web::Data;
-use actix_web::{HttpRequest, HttpResponse};
-use serde::Serialize;
+use actix_web::web::{Bytes, Data, Payload, Query};
+use actix_web::{rt, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use futures_util::StreamExt;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
 
+use crate::chooser::LearnTable;
+use crate::control::{Command, ControlHandle};
+use crate::history::{History, HistoryQuery};
+use crate::metrics::Metrics;
+use crate::sink::OutputStats;
 use crate::state::{
-    FrequencyStats, GroundStationMap, GroundStationStats, PositionReportsByFlightMap, SessionState,
+    BandList, FrequencyStats, GroundStationMap, GroundStationStats, PositionReportsByFlightMap,
+    Receivers, StderrLog,
 };
+use crate::stream::{FrameStream, StreamFilter};
+use crate::swarm::{Registry, Report};
 
 pub async fn web_index(_req: HttpRequest) -> HttpResponse {
     HttpResponse::Ok()
@@ -14,6 +29,16 @@ pub async fn web_index(_req: HttpRequest) -> HttpResponse {
         .body("OK")
 }
 
+pub async fn metrics(req: HttpRequest) -> HttpResponse {
+    let metrics = req.app_data::<Data<Metrics>>().unwrap();
+    let gs_info = req.app_data::<Data<GroundStationMap>>().unwrap();
+    let flights = req.app_data::<Data<PositionReportsByFlightMap>>().unwrap();
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(gs_info, flights.len()))
+}
+
 pub async fn api_gs_list(req: HttpRequest) -> HttpResponse {
     let gs_info = req.app_data::<Data<GroundStationMap>>().unwrap();
 
@@ -22,6 +47,14 @@ pub async fn api_gs_list(req: HttpRequest) -> HttpResponse {
         .body(serde_json::to_string(&gs_info).unwrap())
 }
 
+pub async fn api_band_list(req: HttpRequest) -> HttpResponse {
+    let band_list = req.app_data::<Data<BandList>>().unwrap();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&band_list).unwrap())
+}
+
 pub async fn api_gs_stats(req: HttpRequest) -> HttpResponse {
     let gs_stats = req.app_data::<Data<GroundStationStats>>().unwrap();
 
@@ -38,13 +71,336 @@ pub async fn api_freq_stats(req: HttpRequest) -> HttpResponse {
         .body(serde_json::to_string(&freq_stats).unwrap())
 }
 
+pub async fn api_history(req: HttpRequest, query: Query<HistoryQuery>) -> HttpResponse {
+    let history = req.app_data::<Data<History>>().unwrap();
+    let (from, to) = match query.range() {
+        Ok(val) => val,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let resolution = history.resolution(query.resolution);
+    let data = history.query(query.series, query.id, from, to, resolution);
+
+    HttpResponse::Ok().json(json!({
+        "series": query.series,
+        "from": from,
+        "to": to,
+        "resolution": resolution,
+        "data": data,
+    }))
+}
+
+pub async fn api_chooser_learn(req: HttpRequest) -> HttpResponse {
+    let learned = req.app_data::<Data<LearnTable>>().unwrap();
+    HttpResponse::Ok().json(learned.snapshot())
+}
+
 pub async fn api_session_list(req: HttpRequest) -> HttpResponse {
-    let session_ptr = req.app_data::<Data<RwLock<SessionState>>>().unwrap();
-    let session = session_ptr.read().unwrap();
+    let receivers = req.app_data::<Data<Receivers>>().unwrap();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&receivers).unwrap())
+}
+
+pub async fn api_session_stderr(req: HttpRequest) -> HttpResponse {
+    let receivers = req.app_data::<Data<Receivers>>().unwrap();
+    let stderr_logs: Vec<Data<RwLock<StderrLog>>> = receivers
+        .read()
+        .unwrap()
+        .iter()
+        .map(|x| x.stderr.clone())
+        .collect();
 
     HttpResponse::Ok()
         .content_type(ContentType::json())
-        .body(serde_json::to_string(&*session).unwrap())
+        .body(serde_json::to_string(&stderr_logs).unwrap())
+}
+
+pub async fn api_outputs(req: HttpRequest) -> HttpResponse {
+    let output_stats = req.app_data::<Data<OutputStats>>().unwrap();
+
+    HttpResponse::Ok().content_type(ContentType::json()).body(
+        serde_json::to_string(&output_stats.iter().map(|x| x.as_ref()).collect::<Vec<_>>())
+            .unwrap(),
+    )
+}
+
+pub async fn api_swarm(req: HttpRequest) -> HttpResponse {
+    let registry = req.app_data::<Data<Registry>>().unwrap();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&registry).unwrap())
+}
+
+#[derive(Debug, Deserialize)]
+struct SwarmParams {
+    id: Option<String>,
+    name: Option<String>,
+    band: Option<u32>,
+
+    #[serde(flatten)]
+    report: Report,
+}
+
+/// Authenticates a swarm request and parses its JSON body. Requests made on behalf of a member get a 404 when the
+/// member is unknown, telling it to join again
+fn swarm_request(
+    req: &HttpRequest,
+    body: &Bytes,
+    member: bool,
+    handle: impl FnOnce(&Registry, String, SwarmParams) -> HttpResponse,
+) -> HttpResponse {
+    let registry = req.app_data::<Data<Registry>>().unwrap();
+
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if !registry.authorize(token.trim()) {
+        return HttpResponse::Unauthorized().body("Invalid or missing bearer token");
+    }
+
+    let params: SwarmParams = match serde_json::from_slice(body) {
+        Ok(val) => val,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid JSON body: {}", e)),
+    };
+
+    let id = match params.id {
+        Some(ref id) if !member || registry.is_member(id) => id.clone(),
+        Some(ref id) => {
+            return HttpResponse::NotFound().body(format!("Unknown swarm member: {}", id))
+        }
+        None if !member => String::new(),
+        None => return HttpResponse::BadRequest().body("Expected a member ID"),
+    };
+
+    handle(registry, id, params)
+}
+
+pub async fn api_swarm_join(req: HttpRequest, body: Bytes) -> HttpResponse {
+    swarm_request(&req, &body, false, |registry, _, params| {
+        let id = registry.join(params.name.as_deref());
+
+        HttpResponse::Ok().content_type(ContentType::json()).body(
+            json!({ "id": id, "heartbeat_secs": registry.heartbeat_interval().as_secs() })
+                .to_string(),
+        )
+    })
+}
+
+pub async fn api_swarm_lease(req: HttpRequest, body: Bytes) -> HttpResponse {
+    swarm_request(&req, &body, true, |registry, id, params| {
+        let band = match params.band {
+            Some(val) => val,
+            None => return HttpResponse::BadRequest().body("Expected a band"),
+        };
+
+        match registry.lease(&id, band) {
+            Ok(lease) => HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(serde_json::to_string(&lease).unwrap()),
+            Err(e) => HttpResponse::Conflict().body(e),
+        }
+    })
+}
+
+pub async fn api_swarm_heartbeat(req: HttpRequest, body: Bytes) -> HttpResponse {
+    swarm_request(&req, &body, true, |registry, id, params| {
+        let reassign = match registry.heartbeat(&id, params.band, &params.report) {
+            Ok(val) => val,
+            Err(e) => return HttpResponse::Conflict().body(e),
+        };
+
+        let gs_info = req.app_data::<Data<GroundStationMap>>().unwrap();
+        let flights = req.app_data::<Data<PositionReportsByFlightMap>>().unwrap();
+        params.report.merge(gs_info, flights);
+
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(json!({ "reassign": reassign }).to_string())
+    })
+}
+
+pub async fn api_swarm_leave(req: HttpRequest, body: Bytes) -> HttpResponse {
+    swarm_request(&req, &body, false, |registry, id, _| {
+        registry.leave(&id);
+
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body("{}")
+    })
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ControlParams {
+    receiver: Option<String>,
+    band: Option<u32>,
+    minutes: Option<u64>,
+    chooser: Option<String>,
+}
+
+/// Authenticates a control API request and hands the command built from its JSON body to the read loop
+async fn control_request(
+    req: &HttpRequest,
+    body: &Bytes,
+    build: fn(ControlParams) -> Result<Command, String>,
+) -> HttpResponse {
+    let control = req.app_data::<Data<ControlHandle>>().unwrap();
+    if !control.is_enabled() {
+        return HttpResponse::Forbidden()
+            .body("Control API is disabled: set --api-token to enable it");
+    }
+
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if !control.authorize(token.trim()) {
+        return HttpResponse::Unauthorized().body("Invalid or missing bearer token");
+    }
+
+    let params: ControlParams = if body.iter().all(|x| x.is_ascii_whitespace()) {
+        ControlParams::default()
+    } else {
+        match serde_json::from_slice(body) {
+            Ok(val) => val,
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid JSON body: {}", e)),
+        }
+    };
+
+    let receiver = params.receiver.clone();
+    let command = match build(params) {
+        Ok(val) => val,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    match control.submit(receiver.as_deref(), command).await {
+        Ok(msg) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(json!({ "ok": true, "message": msg }).to_string()),
+        Err(e) => HttpResponse::Conflict()
+            .content_type(ContentType::json())
+            .body(json!({ "ok": false, "message": e }).to_string()),
+    }
+}
+
+pub async fn api_control_switch(req: HttpRequest, body: Bytes) -> HttpResponse {
+    control_request(&req, &body, |params| Ok(Command::Switch(params.band))).await
+}
+
+pub async fn api_control_pin(req: HttpRequest, body: Bytes) -> HttpResponse {
+    control_request(&req, &body, |params| match (params.band, params.minutes) {
+        (Some(band), Some(minutes)) if minutes > 0 => {
+            Ok(Command::Pin(band, Duration::from_secs(minutes * 60)))
+        }
+        _ => Err("Expected a band and a positive number of minutes".to_string()),
+    })
+    .await
+}
+
+pub async fn api_control_resume(req: HttpRequest, body: Bytes) -> HttpResponse {
+    control_request(&req, &body, |_| Ok(Command::Resume)).await
+}
+
+pub async fn api_control_chooser(req: HttpRequest, body: Bytes) -> HttpResponse {
+    control_request(&req, &body, |params| match params.chooser {
+        Some(spec) if !spec.is_empty() => Ok(Command::Chooser(spec)),
+        _ => Err("Expected a chooser spec".to_string()),
+    })
+    .await
+}
+
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub async fn api_stream_frames(req: HttpRequest, filter: Query<StreamFilter>) -> HttpResponse {
+    let stream = req.app_data::<Data<FrameStream>>().unwrap();
+    let filter = filter.into_inner();
+
+    let body = futures_util::stream::unfold(
+        (stream.subscribe(), filter),
+        |(mut rx, filter)| async move {
+            loop {
+                let chunk = match time::timeout(SSE_KEEP_ALIVE, rx.recv()).await {
+                    Ok(Ok(event)) => {
+                        if !filter.matches(&event) {
+                            continue;
+                        }
+
+                        event.to_sse()?
+                    }
+                    Ok(Err(RecvError::Lagged(count))) => {
+                        format!(": {} event(s) dropped\n\n", count)
+                    }
+                    Ok(Err(RecvError::Closed)) => return None,
+                    Err(_) => ": keep-alive\n\n".to_string(),
+                };
+
+                return Some((Ok::<_, actix_web::Error>(Bytes::from(chunk)), (rx, filter)));
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+pub async fn api_stream_frames_ws(
+    req: HttpRequest,
+    filter: Query<StreamFilter>,
+    body: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let stream = req.app_data::<Data<FrameStream>>().unwrap();
+    let filter = filter.into_inner();
+
+    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
+    let mut rx = stream.subscribe();
+
+    rt::spawn(async move {
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) => {
+                        if !filter.matches(&event) {
+                            continue;
+                        }
+
+                        let msg = match event.to_ws() {
+                            Some(val) => val,
+                            None => break,
+                        };
+                        if session.text(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        debug!("WebSocket client fell behind, {} event(s) dropped", count)
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                msg = msg_stream.next() => match msg {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
 }
 
 #[derive(Debug, Serialize)]
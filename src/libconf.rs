@@ -0,0 +1,478 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A single value of a libconfig configuration file
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum Setting {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Group(Vec<(String, Setting)>),
+    Array(Vec<Setting>),
+    List(Vec<Setting>),
+}
+
+impl Setting {
+    pub fn get(&self, name: &str) -> Option<&Setting> {
+        match self {
+            Setting::Group(settings) => settings.iter().find(|x| x.0 == name).map(|x| &x.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Setting::Int(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Setting::Int(val) => Some(*val as f64),
+            Setting::Float(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Setting::Str(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn as_slice(&self) -> Option<&[Setting]> {
+        match self {
+            Setting::Array(items) | Setting::List(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line, msg))
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), String> {
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.bump();
+            } else if c == '#' {
+                while !matches!(self.bump(), Some('\n') | None) {}
+            } else if c == '/' {
+                let mut lookahead = self.chars.clone();
+                lookahead.next();
+                match lookahead.next() {
+                    Some('/') => while !matches!(self.bump(), Some('\n') | None) {},
+                    Some('*') => {
+                        self.bump();
+                        self.bump();
+                        let mut last = ' ';
+                        loop {
+                            match self.bump() {
+                                Some('/') if last == '*' => break,
+                                Some(c) => last = c,
+                                None => return self.error("unterminated comment"),
+                            }
+                        }
+                    }
+                    _ => return self.error("unexpected '/'"),
+                }
+            } else {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn peek(&mut self) -> Result<Option<char>, String> {
+        self.skip_whitespace()?;
+        Ok(self.chars.peek().copied())
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.peek()? {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => self.error(&format!("expected '{}', found '{}'", expected, c)),
+            None => self.error(&format!("expected '{}', found end of file", expected)),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let mut name = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '*' {
+                name.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+            return self.error(&format!("invalid setting name '{}'", name));
+        }
+
+        Ok(name)
+    }
+
+    fn settings(&mut self, terminator: Option<char>) -> Result<Vec<(String, Setting)>, String> {
+        let mut settings = vec![];
+
+        loop {
+            match self.peek()? {
+                None if terminator.is_none() => break,
+                None => return self.error("unexpected end of file"),
+                Some(c) if Some(c) == terminator => break,
+                Some('@') => return self.error("@include directives are not supported"),
+                Some(_) => {}
+            }
+
+            let name = self.name()?;
+            match self.peek()? {
+                Some('=') | Some(':') => {
+                    self.bump();
+                }
+                _ => return self.error(&format!("expected '=' or ':' after '{}'", name)),
+            }
+
+            let value = self.value()?;
+            if let Some(';') | Some(',') = self.peek()? {
+                self.bump();
+            }
+
+            settings.push((name, value));
+        }
+
+        Ok(settings)
+    }
+
+    fn values(&mut self, close: char) -> Result<Vec<Setting>, String> {
+        let mut items = vec![];
+
+        loop {
+            if self.peek()? == Some(close) {
+                self.bump();
+                break;
+            }
+
+            items.push(self.value()?);
+
+            match self.peek()? {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(c) if c == close => {}
+                _ => return self.error(&format!("expected ',' or '{}'", close)),
+            }
+        }
+
+        Ok(items)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut value = String::new();
+
+        // Adjacent string literals are concatenated
+        while self.peek()? == Some('"') {
+            self.bump();
+            loop {
+                match self.bump() {
+                    Some('"') => break,
+                    Some('\\') => match self.bump() {
+                        Some('n') => value.push('\n'),
+                        Some('r') => value.push('\r'),
+                        Some('t') => value.push('\t'),
+                        Some('f') => value.push('\x0c'),
+                        Some(c) => value.push(c),
+                        None => return self.error("unterminated string"),
+                    },
+                    Some(c) => value.push(c),
+                    None => return self.error("unterminated string"),
+                }
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn scalar(&mut self) -> Result<Setting, String> {
+        let mut token = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+' {
+                token.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        if token.eq_ignore_ascii_case("true") {
+            return Ok(Setting::Bool(true));
+        } else if token.eq_ignore_ascii_case("false") {
+            return Ok(Setting::Bool(false));
+        }
+
+        let number = token.trim_end_matches(['L', 'l']);
+        if let Some(hex) = number.strip_prefix("0x").or(number.strip_prefix("0X")) {
+            if let Ok(val) = i64::from_str_radix(hex, 16) {
+                return Ok(Setting::Int(val));
+            }
+        } else if let Ok(val) = number.parse::<i64>() {
+            return Ok(Setting::Int(val));
+        } else if let Ok(val) = token.parse::<f64>() {
+            return Ok(Setting::Float(val));
+        }
+
+        self.error(&format!("invalid value '{}'", token))
+    }
+
+    fn value(&mut self) -> Result<Setting, String> {
+        match self.peek()? {
+            Some('{') => {
+                self.bump();
+                let settings = self.settings(Some('}'))?;
+                self.expect('}')?;
+                Ok(Setting::Group(settings))
+            }
+            Some('[') => {
+                self.bump();
+                Ok(Setting::Array(self.values(']')?))
+            }
+            Some('(') => {
+                self.bump();
+                Ok(Setting::List(self.values(')')?))
+            }
+            Some('"') => Ok(Setting::Str(self.string()?)),
+            Some(_) => self.scalar(),
+            None => self.error("expected a value, found end of file"),
+        }
+    }
+}
+
+/// Parses the contents of a libconfig file (as used by dumphfdl's systable.conf) into its root group
+pub fn parse(contents: &str) -> Result<Setting, String> {
+    let mut parser = Parser {
+        chars: contents.chars().peekable(),
+        line: 1,
+    };
+
+    Ok(Setting::Group(parser.settings(None)?))
+}
+
+fn write_value(out: &mut String, value: &Setting, depth: usize) {
+    let indent = "  ".repeat(depth);
+
+    match value {
+        Setting::Int(val) => out.push_str(&val.to_string()),
+        Setting::Float(val) => out.push_str(&format!("{:?}", val)),
+        Setting::Bool(val) => out.push_str(&val.to_string()),
+        Setting::Str(val) => {
+            out.push('"');
+            out.push_str(&val.replace('\\', "\\\\").replace('"', "\\\""));
+            out.push('"');
+        }
+        Setting::Group(settings) => {
+            out.push_str("{\n");
+            write_settings(out, settings, depth + 1);
+            out.push_str(&indent);
+            out.push('}');
+        }
+        Setting::Array(items) | Setting::List(items) => {
+            let (open, close) = match value {
+                Setting::Array(_) => ('[', ']'),
+                _ => ('(', ')'),
+            };
+            let nested = items
+                .iter()
+                .any(|x| matches!(x, Setting::Group(_) | Setting::List(_) | Setting::Array(_)));
+
+            out.push(open);
+            for (i, item) in items.iter().enumerate() {
+                if nested {
+                    out.push_str(if i == 0 { "\n" } else { ",\n" });
+                    out.push_str(&"  ".repeat(depth + 1));
+                } else {
+                    out.push_str(if i == 0 { " " } else { ", " });
+                }
+                write_value(out, item, depth + 1);
+            }
+            if nested {
+                out.push('\n');
+                out.push_str(&indent);
+            } else {
+                out.push(' ');
+            }
+            out.push(close);
+        }
+    }
+}
+
+fn write_settings(out: &mut String, settings: &[(String, Setting)], depth: usize) {
+    for (name, value) in settings {
+        out.push_str(&"  ".repeat(depth));
+        out.push_str(name);
+        out.push_str(" = ");
+        write_value(out, value, depth);
+        out.push_str(";\n");
+    }
+}
+
+/// Serializes a root group back into libconfig syntax
+pub fn to_string(root: &[(String, Setting)]) -> String {
+    let mut out = String::new();
+    write_settings(&mut out, root, 0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(contents: &str) -> Setting {
+        parse(contents).unwrap()
+    }
+
+    #[test]
+    fn parses_strings_with_escapes() {
+        let root = root(r#"a = "tab\tquote\"back\\slash\nline"; b = "con" "cat";"#);
+
+        assert_eq!(
+            root.get("a").and_then(|x| x.as_str()),
+            Some("tab\tquote\"back\\slash\nline")
+        );
+        assert_eq!(root.get("b").and_then(|x| x.as_str()), Some("concat"));
+    }
+
+    #[test]
+    fn parses_numbers() {
+        let root =
+            root("i = 42; n = -7; l = 9000000000L; h = 0x1F; f = 21934.0; e = 1.5e3; b = TRUE;");
+
+        assert_eq!(root.get("i").and_then(|x| x.as_i64()), Some(42));
+        assert_eq!(root.get("n").and_then(|x| x.as_i64()), Some(-7));
+        assert_eq!(root.get("l").and_then(|x| x.as_i64()), Some(9000000000));
+        assert_eq!(root.get("h").and_then(|x| x.as_i64()), Some(31));
+        assert_eq!(root.get("f").and_then(|x| x.as_f64()), Some(21934.0));
+        assert_eq!(root.get("f").and_then(|x| x.as_i64()), None);
+        assert_eq!(root.get("e").and_then(|x| x.as_f64()), Some(1500.0));
+        assert!(matches!(root.get("b"), Some(Setting::Bool(true))));
+    }
+
+    #[test]
+    fn parses_nested_groups_and_lists() {
+        let root = root(
+            "stations = ( { id = 1; name: \"A\"; freqs = [ 1, 2 ]; }, { id = 2; name = \"B\"; freqs = [ ]; } );\n\
+             nested = { inner = { deep = ( 1, \"two\", ( 3 ) ); }; };",
+        );
+
+        let stations = root.get("stations").and_then(|x| x.as_slice()).unwrap();
+        assert_eq!(stations.len(), 2);
+        assert_eq!(stations[0].get("name").and_then(|x| x.as_str()), Some("A"));
+        assert_eq!(
+            stations[0]
+                .get("freqs")
+                .and_then(|x| x.as_slice())
+                .map(|x| x.len()),
+            Some(2)
+        );
+        assert_eq!(
+            stations[1]
+                .get("freqs")
+                .and_then(|x| x.as_slice())
+                .map(|x| x.len()),
+            Some(0)
+        );
+
+        let deep = root
+            .get("nested")
+            .and_then(|x| x.get("inner"))
+            .and_then(|x| x.get("deep"))
+            .and_then(|x| x.as_slice())
+            .unwrap();
+        assert_eq!(deep.len(), 3);
+        assert_eq!(deep[1].as_str(), Some("two"));
+        assert!(matches!(deep[2], Setting::List(_)));
+    }
+
+    #[test]
+    fn skips_comments() {
+        let root = root(
+            "# hash comment\n\
+             a = 1; // line comment\n\
+             /* block\n comment */ b = 2;\n\
+             c = /* inline */ 3;",
+        );
+
+        assert_eq!(root.get("a").and_then(|x| x.as_i64()), Some(1));
+        assert_eq!(root.get("b").and_then(|x| x.as_i64()), Some(2));
+        assert_eq!(root.get("c").and_then(|x| x.as_i64()), Some(3));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for contents in [
+            "a = \"unterminated;",
+            "a = 1; /* unterminated",
+            "a = { b = 1;",
+            "a = ( 1, 2",
+            "a = [ 1 2 ];",
+            "a 1;",
+            "1a = 1;",
+            "a = ;",
+            "a = bogus;",
+            "a = 0xZZ;",
+            "a = / 1;",
+            "@include \"other.cfg\"",
+            "a =",
+            "}",
+        ] {
+            assert!(parse(contents).is_err(), "{:?} should not parse", contents);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_to_string() {
+        let contents = "version = 51;\nstations = ( { id = 1; name = \"A \\\"B\\\"\"; lat = 1.5; freqs = ( 1.0, 2.0 ); } );\n";
+        let parsed = match root(contents) {
+            Setting::Group(settings) => settings,
+            _ => unreachable!(),
+        };
+
+        let written = to_string(&parsed);
+        let reparsed = root(&written);
+        match reparsed {
+            Setting::Group(ref settings) => assert_eq!(to_string(settings), written),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            reparsed
+                .get("stations")
+                .and_then(|x| x.as_slice())
+                .and_then(|x| x[0].get("name"))
+                .and_then(|x| x.as_str()),
+            Some("A \"B\"")
+        );
+    }
+}
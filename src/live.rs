@@ -0,0 +1,340 @@
+use crate::chooser::{self, ChooserPlugin};
+use crate::config::Config;
+use crate::control::{Action, Control};
+use crate::pipeline::Outcome;
+use crate::sink::Outputs;
+use crate::state::SharedState;
+use crate::supervisor::{ChildExit, Supervisor};
+use crate::{child, pipeline, shutdown};
+use actix_web::rt;
+use log::*;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tempfile::{NamedTempFile, TempPath};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::time;
+
+/// Runs dumphfdl sessions on the bands picked by the chooser until shutdown or too many child failures
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    config: &Config,
+    shared_state: &mut SharedState,
+    plugin: &mut dyn ChooserPlugin,
+    control: &mut Control,
+    supervisor: &mut Supervisor,
+    outputs: &Outputs,
+    name: &str,
+    shutdown_rx: &mut watch::Receiver<bool>,
+) -> io::Result<Outcome> {
+    let mut systable_temp_path: Option<TempPath> = None;
+
+    let systable_path = match config.info.source {
+        Some(ref path) => {
+            info!("Using System Table {:?}", path);
+            path.to_owned()
+        }
+        None => {
+            let mut systable = NamedTempFile::new()?;
+            write!(systable, "{}", config.info.raw)?;
+            systable.seek(SeekFrom::Start(0))?;
+
+            let temp_path = systable_temp_path.insert(systable.into_temp_path());
+
+            info!("System Table information written to {:?}", temp_path);
+            temp_path.to_path_buf()
+        }
+    };
+
+    let timeout = Duration::from_secs(config.timeout as u64);
+
+    info!("Starting listening session...");
+    info!("");
+
+    let mut restart_band: Option<Vec<u32>> = None;
+    let mut swap_chooser: Option<String> = None;
+
+    loop {
+        let band = match restart_band.take() {
+            Some(val) => val,
+            None => match control.choose(config, plugin).await {
+                Ok(val) => val,
+                Err(e) => {
+                    error!("Failed to choose a frequency band to listen to: {}", e);
+                    return Ok(Outcome::Exit);
+                }
+            },
+        };
+
+        let band_id = shared_state.band_of(&band).unwrap_or(0);
+
+        let tuning = match shared_state.tune(&config.sdr(), &band) {
+            Some(val) => val,
+            None => {
+                error!("Bandwidth calculation failed: {:?}", band);
+                return Ok(Outcome::Exit);
+            }
+        };
+
+        if tuning.is_partial(&band) {
+            warn!(
+                "Band {} {:?} doesn't fit receiver {} ({}), listening to its most active frequencies {:?}",
+                band_id, band, shared_state.receiver, config.sdr, tuning.freqs
+            );
+        }
+
+        shared_state.update_current_band(band_id, &tuning.freqs);
+
+        info!(
+            "NEW SESSION: receiver={} sample_rate={} band={:?}",
+            shared_state.receiver, tuning.sample_rate, tuning.freqs
+        );
+
+        let center_freq = match config.center_freq {
+            true => vec!["--centerfreq".to_string(), tuning.center_freq.to_string()],
+            false => vec![],
+        };
+
+        let session_start = Instant::now();
+        let mut child_exit: Option<ChildExit> = None;
+        let mut exit_status: Option<String> = None;
+
+        match Command::new(config.bin.clone())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("--system-table")
+            .arg(&systable_path)
+            .arg("--sample-rate")
+            .arg(tuning.sample_rate.to_string())
+            .args(center_freq)
+            .arg("--output")
+            .arg("decoded:json:file:path=-")
+            .args(config.additional_args.clone())
+            .args(tuning.freqs.iter().map(|f| f.to_string()))
+            .spawn()
+        {
+            Ok(mut proc) => {
+                shared_state.session_stderr.write().unwrap().clear();
+
+                let stderr_task = proc.stderr.take().map(|stderr| {
+                    child::capture_stderr(
+                        stderr,
+                        config.stderr_level,
+                        shared_state.session_stderr.clone(),
+                    )
+                });
+
+                match proc.stdout.take() {
+                    Some(child_stdout) => {
+                        let mut lines = BufReader::new(child_stdout).lines();
+                        let mut last_cleanup = Instant::now();
+                        let mut frames: u64 = 0;
+
+                        loop {
+                            let read_results = tokio::select! {
+                                _ = shutdown::requested(shutdown_rx) => break,
+                                event = control.recv() => {
+                                    match control.handle(event, config, shared_state, band_id) {
+                                        Action::Continue => continue,
+                                        Action::EndSession(reason) => {
+                                            shared_state.on_switch(name, reason);
+                                            break;
+                                        }
+                                        Action::SwapChooser(spec) => {
+                                            swap_chooser = Some(spec);
+                                            break;
+                                        }
+                                    }
+                                }
+                                results = rt::time::timeout(timeout, lines.next_line()) => results,
+                            };
+
+                            if let Ok(results) = read_results {
+                                match results {
+                                    Ok(None) => {
+                                        child_exit = Some(if frames == 0 {
+                                            ChildExit::ImmediateExit
+                                        } else {
+                                            ChildExit::EndOfStream(frames)
+                                        });
+                                        break;
+                                    }
+                                    Ok(Some(msg)) => {
+                                        let frame = match pipeline::decode(&msg) {
+                                            Some(val) => val,
+                                            None => continue,
+                                        };
+
+                                        frames += 1;
+
+                                        outputs.publish(msg.trim());
+                                        shared_state.stream.on_frame(msg.trim(), &frame);
+
+                                        if pipeline::process(&frame, shared_state, plugin)
+                                            && !control.is_pinned()
+                                        {
+                                            info!(
+                                                "{} elects to change bands after last HFDL frame.",
+                                                name
+                                            );
+                                            shared_state.on_switch(name, "frame");
+                                            break;
+                                        }
+
+                                        if last_cleanup.elapsed().as_secs() >= config.ac_timeout {
+                                            shared_state.clean_up();
+                                            last_cleanup = Instant::now();
+                                        }
+                                    }
+                                    Err(e) => {
+                                        child_exit = Some(ChildExit::ReadError(e.to_string()));
+                                        break;
+                                    }
+                                }
+                            } else if chooser::on_timeout(plugin).await && !control.is_pinned() {
+                                info!(
+                                    "Been {}s since last message on band. {} elects to change bands.",
+                                    config.timeout, name
+                                );
+                                shared_state.on_switch(name, "timeout");
+                                break;
+                            }
+                        }
+                    }
+                    None => {
+                        child_exit = Some(ChildExit::ReadError(
+                            "unable to get STDOUT for child dumphfdl process".to_string(),
+                        ));
+                    }
+                }
+
+                if shutdown::is_requested(shutdown_rx) {
+                    info!("Stopping dumphfdl...");
+                    match child::terminate(&mut proc, Duration::from_secs(config.shutdown_timeout))
+                        .await
+                    {
+                        Ok(status) => info!("dumphfdl exited: {}", status),
+                        Err(e) => error!("Failed to stop dumphfdl: {}", e),
+                    }
+                } else {
+                    if let Err(e) = proc.kill().await {
+                        warn!("Failed to kill dumphfdl: {}", e);
+                    }
+                    if let Ok(status) = proc.wait().await {
+                        exit_status = Some(status.to_string());
+                    }
+                }
+
+                if let Some(task) = stderr_task {
+                    if time::timeout(Duration::from_secs(1), task).await.is_err() {
+                        warn!("Timed out waiting for dumphfdl stderr to drain");
+                    }
+                }
+            }
+            Err(e) => {
+                child_exit = Some(ChildExit::SpawnFailure(e.to_string()));
+            }
+        }
+
+        if shutdown::is_requested(shutdown_rx) {
+            break;
+        }
+
+        let backoff = match child_exit {
+            Some(ref exit) => {
+                let backoff = supervisor.on_failure(session_start.elapsed());
+
+                error!(
+                    "dumphfdl failed: {} [{}] ({})",
+                    exit,
+                    exit_status.as_deref().unwrap_or("no exit status"),
+                    supervisor.attempt()
+                );
+
+                shared_state.update_child_exit(
+                    Some(exit.to_string()),
+                    exit_status,
+                    supervisor.restarts(),
+                    supervisor.failures(),
+                );
+
+                match backoff {
+                    Some(val) => {
+                        restart_band = Some(band);
+                        Some(val)
+                    }
+                    None => break,
+                }
+            }
+            None => {
+                supervisor.on_session_end(session_start.elapsed());
+                None
+            }
+        };
+
+        if config.end_session_wait > 0 {
+            info!(
+                "Waiting {} seconds before starting new session",
+                config.end_session_wait
+            );
+            time::sleep(Duration::from_secs(config.end_session_wait)).await;
+        }
+
+        if let Some(backoff) = backoff {
+            info!(
+                "Restarting dumphfdl in {:.1} seconds",
+                backoff.as_secs_f64()
+            );
+            tokio::select! {
+                _ = shutdown::requested(shutdown_rx) => break,
+                _ = time::sleep(backoff) => {},
+            }
+        }
+
+        info!("Ending session...");
+
+        shared_state.clean_up();
+
+        info!("");
+
+        if let Some(info) = shared_state.systable_update() {
+            return Ok(Outcome::Reload(info));
+        }
+        if let Some(spec) = swap_chooser.take() {
+            return Ok(Outcome::SwapChooser(spec));
+        }
+    }
+
+    if shutdown::is_requested(shutdown_rx) {
+        if config.end_session_wait > 0 {
+            info!(
+                "Waiting {} seconds for dumphfdl clean up",
+                config.end_session_wait
+            );
+            time::sleep(Duration::from_secs(config.end_session_wait)).await;
+        }
+    } else {
+        error!("Encountered too many consecutive dumphfdl failures: process may be prematurely exiting");
+        error!(
+            "Verify that dumphfdl is being fed with correct arguments and can be run indepedently"
+        );
+
+        let stderr_log = shared_state.session_stderr.read().unwrap();
+        if stderr_log.lines().is_empty() {
+            error!("dumphfdl did not write anything to stderr");
+        } else {
+            error!(
+                "Last {} line(s) of dumphfdl stderr:",
+                stderr_log.lines().len()
+            );
+            for line in stderr_log.lines() {
+                error!("  {}", line.trim_end());
+            }
+        }
+    }
+
+    Ok(Outcome::Exit)
+}
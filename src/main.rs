@@ -1,23 +1,44 @@
+use crate::pipeline::Outcome;
 use crate::state::SharedState;
-use actix_web::{rt, web, App, HttpServer};
+use crate::store::Store;
+use crate::supervisor::Supervisor;
+use crate::swarm::{Coordinator, Follower, Registry};
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpServer};
 use clap::Parser;
+use futures_util::future;
 use log::*;
-use serde_json::Value;
 use std::io;
-use std::io::{Seek, SeekFrom, Write};
-use std::process::Stdio;
-use std::time::{Duration, Instant};
-use tempfile::NamedTempFile;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
-use tokio::time;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 
 mod args;
+mod attach;
+mod child;
 mod chooser;
+mod client;
 mod config;
+mod control;
+mod geo;
 mod hfdl;
+mod history;
 mod http;
+mod libconf;
+mod live;
+mod metrics;
+mod pipeline;
+mod replay;
+mod retune;
+mod sdr;
+mod shutdown;
+mod sink;
 mod state;
+mod store;
+mod stream;
+mod supervisor;
+mod swarm;
+mod systable;
 mod utils;
 
 #[tokio::main]
@@ -46,218 +67,291 @@ async fn main() -> io::Result<()> {
     info!("Configuration demarshalled from command line arguments.");
     info!("  {}", config);
 
-    let (name, props) = args.chooser_params();
-    info!("Chooser plugin name={} props={:?}", name, props);
+    let mut shared_states = vec![SharedState::new(&config, &config.receivers[0].name)];
+    if let Some(ref path) = config.store {
+        match Store::open(path) {
+            Ok(store) => shared_states[0].restore(web::Data::new(store)),
+            Err(e) => {
+                error!("Failed to open state store: {}", e);
+                return Ok(());
+            }
+        }
+    }
+    for receiver in config.receivers.iter().skip(1) {
+        let shared_state = shared_states[0].sibling(&config, &receiver.name);
+        shared_states.push(shared_state);
+    }
+    let shared_state = &shared_states[0];
 
-    let mut shared_state = SharedState::new(&config);
+    let (control_handle, mut controls) = control::channel(&config);
+    let control_handle = web::Data::new(control_handle);
 
-    let mut plugin = match chooser::get(name, &config, &props, shared_state.gs_info.clone()) {
-        Ok(plugin) => plugin,
-        Err(e) => {
-            error!("PLUGIN INIT[{}]: {}", name, e);
-            return Ok(());
-        }
-    };
+    let outputs = sink::Outputs::new(&config.outputs, config.output_queue);
+
+    let shutdown_rx = shutdown::listen()?;
+    let mut server_handle: Option<ServerHandle> = None;
+    let mut registry: Option<web::Data<Registry>> = None;
+    let mut swarm_tasks: Vec<tokio::task::JoinHandle<()>> = vec![];
+    let mut followers: Vec<Arc<Follower>> = vec![];
 
     if config.swarm {
         info!("Swarm mode is ON: target={}:{}", config.host, config.port);
-        error!("UNSUPPORTED for now...");
 
-        // TODO: what does swarm mode look like
+        for (receiver, control) in config.receivers.iter().zip(controls.iter_mut()) {
+            let follower = Arc::new(Follower::new(&config, receiver));
+            control.coordinate(Coordinator::Follower(follower.clone()));
+
+            swarm_tasks.push(tokio::spawn(swarm::heartbeat(
+                follower.clone(),
+                receiver.name.clone(),
+                shared_state.gs_info.clone(),
+                shared_state.flight_posrpt.clone(),
+                control_handle.clone(),
+                shutdown_rx.clone(),
+            )));
+            followers.push(follower);
+        }
     } else {
         info!(
             "Swarm mode is OFF: starting web server on {}:{}",
             config.host, config.port
         );
 
-        let session = shared_state.session.clone();
+        let receivers = shared_state.receivers.clone();
+        let band_list = shared_state.band_list.clone();
         let gs_info = shared_state.gs_info.clone();
         let gs_stats = shared_state.gs_stats.clone();
         let flight_posrpt = shared_state.flight_posrpt.clone();
         let freq_stats = shared_state.freq_stats.clone();
+        let output_stats = web::Data::new(outputs.stats());
+        let metrics = shared_state.metrics.clone();
+        let history = shared_state.history.clone();
+        let learned = shared_state.learned.clone();
+        let stream = shared_state.stream.clone();
+        let control_handle = control_handle.clone();
+        let swarm_registry = registry
+            .insert(web::Data::new(Registry::new(&config)))
+            .clone();
+
+        for (receiver, control) in config.receivers.iter().zip(controls.iter_mut()) {
+            control.coordinate(Coordinator::Leader {
+                registry: swarm_registry.clone(),
+                receiver: receiver.name.clone(),
+            });
+        }
+        let swarm_leader = config.swarm_leader;
+        if swarm_leader {
+            info!("Swarm leader: accepting members");
+            swarm_tasks.push(tokio::spawn(swarm::reap(
+                swarm_registry.clone(),
+                shutdown_rx.clone(),
+            )));
+        }
 
         let server_host = config.host.clone();
         let server_port = config.port;
 
-        tokio::spawn(async move {
-            let server = HttpServer::new(move || {
-                App::new()
-                    .app_data(session.clone())
-                    .app_data(gs_info.clone())
-                    .app_data(gs_stats.clone())
-                    .app_data(flight_posrpt.clone())
-                    .app_data(freq_stats.clone())
-                    .route("/", web::get().to(http::web_index))
-                    .route("/api/session", web::get().to(http::api_session_list))
-                    .route("/api/ground-stations", web::get().to(http::api_gs_list))
-                    .route(
-                        "/api/ground-station/stats",
-                        web::get().to(http::api_gs_stats),
-                    )
-                    .route("/api/freq-stats", web::get().to(http::api_freq_stats))
-                    .route("/api/flights", web::get().to(http::api_flights_list))
-                    .route(
-                        "/api/flight/{callsign}",
-                        web::get().to(http::api_flights_detail),
-                    )
-            })
-            .bind((server_host, server_port))
-            .unwrap()
-            .run();
-            server.await
-        });
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(receivers.clone())
+                .app_data(band_list.clone())
+                .app_data(gs_info.clone())
+                .app_data(gs_stats.clone())
+                .app_data(flight_posrpt.clone())
+                .app_data(freq_stats.clone())
+                .app_data(output_stats.clone())
+                .app_data(metrics.clone())
+                .app_data(history.clone())
+                .app_data(learned.clone())
+                .app_data(stream.clone())
+                .app_data(control_handle.clone())
+                .app_data(swarm_registry.clone())
+                .route("/", web::get().to(http::web_index))
+                .route("/metrics", web::get().to(http::metrics))
+                .route("/api/session", web::get().to(http::api_session_list))
+                .route(
+                    "/api/session/stderr",
+                    web::get().to(http::api_session_stderr),
+                )
+                .route("/api/bands", web::get().to(http::api_band_list))
+                .route("/api/ground-stations", web::get().to(http::api_gs_list))
+                .route(
+                    "/api/ground-station/stats",
+                    web::get().to(http::api_gs_stats),
+                )
+                .route("/api/freq-stats", web::get().to(http::api_freq_stats))
+                .route("/api/history", web::get().to(http::api_history))
+                .route("/api/chooser/learn", web::get().to(http::api_chooser_learn))
+                .route("/api/stream/frames", web::get().to(http::api_stream_frames))
+                .route(
+                    "/api/stream/frames/ws",
+                    web::get().to(http::api_stream_frames_ws),
+                )
+                .route(
+                    "/api/control/switch",
+                    web::post().to(http::api_control_switch),
+                )
+                .route("/api/control/pin", web::post().to(http::api_control_pin))
+                .route(
+                    "/api/control/resume",
+                    web::post().to(http::api_control_resume),
+                )
+                .route(
+                    "/api/control/chooser",
+                    web::post().to(http::api_control_chooser),
+                )
+                .route("/api/swarm", web::get().to(http::api_swarm))
+                .configure(|cfg| {
+                    if swarm_leader {
+                        swarm::routes(cfg)
+                    }
+                })
+                .route("/api/outputs", web::get().to(http::api_outputs))
+                .route("/api/flights", web::get().to(http::api_flights_list))
+                .route(
+                    "/api/flight/{callsign}",
+                    web::get().to(http::api_flights_detail),
+                )
+        })
+        .disable_signals()
+        .shutdown_timeout(config.shutdown_timeout)
+        .bind((server_host, server_port))
+        .unwrap()
+        .run();
+
+        server_handle = Some(server.handle());
+        tokio::spawn(server);
     }
 
-    let mut systable = NamedTempFile::new()?;
-    write!(systable, "{}", config.info.raw)?;
-    systable.seek(SeekFrom::Start(0))?;
+    let sessions = config
+        .receivers
+        .iter()
+        .zip(shared_states.iter_mut())
+        .zip(controls)
+        .map(|((receiver, shared_state), control)| {
+            run_receiver(
+                config.for_receiver(receiver),
+                receiver.chooser.clone(),
+                shared_state,
+                control,
+                &outputs,
+                registry.clone(),
+                shutdown_rx.clone(),
+            )
+        });
+    let results = future::join_all(sessions).await;
 
-    let systable_temp_path = systable.into_temp_path();
-    let timeout = Duration::from_secs(config.timeout as u64);
+    for task in swarm_tasks {
+        task.abort();
+    }
+    for follower in followers {
+        follower.leave().await;
+    }
 
-    info!(
-        "System Table information written to {:?}",
-        systable_temp_path
-    );
-    info!("Starting listening session...");
-    info!("");
+    if let Some(handle) = server_handle {
+        info!("Stopping web server...");
+        shared_states[0].stream.close();
+        handle.stop(true).await;
+    }
 
-    let mut bad_child_reads = 0;
+    outputs
+        .shutdown(Duration::from_secs(config.shutdown_timeout))
+        .await;
 
-    while bad_child_reads < config.max_bad_child_reads {
-        let band = match plugin.choose() {
-            Ok(val) => val.to_owned(),
-            Err(e) => {
-                error!("Failed to choose a frequency band to listen to: {}", e);
-                return Ok(());
-            }
-        };
+    shared_states[0].shutdown();
 
-        shared_state.update_current_band(&band);
+    info!("Shutdown complete");
 
-        let bandwidth = match band.iter().max().unwrap_or(&0) - band.iter().min().unwrap_or(&0) {
-            d if d >= 452 && d < 764 => "768000",
-            d if d >= 380 && d < 452 => "456000",
-            d if d > 252 && d < 380 => "384000",
-            d if d <= 252 => "256000",
-            _ => {
-                error!("Bandwidth calculation failed: {:?}", band);
-                return Ok(());
-            }
-        };
+    results.into_iter().collect()
+}
+
+/// Runs one receiver's listening sessions until shutdown, reloading the System Table and swapping choosers as asked
+async fn run_receiver(
+    mut config: config::Config,
+    mut chooser_spec: String,
+    shared_state: &mut SharedState,
+    mut control: control::Control,
+    outputs: &sink::Outputs,
+    registry: Option<web::Data<Registry>>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let mut supervisor = Supervisor::new(&config);
+
+    loop {
+        let spec = chooser::parse_spec(&chooser_spec);
+        let name = spec.name;
+        info!(
+            "Chooser plugin receiver={} name={} props={:?}",
+            shared_state.receiver, name, spec.props
+        );
 
-        info!("NEW SESSION: sample_rate={} band={:?}", bandwidth, band);
-
-        let mut proc = match Command::new(config.bin.clone())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .arg("--system-table")
-            .arg(systable_temp_path.to_path_buf())
-            .arg("--sample-rate")
-            .arg(bandwidth)
-            .arg("--output")
-            .arg("decoded:json:file:path=-")
-            .args(config.additional_args.clone())
-            .args(band.into_iter().map(|f| f.to_string()))
-            .spawn()
-        {
-            Ok(proc) => proc,
+        let mut plugin = match chooser::get(&spec, &config, &shared_state.chooser_context()) {
+            Ok(plugin) => plugin,
             Err(e) => {
-                error!("Failed to start dumphfdl: {}", e);
-                continue;
+                error!("PLUGIN INIT[{}]: {}", name, e);
+                break;
             }
         };
-
-        let child_stdout = match proc.stdout.take() {
-            Some(stdout) => stdout,
-            None => {
-                error!("Unable to get STDOUT for child dumphfdl process!");
-                continue;
-            }
+        shared_state.update_chooser(&chooser_spec);
+
+        let outcome = if let Some(ref endpoint) = config.attach {
+            attach::run(
+                &config,
+                endpoint,
+                shared_state,
+                plugin.as_mut(),
+                &mut control,
+                &mut supervisor,
+                outputs,
+                name,
+                &mut shutdown_rx,
+            )
+            .await?
+        } else if config.replay.is_empty() {
+            live::run(
+                &config,
+                shared_state,
+                plugin.as_mut(),
+                &mut control,
+                &mut supervisor,
+                outputs,
+                name,
+                &mut shutdown_rx,
+            )
+            .await?
+        } else {
+            control.close();
+            replay::run(
+                &config,
+                shared_state,
+                plugin.as_mut(),
+                name,
+                &mut shutdown_rx,
+            )
+            .await?;
+
+            Outcome::Exit
         };
-        let mut reader = BufReader::new(child_stdout);
-        let mut last_cleanup = Instant::now();
-
-        loop {
-            let mut msg = String::new();
-
-            if let Ok(results) = rt::time::timeout(timeout, reader.read_line(&mut msg)).await {
-                match results {
-                    Ok(size) => {
-                        if size == 0 {
-                            // TODO: look at stderr?
-
-                            error!("Read error: encountered 0 sized read from dumphfdl! (attempt {} of {})", bad_child_reads + 1, config.max_bad_child_reads);
-                            bad_child_reads += 1;
-                            break;
-                        }
-
-                        let frame: Value = match serde_json::from_str(&msg) {
-                            Ok(val) => val,
-                            Err(e) => {
-                                error!("Bad JSON decode: {}", e);
-                                continue;
-                            }
-                        };
-
-                        println!("{}", msg.trim());
-
-                        shared_state.update(&frame);
-
-                        if plugin.on_recv_frame(&frame) {
-                            info!("{} elects to change bands after last HFDL frame.", name);
-                            break;
-                        }
-
-                        if last_cleanup.elapsed().as_secs() >= config.ac_timeout {
-                            shared_state.clean_up();
-                            last_cleanup = Instant::now();
-                        }
-                    }
-                    Err(e) => {
-                        error!(
-                            "Read error: {} (attempt {} of {})",
-                            e,
-                            bad_child_reads + 1,
-                            config.max_bad_child_reads
-                        );
-
-                        bad_child_reads += 1;
-                        break;
-                    }
-                }
-            } else if plugin.on_timeout() {
-                info!(
-                    "Been {}s since last message on band. {} elects to change bands.",
-                    config.timeout, name
-                );
-                break;
-            }
-        }
 
-        proc.kill().await?;
+        drop(plugin);
 
-        if bad_child_reads < config.max_bad_child_reads && config.end_session_wait > 0 {
-            info!(
-                "Waiting {} seconds before starting new session",
-                config.end_session_wait
-            );
-            time::sleep(Duration::from_secs(config.end_session_wait)).await;
+        match outcome {
+            Outcome::Exit => break,
+            Outcome::Reload(mut info) => {
+                if let Err(e) = info.apply_bands(&config.bands) {
+                    error!("Custom bands don't apply to the new System Table: {}", e);
+                }
+                config.info = info;
+                shared_state.reload(&config);
+                if let Some(ref registry) = registry {
+                    registry.reload(&config);
+                }
+            }
+            Outcome::SwapChooser(spec) => chooser_spec = spec,
         }
-
-        info!("Ending session...");
-
-        shared_state.clean_up();
-
-        info!("");
     }
 
-    if bad_child_reads > 0 {
-        error!("Encountered read errors from dumphfdl: process may be prematurely exiting");
-        error!(
-            "Verify that dumphfdl is being fed with correct arguments and can be run indepedently"
-        );
-    }
+    shared_state.end_session("shutdown");
 
     Ok(())
 }
@@ -0,0 +1,363 @@
+use crate::hfdl::{Frame, FrameKind};
+use crate::state::GroundStationMap;
+use dashmap::DashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Instant;
+
+/// Upper bounds (dBFS) of the signal level histogram buckets
+const SIGNAL_BUCKETS: [f64; 10] = [
+    -45.0, -40.0, -35.0, -30.0, -25.0, -20.0, -15.0, -10.0, -5.0, 0.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FrameKey {
+    freq: u32,
+    band: u32,
+    gs_id: Option<u8>,
+    kind: FrameKind,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; SIGNAL_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, val: f64) {
+        for (i, bound) in SIGNAL_BUCKETS.iter().enumerate() {
+            if val <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += val;
+    }
+}
+
+/// Session gauges of a single receiver
+#[derive(Debug, Default)]
+struct ReceiverGauges {
+    current_band: AtomicU32,
+    sessions: AtomicU64,
+    session_start: RwLock<Option<Instant>>,
+    restarts: AtomicU32,
+    consecutive_failures: AtomicU32,
+}
+
+/// Name, type, help and value of a per receiver gauge
+type Gauge = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ReceiverGauges) -> String,
+);
+
+/// Counters exported in Prometheus text format by /metrics
+pub struct Metrics {
+    frames: DashMap<FrameKey, u64>,
+    signal: DashMap<u32, Histogram>,
+    switches: DashMap<(String, String), u64>,
+
+    receivers: DashMap<String, ReceiverGauges>,
+}
+
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            frames: DashMap::new(),
+            signal: DashMap::new(),
+            switches: DashMap::new(),
+
+            receivers: DashMap::new(),
+        }
+    }
+
+    /// Invoked for each received frame, `band` being the band its frequency belongs to (0 if unknown)
+    pub fn on_frame(&self, frame: &Frame, band: u32) {
+        let kind = match frame.kind() {
+            Some(val) => val,
+            None => return,
+        };
+        let gs_id = frame.ground_station();
+
+        *self
+            .frames
+            .entry(FrameKey {
+                freq: frame.hfdl.freq / 1000,
+                band,
+                gs_id,
+                kind,
+            })
+            .or_insert(0) += 1;
+
+        self.signal
+            .entry(band)
+            .or_default()
+            .observe(frame.hfdl.sig_level);
+    }
+
+    /// Invoked whenever a receiver starts listening on a (possibly) new band
+    pub fn on_session_start(&self, receiver: &str, band: u32) {
+        let gauges = self.receivers.entry(receiver.to_string()).or_default();
+        gauges.current_band.store(band, Ordering::Relaxed);
+        gauges.sessions.fetch_add(1, Ordering::Relaxed);
+        *gauges.session_start.write().unwrap() = Some(Instant::now());
+    }
+
+    /// Invoked when dumphfdl exits with an error, `restarts` being the receiver's total restart count.
+    /// The exported counter never goes down, even if the total it is fed starts over
+    pub fn on_child_exit(&self, receiver: &str, restarts: u32, consecutive_failures: u32) {
+        let gauges = self.receivers.entry(receiver.to_string()).or_default();
+        gauges.restarts.fetch_max(restarts, Ordering::Relaxed);
+        gauges
+            .consecutive_failures
+            .store(consecutive_failures, Ordering::Relaxed);
+    }
+
+    /// Invoked when the chooser is asked for a new band, `reason` being what prompted it
+    pub fn on_switch(&self, chooser: &str, reason: &str) {
+        *self
+            .switches
+            .entry((chooser.to_string(), reason.to_string()))
+            .or_insert(0) += 1;
+    }
+
+    pub fn render(&self, gs_info: &GroundStationMap, tracked_flights: usize) -> String {
+        let mut out = String::new();
+
+        let gs_labels = |gs_id: Option<u8>| match gs_id {
+            Some(id) => format!(
+                "gs_id=\"{}\",gs=\"{}\"",
+                id,
+                escape(
+                    &gs_info
+                        .get(&id)
+                        .map_or(String::from("unknown"), |x| x.name.clone())
+                )
+            ),
+            None => String::from("gs_id=\"\",gs=\"\""),
+        };
+
+        out.push_str("# HELP hfdl_frames_total HFDL frames received by frequency, band, ground station and kind\n");
+        out.push_str("# TYPE hfdl_frames_total counter\n");
+        let mut frames: Vec<(FrameKey, u64)> =
+            self.frames.iter().map(|x| (*x.key(), *x.value())).collect();
+        frames.sort_by_key(|(key, _)| (key.freq, key.gs_id, key.kind.label()));
+        for (key, count) in frames {
+            let _ = writeln!(
+                out,
+                "hfdl_frames_total{{freq=\"{}\",band=\"{}\",{},kind=\"{}\"}} {}",
+                key.freq,
+                key.band,
+                gs_labels(key.gs_id),
+                key.kind.label(),
+                count
+            );
+        }
+
+        out.push_str(
+            "# HELP hfdl_signal_level_dbfs Signal level of received HFDL frames by band\n",
+        );
+        out.push_str("# TYPE hfdl_signal_level_dbfs histogram\n");
+        let mut bands: Vec<u32> = self.signal.iter().map(|x| *x.key()).collect();
+        bands.sort();
+        for band in bands {
+            let histogram = match self.signal.get(&band) {
+                Some(val) => val,
+                None => continue,
+            };
+
+            for (i, bound) in SIGNAL_BUCKETS.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "hfdl_signal_level_dbfs_bucket{{band=\"{}\",le=\"{}\"}} {}",
+                    band, bound, histogram.buckets[i]
+                );
+            }
+            let _ = writeln!(
+                out,
+                "hfdl_signal_level_dbfs_bucket{{band=\"{}\",le=\"+Inf\"}} {}",
+                band, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "hfdl_signal_level_dbfs_sum{{band=\"{}\"}} {}",
+                band, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "hfdl_signal_level_dbfs_count{{band=\"{}\"}} {}",
+                band, histogram.count
+            );
+        }
+
+        out.push_str(
+            "# HELP hfdl_chooser_switches_total Band changes requested from the chooser by reason\n",
+        );
+        out.push_str("# TYPE hfdl_chooser_switches_total counter\n");
+        let mut switches: Vec<((String, String), u64)> = self
+            .switches
+            .iter()
+            .map(|x| (x.key().clone(), *x.value()))
+            .collect();
+        switches.sort();
+        for ((chooser, reason), count) in switches {
+            let _ = writeln!(
+                out,
+                "hfdl_chooser_switches_total{{chooser=\"{}\",reason=\"{}\"}} {}",
+                escape(&chooser),
+                escape(&reason),
+                count
+            );
+        }
+
+        let mut receivers: Vec<String> = self.receivers.iter().map(|x| x.key().clone()).collect();
+        receivers.sort();
+
+        let gauges: [Gauge; 5] = [
+            (
+                "hfdl_current_band",
+                "gauge",
+                "Band currently listened to by each receiver",
+                |x| x.current_band.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "hfdl_sessions_total",
+                "counter",
+                "Listening sessions started",
+                |x| x.sessions.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "hfdl_session_duration_seconds",
+                "gauge",
+                "Time spent in the current listening session",
+                |x| {
+                    format!(
+                        "{:.3}",
+                        x.session_start
+                            .read()
+                            .unwrap()
+                            .map_or(0.0, |x| x.elapsed().as_secs_f64())
+                    )
+                },
+            ),
+            (
+                "hfdl_dumphfdl_restarts_total",
+                "counter",
+                "dumphfdl restarts after a failure",
+                |x| x.restarts.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "hfdl_dumphfdl_consecutive_failures",
+                "gauge",
+                "dumphfdl failures since the last healthy session",
+                |x| x.consecutive_failures.load(Ordering::Relaxed).to_string(),
+            ),
+        ];
+        for (name, kind, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for receiver in receivers.iter() {
+                if let Some(gauges) = self.receivers.get(receiver) {
+                    let _ = writeln!(
+                        out,
+                        "{}{{receiver=\"{}\"}} {}",
+                        name,
+                        escape(receiver),
+                        value(&gauges)
+                    );
+                }
+            }
+        }
+
+        out.push_str("# HELP hfdl_tracked_flights Flights with recent position reports\n");
+        out.push_str("# TYPE hfdl_tracked_flights gauge\n");
+        let _ = writeln!(out, "hfdl_tracked_flights {}", tracked_flights);
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::GroundStationInfo;
+    use serde_json::json;
+
+    #[test]
+    fn renders_the_exposition_text() {
+        let metrics = Metrics::new();
+        let frame: Frame = serde_json::from_value(json!({"hfdl": {
+            "t": {"sec": 0, "usec": 0}, "freq": 8927000, "bit_rate": 1800, "sig_level": -22.5,
+            "lpdu": {
+                "err": false,
+                "src": {"id": 1, "type": "Ground station", "name": "San Francisco, California"},
+                "dst": {"id": 12, "type": "Aircraft"},
+                "type": {"id": 79, "name": "Logon confirm"},
+            },
+        }}))
+        .unwrap();
+        metrics.on_frame(&frame, 8);
+        metrics.on_frame(&frame, 8);
+        metrics.on_switch("propagation", "timeout");
+        metrics.on_session_start("rx\"1", 8);
+        metrics.on_child_exit("rx\"1", 3, 2);
+        // A lower total doesn't take the counter back
+        metrics.on_child_exit("rx\"1", 1, 0);
+
+        let gs_info = GroundStationMap::new();
+        gs_info.insert(
+            1,
+            GroundStationInfo {
+                name: "San Francisco, California".to_string(),
+                position: vec![37.0, -122.0],
+                assigned_bands: vec![],
+                active_bands: vec![],
+                systable_version: None,
+                last_heard: None,
+            },
+        );
+
+        let out = metrics.render(&gs_info, 5);
+        for line in [
+            "# TYPE hfdl_frames_total counter",
+            "hfdl_frames_total{freq=\"8927\",band=\"8\",gs_id=\"1\",gs=\"San Francisco, California\",kind=\"lpdu\"} 2",
+            "hfdl_signal_level_dbfs_bucket{band=\"8\",le=\"-25\"} 0",
+            "hfdl_signal_level_dbfs_bucket{band=\"8\",le=\"-20\"} 2",
+            "hfdl_signal_level_dbfs_bucket{band=\"8\",le=\"+Inf\"} 2",
+            "hfdl_signal_level_dbfs_sum{band=\"8\"} -45",
+            "hfdl_signal_level_dbfs_count{band=\"8\"} 2",
+            "hfdl_chooser_switches_total{chooser=\"propagation\",reason=\"timeout\"} 1",
+            "hfdl_current_band{receiver=\"rx\\\"1\"} 8",
+            "hfdl_sessions_total{receiver=\"rx\\\"1\"} 1",
+            "# TYPE hfdl_dumphfdl_restarts_total counter",
+            "hfdl_dumphfdl_restarts_total{receiver=\"rx\\\"1\"} 3",
+            "hfdl_dumphfdl_consecutive_failures{receiver=\"rx\\\"1\"} 0",
+            "hfdl_tracked_flights 5",
+        ] {
+            assert!(out.lines().any(|x| x == line), "{} missing from:\n{}", line, out);
+        }
+
+        // Every sample belongs to a family announced by HELP and TYPE lines
+        for sample in out.lines().filter(|x| !x.starts_with('#')) {
+            let name = sample.split(['{', ' ']).next().unwrap();
+            let family = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|x| name.strip_suffix(x))
+                .filter(|_| name.starts_with("hfdl_signal_level_dbfs"))
+                .unwrap_or(name);
+            assert!(out.contains(&format!("# HELP {} ", family)), "{}", sample);
+            assert!(out.contains(&format!("# TYPE {} ", family)), "{}", sample);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use crate::chooser::ChooserPlugin;
+use crate::config::HFDLInfo;
+use crate::hfdl::Frame;
+use crate::state::SharedState;
+use log::*;
+
+/// Why a listening loop returned
+pub enum Outcome {
+    Exit,
+
+    /// A newer System Table has been assembled and should be loaded before the next session
+    Reload(HFDLInfo),
+
+    /// The control API requested a different chooser
+    SwapChooser(String),
+}
+
+/// Decodes a single line of dumphfdl JSON output. The line itself is kept by the caller for passthrough output
+pub fn decode(msg: &str) -> Option<Frame> {
+    match serde_json::from_str(msg) {
+        Ok(val) => Some(val),
+        Err(e) => {
+            error!("Bad JSON decode: {}", e);
+            None
+        }
+    }
+}
+
+/// Feeds a decoded frame into the shared state and the chooser. Returns whether the chooser elects to change bands
+pub fn process(
+    frame: &Frame,
+    shared_state: &mut SharedState,
+    plugin: &mut dyn ChooserPlugin,
+) -> bool {
+    shared_state.update(frame);
+
+    plugin.on_recv_frame(frame)
+}
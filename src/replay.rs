@@ -0,0 +1,321 @@
+use crate::chooser::{self, ChooserPlugin};
+use crate::config::Config;
+use crate::state::SharedState;
+use crate::{pipeline, shutdown};
+use flate2::read::MultiGzDecoder;
+use log::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task;
+use tokio::time;
+
+/// Lines handed over from the reader thread at once, and batches it may read ahead
+const BATCH_LINES: usize = 256;
+const READ_AHEAD: usize = 16;
+
+fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut file = File::open(path)?;
+
+    let mut magic = [0u8; 2];
+    let size = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    if size == magic.len() && magic == [0x1f, 0x8b] {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Reads the non-empty lines of each file in batches on a blocking thread, so reading and decompressing recordings
+/// doesn't stall the runtime. Files that can't be read are logged and skipped. Reading stops once the receiver is
+/// dropped
+fn read(paths: Vec<PathBuf>) -> mpsc::Receiver<Vec<String>> {
+    let (tx, rx) = mpsc::channel(READ_AHEAD);
+
+    task::spawn_blocking(move || {
+        for path in paths.iter() {
+            info!("REPLAY: reading {:?}", path);
+
+            let reader = match open(path) {
+                Ok(val) => val,
+                Err(e) => {
+                    error!("Unable to open replay file {:?}: {}", path, e);
+                    continue;
+                }
+            };
+
+            let mut batch: Vec<String> = Vec::with_capacity(BATCH_LINES);
+            for line in reader.lines() {
+                match line {
+                    Ok(val) if val.trim().is_empty() => continue,
+                    Ok(val) => batch.push(val),
+                    Err(e) => {
+                        error!("Read error in {:?}: {}", path, e);
+                        break;
+                    }
+                }
+
+                if batch.len() >= BATCH_LINES {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_LINES));
+                    if tx.blocking_send(full).is_err() {
+                        return;
+                    }
+                }
+            }
+
+            if !batch.is_empty() && tx.blocking_send(batch).is_err() {
+                return;
+            }
+        }
+    });
+
+    rx
+}
+
+async fn would_switch(
+    plugin: &mut dyn ChooserPlugin,
+    shared_state: &mut SharedState,
+    recorded_at: Option<f64>,
+) -> bool {
+    let band = match chooser::choose(plugin).await {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Failed to choose a frequency band to listen to: {}", e);
+            return false;
+        }
+    };
+
+    let band_id = shared_state.band_of(&band).unwrap_or(0);
+    shared_state.update_current_band(band_id, &band);
+
+    info!(
+        "REPLAY: [{}] would switch to band {} {:?}",
+        recorded_at
+            .map(|x| format!("{:.6}", x))
+            .unwrap_or("start".to_string()),
+        band_id,
+        band
+    );
+
+    true
+}
+
+/// Feeds recorded dumphfdl JSON output through the frame pipeline, logging chooser decisions instead of acting on them
+pub async fn run(
+    config: &Config,
+    shared_state: &mut SharedState,
+    plugin: &mut dyn ChooserPlugin,
+    name: &str,
+    shutdown_rx: &mut watch::Receiver<bool>,
+) -> io::Result<()> {
+    match config.replay_speed {
+        Some(speed) => info!("Replaying {} file(s) at {}x", config.replay.len(), speed),
+        None => info!("Replaying {} file(s) at max speed", config.replay.len()),
+    }
+
+    if !would_switch(plugin, shared_state, None).await {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let mut last_cleanup = Instant::now();
+
+    let mut anchor: Option<(Instant, f64)> = None;
+    let mut last_recorded: Option<f64> = None;
+
+    let mut frames: u64 = 0;
+    let mut bad_frames: u64 = 0;
+    let mut switches: u64 = 0;
+
+    let mut batches = read(config.replay.clone());
+
+    'lines: loop {
+        let batch = tokio::select! {
+            _ = shutdown::requested(shutdown_rx) => break,
+            batch = batches.recv() => match batch {
+                Some(val) => val,
+                None => break,
+            },
+        };
+
+        for msg in batch {
+            if shutdown::is_requested(shutdown_rx) {
+                break 'lines;
+            }
+
+            let frame = match pipeline::decode(&msg) {
+                Some(val) => val,
+                None => {
+                    bad_frames += 1;
+                    continue;
+                }
+            };
+
+            let recorded_at = frame.hfdl.t.as_secs_f64();
+
+            if let Some(speed) = config.replay_speed {
+                let (wall, recorded) = match anchor {
+                    Some((wall, recorded)) if recorded_at >= recorded => (wall, recorded),
+                    _ => {
+                        anchor = Some((Instant::now(), recorded_at));
+                        (Instant::now(), recorded_at)
+                    }
+                };
+
+                let deadline = wall + Duration::from_secs_f64((recorded_at - recorded) / speed);
+                tokio::select! {
+                    _ = shutdown::requested(shutdown_rx) => break 'lines,
+                    _ = time::sleep_until(deadline.into()) => {},
+                }
+            }
+
+            if let Some(last) = last_recorded {
+                let gap = recorded_at - last;
+                let timeouts = (gap / config.timeout as f64).max(0.0) as u64;
+
+                for _ in 0..timeouts {
+                    if chooser::on_timeout(plugin).await {
+                        info!(
+                            "Been {}s since last message on band. {} elects to change bands.",
+                            config.timeout, name
+                        );
+                        if would_switch(plugin, shared_state, Some(last)).await {
+                            switches += 1;
+                        }
+                        break;
+                    }
+                }
+            }
+
+            last_recorded = Some(recorded_at);
+
+            frames += 1;
+
+            shared_state.stream.on_frame(msg.trim(), &frame);
+
+            if pipeline::process(&frame, shared_state, plugin) {
+                info!("{} elects to change bands after last HFDL frame.", name);
+                if would_switch(plugin, shared_state, Some(recorded_at)).await {
+                    switches += 1;
+                }
+            }
+
+            if last_cleanup.elapsed().as_secs() >= config.ac_timeout {
+                shared_state.clean_up();
+                last_cleanup = Instant::now();
+            }
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    info!(
+        "REPLAY: {} frame(s), {} bad, {} band switch(es) in {:.3}s ({:.0} frames/s)",
+        frames,
+        bad_frames,
+        switches,
+        elapsed,
+        if elapsed > 0.0 {
+            frames as f64 / elapsed
+        } else {
+            0.0
+        }
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chooser::parse_spec;
+    use crate::config;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn frame(sec: u64, freq: u32) -> String {
+        format!(
+            r#"{{"hfdl": {{"t": {{"sec": {}, "usec": 0}}, "freq": {}, "bit_rate": 1800, "sig_level": -20.0}}}}"#,
+            sec, freq
+        )
+    }
+
+    /// A plain and a gzipped recording, holding `lines` frames between them
+    fn recordings(lines: usize) -> (NamedTempFile, NamedTempFile) {
+        let mut plain = NamedTempFile::new().unwrap();
+        for i in 0..lines / 2 {
+            writeln!(plain, "{}\n", frame(i as u64, 8927000)).unwrap();
+        }
+
+        let gzipped = NamedTempFile::new().unwrap();
+        let mut encoder = GzEncoder::new(gzipped.reopen().unwrap(), Compression::default());
+        for i in lines / 2..lines {
+            writeln!(encoder, "{}", frame(i as u64, 8927000)).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        (plain, gzipped)
+    }
+
+    #[tokio::test]
+    async fn reads_plain_and_gzipped_files_in_batches() {
+        let (plain, gzipped) = recordings(BATCH_LINES * 2 + 10);
+
+        let mut batches = read(vec![
+            plain.path().to_path_buf(),
+            PathBuf::from("/nonexistent/replay.json"),
+            gzipped.path().to_path_buf(),
+        ]);
+
+        let mut lines: Vec<String> = vec![];
+        while let Some(batch) = batches.recv().await {
+            assert!(!batch.is_empty() && batch.len() <= BATCH_LINES);
+            lines.extend(batch);
+        }
+
+        assert_eq!(lines.len(), BATCH_LINES * 2 + 10);
+        assert_eq!(lines[0], frame(0, 8927000));
+        assert_eq!(
+            lines[lines.len() - 1],
+            frame(BATCH_LINES as u64 * 2 + 9, 8927000)
+        );
+    }
+
+    #[tokio::test]
+    async fn feeds_every_frame_through_the_pipeline() {
+        let (plain, gzipped) = recordings(600);
+        let config = config::test_config(&[
+            "--replay",
+            plain.path().to_str().unwrap(),
+            "--replay",
+            gzipped.path().to_str().unwrap(),
+            "--replay-speed",
+            "max",
+        ]);
+        let mut shared_state = SharedState::new(&config, "rx");
+
+        let band = config.info.bands.keys().min().unwrap().to_string();
+        let spec = format!("single:band={}", band);
+        let spec = parse_spec(&spec);
+        let mut plugin = chooser::get(&spec, &config, &shared_state.chooser_context()).unwrap();
+
+        let (_tx, mut shutdown_rx) = watch::channel(false);
+        run(
+            &config,
+            &mut shared_state,
+            plugin.as_mut(),
+            "single",
+            &mut shutdown_rx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*shared_state.freq_stats.get(&8927000).unwrap(), 600);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config::Config;
+use crate::sdr::Tuning;
+use log::*;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+pub trait RetuneHook {
+    /// Invoked when the chooser elects to listen to a new band on a dumphfdl we do not own
+    fn retune(&mut self, band: u32, tuning: &Tuning);
+}
+
+/// Only reports chooser decisions
+pub struct AdvisoryRetune;
+
+impl RetuneHook for AdvisoryRetune {
+    fn retune(&mut self, band: u32, tuning: &Tuning) {
+        info!(
+            "ADVISORY: chooser would switch to band {} {:?} (sample_rate={})",
+            band, tuning.freqs, tuning.sample_rate
+        );
+    }
+}
+
+/// Runs an external command with the frequencies (kHz) to listen to as arguments
+pub struct CommandRetune {
+    cmd: PathBuf,
+}
+
+impl RetuneHook for CommandRetune {
+    fn retune(&mut self, band: u32, tuning: &Tuning) {
+        let freqs = &tuning.freqs;
+
+        info!(
+            "RETUNE: running {:?} for band {} {:?} (sample_rate={})",
+            self.cmd, band, freqs, tuning.sample_rate
+        );
+
+        let child = Command::new(&self.cmd)
+            .env("HFDL_BAND", band.to_string())
+            .env("HFDL_SAMPLE_RATE", tuning.sample_rate.to_string())
+            .env("HFDL_CENTER_FREQ", tuning.center_freq.to_string())
+            .env(
+                "HFDL_FREQS",
+                freqs
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            )
+            .args(freqs.iter().map(|x| x.to_string()))
+            .kill_on_drop(false)
+            .spawn();
+
+        let cmd = self.cmd.clone();
+        match child {
+            Ok(mut child) => {
+                tokio::spawn(async move {
+                    match child.wait().await {
+                        Ok(status) if status.success() => {}
+                        Ok(status) => error!("Retune command {:?} failed: {}", cmd, status),
+                        Err(e) => error!("Retune command {:?} failed: {}", cmd, e),
+                    }
+                });
+            }
+            Err(e) => error!("Unable to run retune command {:?}: {}", cmd, e),
+        }
+    }
+}
+
+pub fn get(config: &Config) -> Box<dyn RetuneHook> {
+    match config.retune_cmd {
+        Some(ref cmd) => Box::new(CommandRetune { cmd: cmd.clone() }),
+        None => Box::new(AdvisoryRetune),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn runs_the_retune_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let cmd = dir.path().join("retune.sh");
+        let out = dir.path().join("out");
+
+        fs::write(
+            &cmd,
+            format!(
+                "#!/bin/sh\necho \"$HFDL_BAND $HFDL_SAMPLE_RATE $HFDL_CENTER_FREQ [$HFDL_FREQS] $*\" > {}.tmp\nmv {}.tmp {}\n",
+                out.display(),
+                out.display(),
+                out.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&cmd, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = config::test_config(&[
+            "--attach",
+            "udp://127.0.0.1:5555",
+            "--retune-cmd",
+            cmd.to_str().unwrap(),
+        ]);
+        get(&config).retune(
+            13,
+            &Tuning {
+                sample_rate: 384000,
+                center_freq: 13300,
+                freqs: vec![13270, 13312],
+            },
+        );
+
+        for _ in 0..50 {
+            if let Ok(val) = fs::read_to_string(&out) {
+                assert_eq!(val, "13 384000 13300 [13270 13312] 13270 13312\n");
+                return;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("retune command didn't run");
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Guard (kHz) kept between the outermost channels and the edges of the sampled spectrum
+const EDGE_MARGIN: u32 = 4;
+
+/// Receivers dumphfdl is commonly run with, along with the sample rates they accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdrProfile {
+    AirspyHf,
+    RspDx,
+    RtlSdr,
+    Soapy,
+}
+
+impl SdrProfile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SdrProfile::AirspyHf => "airspyhf",
+            SdrProfile::RspDx => "rspdx",
+            SdrProfile::RtlSdr => "rtlsdr",
+            SdrProfile::Soapy => "soapy",
+        }
+    }
+
+    /// Sample rates (Hz) supported by the device, in ascending order
+    pub fn sample_rates(&self) -> &'static [u32] {
+        match self {
+            SdrProfile::AirspyHf => &[192000, 256000, 384000, 456000, 768000, 912000],
+            SdrProfile::RspDx => &[250000, 384000, 500000, 768000, 1000000, 2000000],
+            SdrProfile::RtlSdr => &[250000, 1024000, 1536000, 1800000, 1920000, 2048000, 2400000],
+            SdrProfile::Soapy => &[256000, 384000, 456000, 768000],
+        }
+    }
+
+    /// Widest span (kHz) the device receives without aliasing or roll-off, whatever the sample rate
+    pub fn max_bandwidth(&self) -> u32 {
+        match self {
+            SdrProfile::AirspyHf => 660,
+            SdrProfile::RspDx => 1536,
+            SdrProfile::RtlSdr => 2000,
+            SdrProfile::Soapy => u32::MAX,
+        }
+    }
+}
+
+impl FromStr for SdrProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "airspyhf" | "airspy" => Ok(SdrProfile::AirspyHf),
+            "rspdx" | "sdrplay" => Ok(SdrProfile::RspDx),
+            "rtlsdr" | "rtl-sdr" => Ok(SdrProfile::RtlSdr),
+            "soapy" | "soapysdr" => Ok(SdrProfile::Soapy),
+            _ => Err(format!(
+                "Unknown SDR profile (airspyhf, rspdx, rtlsdr or soapy): {}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SdrProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How dumphfdl is set up to listen to a band
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    pub sample_rate: u32,
+    pub center_freq: u32,
+    pub freqs: Vec<u32>,
+}
+
+impl Tuning {
+    /// Whether only part of the band's frequencies are listened to
+    pub fn is_partial(&self, band: &[u32]) -> bool {
+        self.freqs.len() < band.len()
+    }
+}
+
+/// A receiver's SDR, optionally limited to a lower sample rate than its profile allows
+#[derive(Debug, Clone, Copy)]
+pub struct Sdr {
+    pub profile: SdrProfile,
+    pub max_sample_rate: Option<u32>,
+}
+
+impl Sdr {
+    pub fn new(profile: SdrProfile, max_sample_rate: Option<u32>) -> Self {
+        Sdr {
+            profile,
+            max_sample_rate,
+        }
+    }
+
+    fn sample_rates(&self) -> impl Iterator<Item = u32> + '_ {
+        self.profile
+            .sample_rates()
+            .iter()
+            .copied()
+            .filter(|x| self.max_sample_rate.is_none_or(|max| *x <= max))
+    }
+
+    fn usable_span(&self, sample_rate: u32) -> u32 {
+        (sample_rate / 1000)
+            .saturating_sub(EDGE_MARGIN)
+            .min(self.profile.max_bandwidth())
+    }
+
+    /// Widest span (kHz) of frequencies that can be listened to at once
+    pub fn max_span(&self) -> Option<u32> {
+        self.sample_rates().map(|x| self.usable_span(x)).max()
+    }
+
+    /// Smallest sample rate covering every frequency (in kHz) of a band
+    pub fn sample_rate(&self, freqs: &[u32]) -> Option<u32> {
+        let span = span(freqs);
+        self.sample_rates().find(|x| span <= self.usable_span(*x))
+    }
+
+    /// Picks the sample rate and centre frequency to listen to a band. When the band is wider than the SDR, the
+    /// contiguous run of frequencies with the most traffic according to `activity` is kept instead
+    pub fn tune(&self, band: &[u32], activity: impl Fn(u32) -> u32) -> Option<Tuning> {
+        let max_span = self.max_span()?;
+
+        let mut freqs = band.to_vec();
+        freqs.sort_unstable();
+        freqs.dedup();
+
+        if span(&freqs) > max_span {
+            let mut best: Option<(u64, usize, usize, usize)> = None;
+            let mut end = 0;
+            for start in 0..freqs.len() {
+                end = end.max(start);
+                while end + 1 < freqs.len() && freqs[end + 1] - freqs[start] <= max_span {
+                    end += 1;
+                }
+
+                let heard: u64 = freqs[start..=end].iter().map(|x| activity(*x) as u64).sum();
+                let score = (heard, end - start + 1);
+                if best.is_none_or(|(h, n, _, _)| score > (h, n)) {
+                    best = Some((heard, end - start + 1, start, end));
+                }
+            }
+
+            let (_, _, start, end) = best?;
+            freqs = freqs[start..=end].to_vec();
+        }
+
+        Some(Tuning {
+            sample_rate: self.sample_rate(&freqs)?,
+            center_freq: (freqs.first()? + freqs.last()?) / 2,
+            freqs,
+        })
+    }
+}
+
+/// Distance (kHz) between the lowest and highest frequencies of a band
+pub fn span(freqs: &[u32]) -> u32 {
+    freqs.iter().max().unwrap_or(&0) - freqs.iter().min().unwrap_or(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BAND_5: [u32; 14] = [
+        5451, 5502, 5508, 5514, 5529, 5538, 5544, 5547, 5583, 5589, 5622, 5652, 5655, 5720,
+    ];
+    const BAND_8: [u32; 15] = [
+        8825, 8834, 8843, 8885, 8886, 8894, 8912, 8921, 8927, 8936, 8939, 8942, 8948, 8957, 8977,
+    ];
+
+    #[test]
+    fn picks_the_smallest_sample_rate_covering_a_band() {
+        let rates = |profile| {
+            let sdr = Sdr::new(profile, None);
+            (sdr.sample_rate(&BAND_8), sdr.sample_rate(&BAND_5))
+        };
+
+        assert_eq!(rates(SdrProfile::AirspyHf), (Some(192000), Some(384000)));
+        assert_eq!(rates(SdrProfile::RspDx), (Some(250000), Some(384000)));
+        assert_eq!(rates(SdrProfile::RtlSdr), (Some(250000), Some(1024000)));
+        assert_eq!(rates(SdrProfile::Soapy), (Some(256000), Some(384000)));
+
+        // Nothing an Airspy HF+ samples at covers more than its usable bandwidth
+        let sdr = Sdr::new(SdrProfile::AirspyHf, None);
+        assert_eq!(sdr.max_span(), Some(660));
+        assert_eq!(sdr.sample_rate(&[10000, 10660]), Some(768000));
+        assert_eq!(sdr.sample_rate(&[10000, 10661]), None);
+    }
+
+    #[test]
+    fn caps_the_sample_rate() {
+        let sdr = Sdr::new(SdrProfile::AirspyHf, Some(256000));
+        assert_eq!(sdr.max_span(), Some(252));
+        assert_eq!(sdr.sample_rate(&BAND_8), Some(192000));
+        assert_eq!(sdr.sample_rate(&BAND_5), None);
+
+        // A cap between two rates keeps the lower one
+        let sdr = Sdr::new(SdrProfile::RtlSdr, Some(1500000));
+        assert_eq!(sdr.sample_rate(&BAND_5), Some(1024000));
+
+        let sdr = Sdr::new(SdrProfile::RspDx, Some(100000));
+        assert_eq!(sdr.max_span(), None);
+        assert_eq!(sdr.tune(&BAND_8, |_| 0), None);
+    }
+
+    #[test]
+    fn tunes_to_the_whole_band_when_it_fits() {
+        let sdr = Sdr::new(SdrProfile::AirspyHf, None);
+        let tuning = sdr.tune(&BAND_5, |_| 0).unwrap();
+
+        assert_eq!(tuning.sample_rate, 384000);
+        assert_eq!(tuning.center_freq, (5451 + 5720) / 2);
+        assert_eq!(tuning.freqs, BAND_5);
+        assert!(!tuning.is_partial(&BAND_5));
+    }
+
+    #[test]
+    fn narrows_a_band_that_does_not_fit() {
+        let sdr = Sdr::new(SdrProfile::AirspyHf, Some(192000));
+
+        // Without any traffic, the run with the most frequencies is kept
+        let tuning = sdr.tune(&BAND_5, |_| 0).unwrap();
+        assert_eq!(tuning.freqs, BAND_5[1..13]);
+        assert_eq!(tuning.sample_rate, 192000);
+        assert_eq!(tuning.center_freq, (5502 + 5655) / 2);
+        assert!(tuning.is_partial(&BAND_5));
+
+        // Otherwise the run where most frames were heard wins, even if it is shorter
+        let tuning = sdr
+            .tune(&BAND_5, |x| if x == 5720 { 100 } else { 1 })
+            .unwrap();
+        assert_eq!(tuning.freqs, BAND_5[5..]);
+        assert_eq!(tuning.center_freq, (5538 + 5720) / 2);
+        assert!(span(&tuning.freqs) <= sdr.max_span().unwrap());
+    }
+}
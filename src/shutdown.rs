@@ -0,0 +1,95 @@
+use log::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Spawns a task listening for SIGINT/SIGTERM. The returned receiver flips to true once a shutdown is requested.
+/// A second signal forces an immediate exit.
+pub fn listen() -> std::io::Result<watch::Receiver<bool>> {
+    let (tx, rx) = watch::channel(false);
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigint.recv() => info!("Received SIGINT, shutting down..."),
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down..."),
+        }
+        let _ = tx.send(true);
+
+        tokio::select! {
+            _ = sigint.recv() => {},
+            _ = sigterm.recv() => {},
+        }
+        warn!("Received second shutdown signal, exiting immediately!");
+        std::process::exit(1);
+    });
+
+    Ok(rx)
+}
+
+/// Resolves once a shutdown has been requested
+pub async fn requested(rx: &mut watch::Receiver<bool>) {
+    while !*rx.borrow_and_update() {
+        if rx.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+pub fn is_requested(rx: &watch::Receiver<bool>) -> bool {
+    *rx.borrow()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn resolves_once_requested() {
+        let (tx, mut rx) = watch::channel(false);
+        assert!(!is_requested(&rx));
+
+        let waiting = tokio::spawn(async move {
+            requested(&mut rx).await;
+            rx
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        tx.send(true).unwrap();
+        let rx = time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(is_requested(&rx));
+    }
+
+    #[tokio::test]
+    async fn never_resolves_once_the_sender_is_gone() {
+        let (tx, mut rx) = watch::channel(false);
+        drop(tx);
+
+        assert!(time::timeout(Duration::from_millis(50), requested(&mut rx))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn sigterm_requests_a_shutdown() {
+        let mut rx = listen().unwrap();
+        assert!(!is_requested(&rx));
+
+        // SAFETY: signals our own process, which handles SIGTERM once listen() returned
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGTERM);
+        }
+
+        time::timeout(Duration::from_secs(1), requested(&mut rx))
+            .await
+            .unwrap();
+        assert!(is_requested(&rx));
+    }
+}
@@ -0,0 +1,593 @@
+use chrono::{offset, DateTime, Utc};
+use log::*;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// Clock period after which a file sink starts a new file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Hourly,
+    Daily,
+}
+
+/// When a file sink starts a new file: whichever of the size limit and the period is reached first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub period: Option<Period>,
+}
+
+/// Destination that decoded frames are re-published to
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputSpec {
+    Stdout,
+    Tcp(String),
+    Udp(String),
+    File(PathBuf, Rotation),
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|x| x.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(val) if val > 0 => val
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("File size too large: {}", s)),
+        _ => Err(format!("Invalid file size: {}", s)),
+    }
+}
+
+impl FromStr for OutputSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stdout" || s == "-" {
+            return Ok(OutputSpec::Stdout);
+        }
+
+        let (scheme, target) = match s.split_once("://") {
+            Some(val) => val,
+            None => return Err(format!("Missing scheme in output: {}", s)),
+        };
+
+        match scheme {
+            "tcp" | "udp" => {
+                if target.rsplit_once(':').is_none() {
+                    return Err(format!("Missing port in output: {}", s));
+                }
+
+                Ok(if scheme == "tcp" {
+                    OutputSpec::Tcp(target.to_string())
+                } else {
+                    OutputSpec::Udp(target.to_string())
+                })
+            }
+            "file" => {
+                let (path, options) = target.split_once('?').unwrap_or((target, ""));
+                if path.is_empty() {
+                    return Err(format!("Missing path in output: {}", s));
+                }
+
+                let mut rotation = Rotation::default();
+                for option in options.split('&').filter(|x| !x.is_empty()) {
+                    let repeated = match option.split_once('=') {
+                        Some(("max-size", size)) => {
+                            rotation.max_size.replace(parse_size(size)?).is_some()
+                        }
+                        Some(("rotate", "hourly")) => {
+                            rotation.period.replace(Period::Hourly).is_some()
+                        }
+                        Some(("rotate", "daily")) => {
+                            rotation.period.replace(Period::Daily).is_some()
+                        }
+                        _ => {
+                            return Err(format!(
+                                "Unsupported file output option '{}': expected max-size=SIZE or rotate=hourly|daily",
+                                option
+                            ))
+                        }
+                    };
+
+                    if repeated {
+                        return Err(format!(
+                            "Repeated file output option '{}' in output: {}",
+                            option, s
+                        ));
+                    }
+                }
+
+                Ok(OutputSpec::File(PathBuf::from(path), rotation))
+            }
+            _ => Err(format!(
+                "Unsupported output scheme '{}': expected stdout, tcp, udp or file",
+                scheme
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputSpec::Stdout => write!(f, "stdout"),
+            OutputSpec::Tcp(address) => write!(f, "tcp://{}", address),
+            OutputSpec::Udp(address) => write!(f, "udp://{}", address),
+            OutputSpec::File(path, _) => write!(f, "file://{}", path.display()),
+        }
+    }
+}
+
+/// Delivery counters of a single sink
+pub struct SinkStats {
+    name: String,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl SinkStats {
+    fn on_drop(&self, reason: &str) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped == 1 || dropped % 1000 == 0 {
+            warn!(
+                "OUTPUT[{}]: {} frame(s) dropped so far ({})",
+                self.name, dropped, reason
+            );
+        }
+    }
+}
+
+impl Serialize for SinkStats {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("SinkStats", 3)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("sent", &self.sent.load(Ordering::Relaxed))?;
+        state.serialize_field("dropped", &self.dropped.load(Ordering::Relaxed))?;
+        state.end()
+    }
+}
+
+pub type OutputStats = Vec<Arc<SinkStats>>;
+
+struct Sink {
+    tx: mpsc::Sender<Arc<str>>,
+    stats: Arc<SinkStats>,
+    task: JoinHandle<()>,
+}
+
+/// Fans decoded frames out to every configured sink. Each sink has its own bounded queue so a slow consumer only
+/// ever loses its own frames and never holds up the read loop
+pub struct Outputs {
+    sinks: Vec<Sink>,
+}
+
+impl Outputs {
+    pub fn new(specs: &[OutputSpec], queue_size: usize) -> Self {
+        let sinks = specs
+            .iter()
+            .map(|spec| {
+                let (tx, rx) = mpsc::channel(queue_size);
+                let stats = Arc::new(SinkStats {
+                    name: spec.to_string(),
+                    sent: AtomicU64::new(0),
+                    dropped: AtomicU64::new(0),
+                });
+
+                info!("OUTPUT: publishing frames to {}", spec);
+
+                let task = match spec.to_owned() {
+                    OutputSpec::Stdout => tokio::spawn(run_stdout(rx, stats.clone())),
+                    OutputSpec::Tcp(address) => tokio::spawn(run_tcp(address, rx, stats.clone())),
+                    OutputSpec::Udp(address) => tokio::spawn(run_udp(address, rx, stats.clone())),
+                    OutputSpec::File(path, rotation) => {
+                        let stats = stats.clone();
+                        tokio::task::spawn_blocking(move || run_file(path, rotation, rx, stats))
+                    }
+                };
+
+                Sink { tx, stats, task }
+            })
+            .collect();
+
+        Outputs { sinks }
+    }
+
+    pub fn stats(&self) -> OutputStats {
+        self.sinks.iter().map(|x| x.stats.clone()).collect()
+    }
+
+    /// Queues a single line of dumphfdl JSON output on every sink without blocking
+    pub fn publish(&self, line: &str) {
+        let line: Arc<str> = Arc::from(line);
+
+        for sink in self.sinks.iter() {
+            match sink.tx.try_send(line.clone()) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => sink.stats.on_drop("queue full"),
+                Err(TrySendError::Closed(_)) => sink.stats.on_drop("sink stopped"),
+            }
+        }
+    }
+
+    /// Closes every queue and waits up to `grace` for the sinks to drain
+    pub async fn shutdown(self, grace: Duration) {
+        let tasks: Vec<(String, JoinHandle<()>)> = self
+            .sinks
+            .into_iter()
+            .map(|x| (x.stats.name.clone(), x.task))
+            .collect();
+
+        let deadline = time::Instant::now() + grace;
+        for (name, mut task) in tasks {
+            if time::timeout_at(deadline, &mut task).await.is_err() {
+                warn!("OUTPUT[{}]: timed out draining queued frames", name);
+                task.abort();
+            }
+        }
+    }
+}
+
+async fn run_stdout(mut rx: mpsc::Receiver<Arc<str>>, stats: Arc<SinkStats>) {
+    let mut stdout = tokio::io::stdout();
+
+    while let Some(line) = rx.recv().await {
+        let results = async {
+            stdout.write_all(line.as_bytes()).await?;
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await
+        };
+
+        match results.await {
+            Ok(_) => {
+                stats.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                error!("OUTPUT[{}]: write failed: {}", stats.name, e);
+                stats.on_drop("write failed");
+            }
+        }
+    }
+}
+
+async fn run_tcp(address: String, mut rx: mpsc::Receiver<Arc<str>>, stats: Arc<SinkStats>) {
+    let mut backoff = RECONNECT_MIN;
+    let mut pending: Option<Arc<str>> = None;
+
+    loop {
+        let mut stream = match TcpStream::connect(&address).await {
+            Ok(val) => {
+                info!("OUTPUT[{}]: connected", stats.name);
+                backoff = RECONNECT_MIN;
+                val
+            }
+            Err(e) => {
+                error!(
+                    "OUTPUT[{}]: unable to connect, retrying in {}s: {}",
+                    stats.name,
+                    backoff.as_secs(),
+                    e
+                );
+
+                // Hold on to a single frame so that the end of the stream is noticed while frames keep queueing
+                // (and eventually dropping) behind it
+                let sleep = time::sleep(backoff);
+                tokio::pin!(sleep);
+                loop {
+                    tokio::select! {
+                        _ = &mut sleep => break,
+                        line = rx.recv(), if pending.is_none() => match line {
+                            Some(val) => pending = Some(val),
+                            None => return,
+                        },
+                    }
+                }
+                backoff = (backoff * 2).min(RECONNECT_MAX);
+                continue;
+            }
+        };
+
+        loop {
+            let line = match pending.take() {
+                Some(val) => val,
+                None => match rx.recv().await {
+                    Some(val) => val,
+                    None => return,
+                },
+            };
+
+            let results = async {
+                stream.write_all(line.as_bytes()).await?;
+                stream.write_all(b"\n").await
+            };
+
+            if let Err(e) = results.await {
+                error!("OUTPUT[{}]: connection lost: {}", stats.name, e);
+                stats.on_drop("connection lost");
+                break;
+            }
+
+            stats.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn run_udp(address: String, mut rx: mpsc::Receiver<Arc<str>>, stats: Arc<SinkStats>) {
+    let socket = async {
+        let target = tokio::net::lookup_host(&address)
+            .await?
+            .next()
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })
+        .await?;
+        socket.connect(target).await?;
+
+        io::Result::Ok(socket)
+    };
+
+    let socket = match socket.await {
+        Ok(val) => val,
+        Err(e) => {
+            error!("OUTPUT[{}]: unable to set up socket: {}", stats.name, e);
+            rx.close();
+            while rx.recv().await.is_some() {
+                stats.on_drop("socket unavailable");
+            }
+            return;
+        }
+    };
+
+    while let Some(line) = rx.recv().await {
+        let mut datagram = String::with_capacity(line.len() + 1);
+        datagram.push_str(&line);
+        datagram.push('\n');
+
+        match socket.send(datagram.as_bytes()).await {
+            Ok(_) => {
+                stats.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                debug!("OUTPUT[{}]: send failed: {}", stats.name, e);
+                stats.on_drop("send failed");
+            }
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    writer: Option<BufWriter<File>>,
+    size: u64,
+    opened_at: DateTime<Utc>,
+}
+
+impl RotatingFile {
+    fn period(&self, at: &DateTime<Utc>) -> String {
+        match self.rotation.period {
+            Some(Period::Hourly) => at.format("%Y%m%d%H").to_string(),
+            Some(Period::Daily) => at.format("%Y%m%d").to_string(),
+            None => String::new(),
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let metadata = file.metadata()?;
+
+        self.size = metadata.len();
+        self.opened_at = match metadata.modified() {
+            Ok(modified) if self.size > 0 => modified.into(),
+            _ => offset::Utc::now(),
+        };
+        self.writer = Some(BufWriter::new(file));
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+
+        let stamp = self.opened_at.format("%Y%m%dT%H%M%SZ");
+        let mut rotated = PathBuf::from(format!("{}.{}", self.path.display(), stamp));
+        let mut suffix = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}.{}", self.path.display(), stamp, suffix));
+            suffix += 1;
+        }
+
+        fs::rename(&self.path, &rotated)?;
+        info!("OUTPUT: rotated {:?} to {:?}", self.path, rotated);
+
+        self.open()
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let needs_rotation = self.size > 0
+            && (self
+                .rotation
+                .max_size
+                .is_some_and(|max| self.size + len > max)
+                || self.period(&self.opened_at) != self.period(&offset::Utc::now()));
+
+        if needs_rotation {
+            self.rotate()?;
+        } else if self.writer.is_none() {
+            self.open()?;
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        self.size += len;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn run_file(
+    path: PathBuf,
+    rotation: Rotation,
+    mut rx: mpsc::Receiver<Arc<str>>,
+    stats: Arc<SinkStats>,
+) {
+    let mut file = RotatingFile {
+        path,
+        rotation,
+        writer: None,
+        size: 0,
+        opened_at: offset::Utc::now(),
+    };
+
+    while let Some(line) = rx.blocking_recv() {
+        let mut next = Some(line);
+
+        // Write out whatever is already queued and flush once the queue runs dry
+        while let Some(line) = next {
+            match file.write(&line) {
+                Ok(_) => {
+                    stats.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    error!("OUTPUT[{}]: write failed: {}", stats.name, e);
+                    stats.on_drop("write failed");
+
+                    // Start over with a fresh handle on the next frame
+                    file.writer = None;
+                }
+            }
+            next = rx.try_recv().ok();
+        }
+
+        if let Err(e) = file.flush() {
+            error!("OUTPUT[{}]: flush failed: {}", stats.name, e);
+        }
+    }
+
+    if let Err(e) = file.flush() {
+        error!("OUTPUT[{}]: final flush failed: {}", stats.name, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert_eq!(parse_size(" 2G "), Ok(2 << 30));
+
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1K").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("18446744073709551615K").is_err());
+        assert!(parse_size("17179869184G").is_err());
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn parses_output_specs() {
+        let parse = |s: &str| s.parse::<OutputSpec>();
+
+        assert_eq!(parse("stdout"), Ok(OutputSpec::Stdout));
+        assert_eq!(parse("-"), Ok(OutputSpec::Stdout));
+        assert_eq!(
+            parse("tcp://localhost:5555"),
+            Ok(OutputSpec::Tcp("localhost:5555".to_string()))
+        );
+        assert_eq!(
+            parse("udp://[::1]:5555"),
+            Ok(OutputSpec::Udp("[::1]:5555".to_string()))
+        );
+        assert_eq!(
+            parse("file:///var/log/hfdl.json"),
+            Ok(OutputSpec::File(
+                PathBuf::from("/var/log/hfdl.json"),
+                Rotation::default()
+            ))
+        );
+        assert_eq!(
+            parse("file://hfdl.json?max-size=1M"),
+            Ok(OutputSpec::File(
+                PathBuf::from("hfdl.json"),
+                Rotation {
+                    max_size: Some(1 << 20),
+                    period: None
+                }
+            ))
+        );
+        assert_eq!(
+            parse("file://hfdl.json?max-size=1M&rotate=daily"),
+            Ok(OutputSpec::File(
+                PathBuf::from("hfdl.json"),
+                Rotation {
+                    max_size: Some(1 << 20),
+                    period: Some(Period::Daily)
+                }
+            ))
+        );
+        assert_eq!(
+            parse("file://hfdl.json?rotate=hourly"),
+            Ok(OutputSpec::File(
+                PathBuf::from("hfdl.json"),
+                Rotation {
+                    max_size: None,
+                    period: Some(Period::Hourly)
+                }
+            ))
+        );
+
+        assert!(parse("localhost:5555").is_err());
+        assert!(parse("tcp://localhost").is_err());
+        assert!(parse("http://localhost:5555").is_err());
+        assert!(parse("file://").is_err());
+        assert!(parse("file://hfdl.json?rotate=weekly").is_err());
+        assert!(parse("file://hfdl.json?max-size=0").is_err());
+        assert!(parse("file://hfdl.json?rotate=hourly&rotate=daily").is_err());
+        assert!(parse("file://hfdl.json?max-size=1M&max-size=2M").is_err());
+    }
+}
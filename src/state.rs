@@ -1,5 +1,12 @@
-use crate::config::{Config, FrequencyBandMap};
+use crate::chooser::{ChooserContext, LearnTable};
+use crate::config::{self, Config, FrequencyBandMap, HFDLInfo};
 use crate::hfdl::Frame;
+use crate::history::History;
+use crate::metrics::Metrics;
+use crate::sdr::{self, Sdr, Tuning};
+use crate::store::{SessionRecord, Store};
+use crate::stream::FrameStream;
+use crate::systable::SystableTracker;
 use actix_web::web::Data;
 use chrono::offset;
 use chrono::{DateTime, Utc};
@@ -7,23 +14,26 @@ use dashmap::DashMap;
 use log::*;
 use serde::ser;
 use serde::ser::SerializeStruct;
-use serde::Serialize;
-use serde_json::Value;
-use std::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
 use std::time::Instant;
 
 pub type FrequencyStats = DashMap<u32, u32>;
 pub type GroundStationStats = DashMap<u8, GroundStationStat>;
 pub type GroundStationMap = DashMap<u8, GroundStationInfo>;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct EntityStat {
     pub msgs: u64,
     pub freqs: Vec<u32>,
     pub last_heard: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GroundStationStat {
     pub name: String,
     pub location: Vec<f64>,
@@ -38,6 +48,7 @@ pub struct GroundStationInfo {
     pub position: Vec<f64>,
     pub assigned_bands: Vec<u32>,
     pub active_bands: Vec<u32>,
+    pub systable_version: Option<u32>,
 
     pub last_heard: Option<Instant>,
 }
@@ -47,10 +58,11 @@ impl ser::Serialize for GroundStationInfo {
     where
         S: ser::Serializer,
     {
-        let mut state = serializer.serialize_struct("GroundStationInfo", 4)?;
+        let mut state = serializer.serialize_struct("GroundStationInfo", 5)?;
         state.serialize_field("name", &self.name)?;
         state.serialize_field("position", &self.position)?;
         state.serialize_field("active_bands", &self.active_bands)?;
+        state.serialize_field("systable_version", &self.systable_version)?;
         state.serialize_field(
             "age_in_secs",
             &self.last_heard.map(|i| i.elapsed().as_secs()),
@@ -61,13 +73,68 @@ impl ser::Serialize for GroundStationInfo {
 
 #[derive(Debug, Serialize)]
 pub struct SessionState {
+    receiver: String,
     band: u32,
     freqs: Vec<u32>,
+
+    chooser: String,
+    pinned_band: Option<u32>,
+    pinned_until: Option<DateTime<Utc>>,
+
+    restarts: u32,
+    consecutive_failures: u32,
+    last_exit: Option<String>,
+    last_exit_status: Option<String>,
+    last_exit_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StderrLog {
+    #[serde(skip)]
+    capacity: usize,
+
+    receiver: String,
+
+    lines: VecDeque<String>,
+}
+
+impl SessionState {
+    pub fn band(&self) -> u32 {
+        self.band
+    }
+}
+
+impl StderrLog {
+    pub fn new(receiver: &str, capacity: usize) -> Self {
+        StderrLog {
+            capacity,
+            receiver: receiver.to_string(),
+            lines: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    pub fn lines(&self) -> &VecDeque<String> {
+        &self.lines
+    }
 }
 
 pub fn gs_info_from_config(config: &Config) -> GroundStationMap {
     let info = GroundStationMap::new();
-    for (_, gs_info) in &config.info.stations {
+    for gs_info in config.info.stations.values() {
         info.insert(
             gs_info.id,
             GroundStationInfo {
@@ -75,6 +142,7 @@ pub fn gs_info_from_config(config: &Config) -> GroundStationMap {
                 position: vec![gs_info.lat, gs_info.lon],
                 assigned_bands: gs_info.assigned.clone(),
                 active_bands: vec![],
+                systable_version: None,
                 last_heard: None,
             },
         );
@@ -83,9 +151,40 @@ pub fn gs_info_from_config(config: &Config) -> GroundStationMap {
     info
 }
 
+/// Band receivers can be tuned to
+#[derive(Debug, Clone, Serialize)]
+pub struct BandInfo {
+    pub band: u32,
+    pub label: Option<String>,
+    pub freqs: Vec<u32>,
+    pub span: u32,
+    pub custom: bool,
+}
+
+/// Bands of the System Table and --bands file, in ascending order
+pub type BandList = RwLock<Vec<BandInfo>>;
+
+pub fn band_list_from_config(config: &Config) -> Vec<BandInfo> {
+    let mut bands: Vec<BandInfo> = config
+        .info
+        .bands
+        .iter()
+        .map(|(band, freqs)| BandInfo {
+            band: *band,
+            label: config.info.custom.get(band).cloned().flatten(),
+            freqs: freqs.clone(),
+            span: sdr::span(freqs),
+            custom: config.info.custom.contains_key(band),
+        })
+        .collect();
+    bands.sort_unstable_by_key(|x| x.band);
+
+    bands
+}
+
 pub fn gs_stats_from_config(config: &Config) -> GroundStationStats {
     let stats = GroundStationStats::new();
-    for (_, gs_info) in &config.info.stations {
+    for gs_info in config.info.stations.values() {
         stats.insert(
             gs_info.id,
             GroundStationStat {
@@ -110,7 +209,7 @@ pub fn gs_stats_from_config(config: &Config) -> GroundStationStats {
 
 pub type PositionReportsByFlightMap = DashMap<String, PositionReports>;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PropagationReport {
     pub id: u8,
     pub name: String,
@@ -118,7 +217,7 @@ pub struct PropagationReport {
     pub bands: Vec<u32>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PositionReport {
     pub position: Vec<f64>,
     pub freq: u32,
@@ -143,46 +242,204 @@ impl ser::Serialize for PositionReports {
     }
 }
 
+/// Session and dumphfdl stderr of one receiver
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceiverState {
+    #[serde(flatten)]
+    pub session: Data<RwLock<SessionState>>,
+
+    #[serde(skip)]
+    pub stderr: Data<RwLock<StderrLog>>,
+}
+
+/// Every receiver managed by this process, in configuration order
+pub type Receivers = RwLock<Vec<ReceiverState>>;
+
+/// State of one receiver. Ground station, flight and frequency state along with metrics are shared with every other
+/// receiver of the process
 pub struct SharedState {
+    pub receiver: String,
     bands: FrequencyBandMap,
     spdu_timeout: u64,
     ac_timeout: u64,
+    state_dump: Option<PathBuf>,
+
+    systable: Data<Mutex<SystableTracker>>,
+    systable_loaded: u64,
 
     pub session: Data<RwLock<SessionState>>,
+    pub session_stderr: Data<RwLock<StderrLog>>,
+    pub receivers: Data<Receivers>,
+    pub band_list: Data<BandList>,
 
     pub gs_info: Data<GroundStationMap>,
     pub gs_stats: Data<GroundStationStats>,
     pub flight_posrpt: Data<PositionReportsByFlightMap>,
     pub freq_stats: Data<FrequencyStats>,
+    pub metrics: Data<Metrics>,
+    pub history: Data<History>,
+    pub learned: Data<LearnTable>,
+    pub stream: Data<FrameStream>,
+    pub store: Option<Data<Store>>,
+
+    current_session: Option<SessionRecord>,
+    switch_reason: Option<&'static str>,
+}
+
+/// Handles every receiver of the process shares
+struct Handles {
+    receivers: Data<Receivers>,
+    band_list: Data<BandList>,
+    gs_info: Data<GroundStationMap>,
+    gs_stats: Data<GroundStationStats>,
+    flight_posrpt: Data<PositionReportsByFlightMap>,
+    freq_stats: Data<FrequencyStats>,
+    metrics: Data<Metrics>,
+    history: Data<History>,
+    learned: Data<LearnTable>,
+    stream: Data<FrameStream>,
+    systable: Data<Mutex<SystableTracker>>,
+    store: Option<Data<Store>>,
 }
 
 impl SharedState {
-    pub fn new(config: &Config) -> Self {
-        SharedState {
+    pub fn new(config: &Config, receiver: &str) -> Self {
+        SharedState::around(
+            config,
+            receiver,
+            Handles {
+                receivers: Data::new(RwLock::new(vec![])),
+                band_list: Data::new(RwLock::new(band_list_from_config(config))),
+                gs_info: Data::new(gs_info_from_config(config)),
+                gs_stats: Data::new(gs_stats_from_config(config)),
+                flight_posrpt: Data::new(PositionReportsByFlightMap::new()),
+                freq_stats: Data::new(FrequencyStats::new()),
+                metrics: Data::new(Metrics::new()),
+                history: Data::new(History::new(
+                    config.history_bucket,
+                    config.history_retention,
+                )),
+                learned: Data::new(LearnTable::new()),
+                stream: Data::new(FrameStream::new()),
+                systable: Data::new(Mutex::new(SystableTracker::new(config))),
+                store: None,
+            },
+        )
+    }
+
+    /// State for another receiver sharing everything but its session with this one
+    pub fn sibling(&self, config: &Config, receiver: &str) -> Self {
+        SharedState::around(
+            config,
+            receiver,
+            Handles {
+                receivers: self.receivers.clone(),
+                band_list: self.band_list.clone(),
+                gs_info: self.gs_info.clone(),
+                gs_stats: self.gs_stats.clone(),
+                flight_posrpt: self.flight_posrpt.clone(),
+                freq_stats: self.freq_stats.clone(),
+                metrics: self.metrics.clone(),
+                history: self.history.clone(),
+                learned: self.learned.clone(),
+                stream: self.stream.clone(),
+                systable: self.systable.clone(),
+                store: self.store.clone(),
+            },
+        )
+    }
+
+    /// Builds a receiver's own session state around shared handles and registers it with the other receivers
+    fn around(config: &Config, receiver: &str, handles: Handles) -> Self {
+        let systable_loaded = handles.systable.lock().unwrap().generation();
+
+        let mut shared_state = SharedState {
+            receiver: receiver.to_string(),
             bands: config.info.bands.clone(),
             spdu_timeout: config.spdu_timeout,
             ac_timeout: config.ac_timeout,
+            state_dump: config.state_dump.clone(),
+
+            systable_loaded,
+            systable: handles.systable,
 
             session: Data::new(RwLock::new(SessionState {
+                receiver: receiver.to_string(),
                 band: 0,
                 freqs: vec![],
+
+                chooser: String::new(),
+                pinned_band: None,
+                pinned_until: None,
+
+                restarts: 0,
+                consecutive_failures: 0,
+                last_exit: None,
+                last_exit_status: None,
+                last_exit_at: None,
             })),
+            session_stderr: Data::new(RwLock::new(StderrLog::new(receiver, config.stderr_lines))),
+            receivers: handles.receivers,
+            band_list: handles.band_list,
+
+            gs_info: handles.gs_info,
+            gs_stats: handles.gs_stats,
+            flight_posrpt: handles.flight_posrpt,
+            freq_stats: handles.freq_stats,
+            metrics: handles.metrics,
+            history: handles.history,
+            learned: handles.learned,
+            stream: handles.stream,
+            store: handles.store,
+
+            current_session: None,
+            switch_reason: None,
+        };
+        shared_state.register();
+
+        shared_state
+    }
 
-            gs_info: Data::new(gs_info_from_config(config)),
-            gs_stats: Data::new(gs_stats_from_config(&config)),
-            flight_posrpt: Data::new(PositionReportsByFlightMap::new()),
-            freq_stats: Data::new(FrequencyStats::new()),
+    /// Handles choosers read shared state through
+    pub fn chooser_context(&self) -> ChooserContext {
+        ChooserContext {
+            gs_info: self.gs_info.clone(),
+            flights: self.flight_posrpt.clone(),
+            learned: self.learned.clone(),
+            freq_stats: self.freq_stats.clone(),
         }
     }
 
+    fn register(&mut self) {
+        self.receivers.write().unwrap().push(ReceiverState {
+            session: self.session.clone(),
+            stderr: self.session_stderr.clone(),
+        });
+    }
+
     pub fn freq_to_band(&self, freq: f64) -> Option<u32> {
-        for (band, freqs) in &self.bands {
-            if freqs.iter().any(|&x| x == (freq as u32)) {
-                return Some(*band);
-            }
+        self.band_of(&[freq as u32])
+    }
+
+    /// Band a set of frequencies (kHz) chosen to listen to belongs to
+    pub fn band_of(&self, freqs: &[u32]) -> Option<u32> {
+        config::band_of(&self.bands, freqs)
+    }
+
+    /// Persists state to `store` from now on, starting from what it held when the process last stopped
+    pub fn restore(&mut self, store: Data<Store>) {
+        if let Err(e) = store.restore(self, self.spdu_timeout, self.ac_timeout) {
+            error!("STORE: failed to restore state: {}", e);
         }
+        self.store = Some(store);
+    }
 
-        None
+    fn save(&self) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.save(self, self.ac_timeout) {
+                error!("STORE: failed to save state: {}", e);
+            }
+        }
     }
 
     pub fn clean_up(&mut self) {
@@ -198,32 +455,201 @@ impl SharedState {
         for stale_flight in stale_flights.iter() {
             self.flight_posrpt.remove(stale_flight);
         }
+
+        self.save();
+    }
+
+    /// System Table assembled from Systable HFNPDUs heard by any receiver since this one last loaded a table
+    pub fn systable_update(&mut self) -> Option<HFDLInfo> {
+        let (generation, info) = self
+            .systable
+            .lock()
+            .unwrap()
+            .update_since(self.systable_loaded)?;
+        self.systable_loaded = generation;
+
+        Some(info)
     }
 
-    pub fn update_current_band(&mut self, freqs: &Vec<u32>) {
-        if !freqs.is_empty() {
-            if let Some(band) = self.freq_to_band(freqs[0] as f64) {
-                let mut session = self.session.write().unwrap();
-                session.band = band;
-                session.freqs = freqs.clone();
+    /// Invoked between sessions after a new System Table has been loaded into the configuration
+    pub fn reload(&mut self, config: &Config) {
+        self.bands = config.info.bands.clone();
+        *self.band_list.write().unwrap() = band_list_from_config(config);
+        self.systable.lock().unwrap().reload(&config.info);
+
+        for station in config.info.stations.values() {
+            match self.gs_info.get_mut(&station.id) {
+                Some(mut entry) => {
+                    entry.name = station.name.clone();
+                    entry.position = vec![station.lat, station.lon];
+                    entry.assigned_bands = station.assigned.clone();
+                }
+                None => {
+                    self.gs_info.insert(
+                        station.id,
+                        GroundStationInfo {
+                            name: station.name.clone(),
+                            position: vec![station.lat, station.lon],
+                            assigned_bands: station.assigned.clone(),
+                            active_bands: vec![],
+                            systable_version: None,
+                            last_heard: None,
+                        },
+                    );
+                }
             }
         }
+
+        for (id, stat) in gs_stats_from_config(config) {
+            self.gs_stats.entry(id).or_insert(stat);
+        }
+
+        info!(
+            "System Table version {:?} loaded: {} ground stations, {} bands",
+            config.info.version,
+            config.info.stations.len(),
+            self.bands.len()
+        );
+    }
+
+    fn update_systable_version(&mut self, gs_id: u8, version: u32) {
+        if let Some(mut entry) = self.gs_info.get_mut(&gs_id) {
+            entry.systable_version = Some(version);
+        }
+
+        self.systable.lock().unwrap().on_announce(gs_id, version);
     }
 
-    pub fn update(&mut self, msg: &Value) {
-        let frame: Frame = match serde_json::from_value(msg.clone()) {
-            Ok(val) => val,
-            Err(e) => {
-                error!("Bad JSON deserialization: not a HFDL Frame: {}", e);
-                return;
+    /// Invoked once before exiting so the final state can be persisted
+    pub fn shutdown(&mut self) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.save_final(self, self.ac_timeout) {
+                error!("STORE: failed to save state: {}", e);
             }
+            store.flush();
+        }
+
+        let path = match self.state_dump {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let snapshot = json!({
+            "written_at": offset::Utc::now(),
+            "sessions": self.receivers,
+            "ground_stations": self.gs_info,
+            "ground_station_stats": self.gs_stats,
+            "freq_stats": self.freq_stats,
+            "flights": self.flight_posrpt,
+        });
+
+        match fs::write(path, snapshot.to_string()) {
+            Ok(_) => info!("State snapshot written to {:?}", path),
+            Err(e) => error!("Failed to write state snapshot to {:?}: {}", path, e),
+        }
+    }
+
+    /// Fits a band to the receiver's SDR, favouring the frequencies most frames were heard on
+    pub fn tune(&self, sdr: &Sdr, band: &[u32]) -> Option<Tuning> {
+        sdr.tune(band, |freq| {
+            self.freq_stats.get(&(freq * 1000)).map(|x| *x).unwrap_or(0)
+        })
+    }
+
+    /// Records the band a session listens to. Unknown bands (0) are ignored
+    pub fn update_current_band(&mut self, band: u32, freqs: &[u32]) {
+        if band != 0 && !freqs.is_empty() {
+            let reason = self.switch_reason.take().unwrap_or("restart");
+            self.end_session(reason);
+
+            let mut session = self.session.write().unwrap();
+            session.band = band;
+            session.freqs = freqs.to_vec();
+
+            self.metrics.on_session_start(&self.receiver, band);
+            self.stream.on_session(&self.receiver, band, freqs);
+
+            self.current_session = Some(SessionRecord {
+                receiver: self.receiver.clone(),
+                band,
+                freqs: freqs.to_vec(),
+                started_at: offset::Utc::now(),
+                frames: 0,
+            });
+        }
+    }
+
+    /// Invoked when the chooser, control API or swarm leader ends a session
+    pub fn on_switch(&mut self, chooser: &str, reason: &'static str) {
+        self.metrics.on_switch(chooser, reason);
+        self.switch_reason = Some(reason);
+    }
+
+    /// Records the current session in the store, if any
+    pub fn end_session(&mut self, reason: &str) {
+        let session = match self.current_session.take() {
+            Some(val) => val,
+            None => return,
         };
 
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.record_session(&session, reason) {
+                error!("STORE: {}", e);
+            }
+        }
+    }
+
+    pub fn update_chooser(&mut self, spec: &str) {
+        self.session.write().unwrap().chooser = spec.to_string();
+    }
+
+    pub fn update_pin(&mut self, pin: Option<(u32, DateTime<Utc>)>) {
+        let mut session = self.session.write().unwrap();
+        session.pinned_band = pin.map(|x| x.0);
+        session.pinned_until = pin.map(|x| x.1);
+    }
+
+    pub fn update_child_exit(
+        &mut self,
+        exit: Option<String>,
+        status: Option<String>,
+        restarts: u32,
+        consecutive_failures: u32,
+    ) {
+        let mut session = self.session.write().unwrap();
+        session.last_exit = exit;
+        session.last_exit_status = status;
+        session.last_exit_at = Some(offset::Utc::now());
+        session.restarts = restarts;
+        session.consecutive_failures = consecutive_failures;
+        self.switch_reason = Some("failure");
+
+        self.metrics
+            .on_child_exit(&self.receiver, restarts, consecutive_failures);
+    }
+
+    pub fn update(&mut self, frame: &Frame) {
         {
             *self.freq_stats.entry(frame.hfdl.freq).or_insert(0) += 1;
         }
+        if let Some(ref mut session) = self.current_session {
+            session.frames += 1;
+        }
+
+        let band = self.freq_to_band((frame.hfdl.freq / 1000) as f64);
+        self.metrics.on_frame(frame, band.unwrap_or(0));
+        self.history.record(
+            band,
+            frame.hfdl.freq / 1000,
+            frame.ground_station(),
+            frame.is_uplink(),
+        );
 
         if let Some(ref spdu) = frame.hfdl.spdu {
+            if let Some(version) = spdu.systable_version {
+                self.update_systable_version(spdu.src.id, version);
+            }
+
             for info in &spdu.gs_status {
                 let bands: Vec<u32> = info
                     .freqs
@@ -231,7 +657,7 @@ impl SharedState {
                     .map(|x| self.freq_to_band(x.freq).unwrap_or(0))
                     .collect();
 
-                if bands.iter().any(|&x| x == 0) {
+                if bands.contains(&0) {
                     error!(
                         "ERROR => found frequency in {:?} that does not match band!",
                         info.freqs
@@ -240,15 +666,29 @@ impl SharedState {
                     return;
                 }
 
-                if let Some(mut entry) = self.gs_info.get_mut(&info.gs.id) {
-                    entry.active_bands = bands;
-                    entry.last_heard = Some(Instant::now());
+                let changed = match self.gs_info.get_mut(&info.gs.id) {
+                    Some(mut entry) => {
+                        let changed = entry.active_bands != bands;
+                        entry.active_bands = bands.clone();
+                        entry.last_heard = Some(Instant::now());
+
+                        changed
+                    }
+                    None => false,
+                };
+
+                if let Some(ref store) = self.store {
+                    if changed {
+                        if let Err(e) = store.record_activity(info.gs.id, &bands) {
+                            warn!("STORE: {}", e);
+                        }
+                    }
                 }
             }
 
             if let Some(mut entry) = self.gs_stats.get_mut(&spdu.src.id) {
                 entry.from.msgs += 1;
-                if !entry.from.freqs.iter().any(|&x| x == frame.hfdl.freq) {
+                if !entry.from.freqs.contains(&frame.hfdl.freq) {
                     entry.from.freqs.push(frame.hfdl.freq);
                 }
                 entry.from.last_heard = Some(offset::Utc::now());
@@ -267,7 +707,7 @@ impl SharedState {
             if lpdu.src.entity_name.is_some() {
                 if let Some(mut entry) = self.gs_stats.get_mut(&lpdu.src.id) {
                     entry.from.msgs += 1;
-                    if !entry.from.freqs.iter().any(|&x| x == frame.hfdl.freq) {
+                    if !entry.from.freqs.contains(&frame.hfdl.freq) {
                         entry.from.freqs.push(frame.hfdl.freq);
                     }
                     entry.from.last_heard = Some(offset::Utc::now());
@@ -293,7 +733,7 @@ impl SharedState {
             if lpdu.dst.entity_name.is_some() {
                 if let Some(mut entry) = self.gs_stats.get_mut(&lpdu.dst.id) {
                     entry.to.msgs += 1;
-                    if !entry.to.freqs.iter().any(|&x| x == frame.hfdl.freq) {
+                    if !entry.to.freqs.contains(&frame.hfdl.freq) {
                         entry.to.freqs.push(frame.hfdl.freq);
                     }
                     entry.to.last_heard = Some(offset::Utc::now());
@@ -301,6 +741,20 @@ impl SharedState {
             }
 
             if let Some(ref hfnpdu) = lpdu.hfnpdu {
+                if let Some(version) = hfnpdu.version {
+                    if lpdu.src.entity_name.is_some() {
+                        self.update_systable_version(lpdu.src.id, version as u32);
+                    }
+
+                    if let Some(ref partial) = hfnpdu.systable_partial {
+                        self.systable.lock().unwrap().on_part(
+                            version as u32,
+                            partial,
+                            hfnpdu.stations.as_deref().unwrap_or(&[]),
+                        );
+                    }
+                }
+
                 if let Some(ref acars) = hfnpdu.acars {
                     info!(
                         "ACARS[{:>6}]({:>6}) {:>4}  {:>14} -> {:<14}  {:1} {:1} {:<2} {:<7} {:<7} {:<3}{:1}",
@@ -338,7 +792,7 @@ impl SharedState {
                                 .iter()
                                 .map(|x| self.freq_to_band(x.freq).unwrap_or(0))
                                 .collect();
-                            if heard_bands.iter().any(|&x| x == 0) {
+                            if heard_bands.contains(&0) {
                                 error!(
                                     "ERROR => found frequency in {:?} that does not match band!",
                                     info.heard_on_freqs
@@ -370,9 +824,8 @@ impl SharedState {
                         }
                     }
 
-                    if hfnpdu.flight_id.is_some() && hfnpdu.pos.is_some() {
+                    if let (Some(callsign), Some(pos)) = (&hfnpdu.flight_id, &hfnpdu.pos) {
                         let flight_id = {
-                            let callsign = hfnpdu.flight_id.as_ref().unwrap();
                             format!(
                                 "{}:GS{:02}{}",
                                 if callsign.is_empty() {
@@ -385,7 +838,6 @@ impl SharedState {
                             )
                         };
 
-                        let pos = hfnpdu.pos.as_ref().unwrap();
                         let pos_is_valid = pos.lat > -90.0
                             && pos.lat < 90.0
                             && pos.lon > -180.0
@@ -455,3 +907,77 @@ impl SharedState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use std::ptr;
+
+    #[test]
+    fn siblings_share_everything_but_their_session() {
+        let config = config::test_config(&[]);
+        let first = SharedState::new(&config, "rx1");
+        let second = first.sibling(&config, "rx2");
+
+        assert!(ptr::eq(first.gs_info.get_ref(), second.gs_info.get_ref()));
+        assert!(ptr::eq(
+            first.flight_posrpt.get_ref(),
+            second.flight_posrpt.get_ref()
+        ));
+        assert!(ptr::eq(first.stream.get_ref(), second.stream.get_ref()));
+        assert!(!ptr::eq(first.session.get_ref(), second.session.get_ref()));
+
+        let receivers = first.receivers.read().unwrap();
+        assert_eq!(receivers.len(), 2);
+        assert_eq!(receivers[1].session.read().unwrap().receiver, "rx2");
+        assert_eq!(second.receivers.read().unwrap().len(), 2);
+    }
+
+    /// Systable HFNPDU frame carrying one part of System Table version 52 with a single ground station
+    fn systable_part(part_num: u8, parts_cnt: u8, gs_id: u8) -> Frame {
+        serde_json::from_value(json!({"hfdl": {
+            "t": {"sec": 0, "usec": 0}, "freq": 8927000, "bit_rate": 1800, "sig_level": -20.0,
+            "lpdu": {
+                "err": false,
+                "src": {"id": gs_id, "type": "Ground station", "name": "Test"},
+                "dst": {"id": 255, "type": "Aircraft"},
+                "type": {"id": 255, "name": "Unnumbered data"},
+                "hfnpdu": {
+                    "err": false,
+                    "type": {"id": 208, "name": "System table"},
+                    "version": 52,
+                    "systable_partial": {"part_num": part_num, "parts_cnt": parts_cnt},
+                    "gs_data": [{
+                        "gs_id": gs_id, "lat": 37.0, "lon": -122.0,
+                        "frequencies": [8927.0, 13276.0],
+                    }],
+                },
+            },
+        }}))
+        .unwrap()
+    }
+
+    #[test]
+    fn siblings_load_a_table_assembled_from_parts_heard_by_any_receiver() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("systable.conf");
+        let config = config::test_config(&["--systable-update", path.to_str().unwrap()]);
+        let mut first = SharedState::new(&config, "rx1");
+        let mut second = first.sibling(&config, "rx2");
+
+        first.update(&systable_part(1, 2, 1));
+        second.update(&systable_part(2, 2, 2));
+
+        for state in [&mut first, &mut second] {
+            let info = state.systable_update().unwrap();
+            assert_eq!(info.version, Some(52));
+            assert_eq!(info.stations.len(), 2);
+            assert!(state.systable_update().is_none());
+        }
+
+        // Receivers started after the table was assembled run with the table they were configured with
+        let mut third = first.sibling(&config, "rx3");
+        assert!(third.systable_update().is_none());
+    }
+}
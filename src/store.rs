@@ -0,0 +1,529 @@
+use crate::chooser::LearnedArms;
+use crate::history::{Counts, History, Series};
+use crate::state::{GroundStationStat, SharedState};
+use crate::swarm::Report;
+use chrono::{offset, DateTime, Utc};
+use log::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS snapshots (
+    name TEXT PRIMARY KEY,
+    written_at TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    receiver TEXT NOT NULL,
+    band INTEGER NOT NULL,
+    freqs TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    ended_at TEXT NOT NULL,
+    frames INTEGER NOT NULL,
+    reason TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS history (
+    at INTEGER NOT NULL,
+    series TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    up INTEGER NOT NULL,
+    down INTEGER NOT NULL,
+    PRIMARY KEY (at, series, id)
+);
+CREATE TABLE IF NOT EXISTS gs_activity (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    heard_at TEXT NOT NULL,
+    gs_id INTEGER NOT NULL,
+    bands TEXT NOT NULL
+);
+";
+
+/// Listening session as recorded in the store once it ends
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    pub receiver: String,
+    pub band: u32,
+    pub freqs: Vec<u32>,
+    pub started_at: DateTime<Utc>,
+    pub frames: u64,
+}
+
+/// Writes queued before ground station activity is dropped, so a slow disk never holds up the read loop
+const WRITE_QUEUE: usize = 1024;
+
+/// Change handed to the writer thread
+enum Write {
+    Snapshots {
+        snapshots: Vec<(&'static str, Value)>,
+        windows: Vec<(i64, Series, u32, Counts)>,
+        oldest: i64,
+    },
+    Session {
+        session: SessionRecord,
+        ended_at: DateTime<Utc>,
+        reason: String,
+    },
+    Activity {
+        heard_at: DateTime<Utc>,
+        gs_id: u8,
+        bands: Vec<u32>,
+    },
+
+    /// Acknowledged once every write queued before it is done
+    Flush(SyncSender<()>),
+}
+
+/// SQLite file holding the latest state snapshot, frame count windows and session and ground station activity
+/// history. Reads happen when restoring, writes are queued to a dedicated thread
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+    writes: SyncSender<Write>,
+
+    /// Start of the oldest frame count window that may have changed since the last save
+    history_saved: Mutex<i64>,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let conn =
+            Connection::open(path).map_err(|e| format!("Unable to open {:?}: {}", path, e))?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| format!("Unable to create tables in {:?}: {}", path, e))?;
+
+        info!("STORE: using {:?}", path);
+
+        let conn = Arc::new(Mutex::new(conn));
+        let (writes, rx) = mpsc::sync_channel(WRITE_QUEUE);
+        let writer = conn.clone();
+        thread::Builder::new()
+            .name("store".to_string())
+            .spawn(move || run_writer(writer, rx))
+            .map_err(|e| format!("Unable to start store writer: {}", e))?;
+
+        Ok(Store {
+            conn,
+            writes,
+            history_saved: Mutex::new(0),
+        })
+    }
+
+    /// Queues a write, waiting for room when the queue is full
+    fn queue(&self, write: Write) -> Result<(), String> {
+        self.writes
+            .send(write)
+            .map_err(|_| "Store writer stopped".to_string())
+    }
+
+    /// Waits until every write queued so far is done
+    pub fn flush(&self) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        if self.queue(Write::Flush(tx)).is_ok() {
+            let _ = rx.recv();
+        }
+    }
+
+    fn read_snapshot(&self, name: &str) -> Result<Option<(DateTime<Utc>, Value)>, String> {
+        let row: Option<(DateTime<Utc>, String)> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT written_at, data FROM snapshots WHERE name = ?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| format!("Unable to read {} snapshot: {}", name, e))?;
+
+        match row {
+            Some((written_at, data)) => serde_json::from_str(&data)
+                .map(|x| Some((written_at, x)))
+                .map_err(|e| format!("Invalid {} snapshot: {}", name, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Queues ground station, flight and frequency state along with the frame count windows changed since the last
+    /// save. Ground station and flight ages are kept so they can be aged again when restored. Never blocks: when the
+    /// queue is full the snapshot is dropped and its frame count windows go out with the next one
+    pub fn save(&self, state: &SharedState, ac_timeout: u64) -> Result<(), String> {
+        self.save_snapshot(state, ac_timeout, |write| {
+            match self.writes.try_send(write) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    Err("Write queue full, dropping snapshot".to_string())
+                }
+                Err(TrySendError::Disconnected(_)) => Err("Store writer stopped".to_string()),
+            }
+        })
+    }
+
+    /// Queues the final state before exiting, waiting for room when the queue is full
+    pub fn save_final(&self, state: &SharedState, ac_timeout: u64) -> Result<(), String> {
+        self.save_snapshot(state, ac_timeout, |write| self.queue(write))
+    }
+
+    fn save_snapshot(
+        &self,
+        state: &SharedState,
+        ac_timeout: u64,
+        queue: impl FnOnce(Write) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let report = Report::collect(
+            &state.gs_info,
+            &state.flight_posrpt,
+            Duration::from_secs(ac_timeout),
+        );
+
+        let snapshots = vec![
+            ("report", serde_json::to_value(&report).unwrap()),
+            (
+                "ground_station_stats",
+                serde_json::to_value(&**state.gs_stats).unwrap(),
+            ),
+            (
+                "freq_stats",
+                serde_json::to_value(&**state.freq_stats).unwrap(),
+            ),
+            (
+                "learned",
+                serde_json::to_value(state.learned.snapshot()).unwrap(),
+            ),
+        ];
+
+        let mut saved = self.history_saved.lock().unwrap();
+        let windows = state.history.since(*saved);
+        let latest = windows.iter().map(|x| x.0).max();
+
+        queue(Write::Snapshots {
+            snapshots,
+            windows,
+            oldest: state.history.oldest(),
+        })?;
+
+        if let Some(latest) = latest {
+            *saved = latest;
+        }
+
+        Ok(())
+    }
+
+    fn restore_history(&self, history: &History) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT at, series, id, up, down FROM history WHERE at >= ?1")
+            .map_err(|e| format!("Unable to read history: {}", e))?;
+        let rows = stmt
+            .query_map(params![history.oldest()], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u32>(2)?,
+                    Counts {
+                        up: row.get(3)?,
+                        down: row.get(4)?,
+                    },
+                ))
+            })
+            .map_err(|e| format!("Unable to read history: {}", e))?;
+
+        for row in rows {
+            let (at, series, id, counts) =
+                row.map_err(|e| format!("Unable to read history: {}", e))?;
+            history.load(at, Series::from_str(&series)?, id, counts);
+        }
+
+        Ok(())
+    }
+
+    /// Loads the last snapshot. Ground station activity older than `spdu_timeout` and flights older than
+    /// `ac_timeout`, counting the time the process was down, are left out
+    pub fn restore(
+        &self,
+        state: &SharedState,
+        spdu_timeout: u64,
+        ac_timeout: u64,
+    ) -> Result<(), String> {
+        if let Some((written_at, data)) = self.read_snapshot("report")? {
+            let mut report: Report = serde_json::from_value(data)
+                .map_err(|e| format!("Invalid report snapshot: {}", e))?;
+            let downtime = (offset::Utc::now() - written_at).num_seconds().max(0) as u64;
+
+            report.ground_stations.retain_mut(|x| {
+                x.age_in_secs += downtime;
+                x.age_in_secs < spdu_timeout
+            });
+            report.flights.retain_mut(|x| {
+                x.age_in_secs += downtime;
+                x.age_in_secs < ac_timeout
+            });
+
+            info!(
+                "STORE: restoring {} ground stations and {} flights from {}",
+                report.ground_stations.len(),
+                report.flights.len(),
+                written_at
+            );
+            report.merge(&state.gs_info, &state.flight_posrpt);
+        }
+
+        if let Some((_, data)) = self.read_snapshot("ground_station_stats")? {
+            let stats: HashMap<u8, GroundStationStat> = serde_json::from_value(data)
+                .map_err(|e| format!("Invalid ground station stats snapshot: {}", e))?;
+            for (id, stat) in stats {
+                if let Some(mut entry) = state.gs_stats.get_mut(&id) {
+                    entry.to = stat.to;
+                    entry.from = stat.from;
+                }
+            }
+        }
+
+        if let Some((_, data)) = self.read_snapshot("freq_stats")? {
+            let stats: HashMap<u32, u32> = serde_json::from_value(data)
+                .map_err(|e| format!("Invalid frequency stats snapshot: {}", e))?;
+            for (freq, count) in stats {
+                *state.freq_stats.entry(freq).or_insert(0) += count;
+            }
+        }
+
+        if let Some((_, data)) = self.read_snapshot("learned")? {
+            let arms: LearnedArms = serde_json::from_value(data)
+                .map_err(|e| format!("Invalid learned chooser snapshot: {}", e))?;
+            state.learned.load(arms);
+        }
+
+        self.restore_history(&state.history)
+    }
+
+    pub fn record_session(&self, session: &SessionRecord, reason: &str) -> Result<(), String> {
+        self.queue(Write::Session {
+            session: session.clone(),
+            ended_at: offset::Utc::now(),
+            reason: reason.to_string(),
+        })
+    }
+
+    /// Appends the bands a ground station announced in a SPDU. Never blocks: the write is dropped when the queue is
+    /// full
+    pub fn record_activity(&self, gs_id: u8, bands: &[u32]) -> Result<(), String> {
+        let write = Write::Activity {
+            heard_at: offset::Utc::now(),
+            gs_id,
+            bands: bands.to_vec(),
+        };
+
+        match self.writes.try_send(write) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                Err("Write queue full, dropping ground station activity".to_string())
+            }
+            Err(TrySendError::Disconnected(_)) => Err("Store writer stopped".to_string()),
+        }
+    }
+}
+
+fn write_snapshots(
+    conn: &mut Connection,
+    snapshots: &[(&'static str, Value)],
+    windows: &[(i64, Series, u32, Counts)],
+    oldest: i64,
+) -> Result<(), String> {
+    let tx = conn
+        .transaction()
+        .map_err(|e| format!("Unable to save state: {}", e))?;
+
+    let written_at = offset::Utc::now();
+    for (name, data) in snapshots.iter() {
+        tx.execute(
+            "INSERT INTO snapshots (name, written_at, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (name) DO UPDATE SET written_at = excluded.written_at, data = excluded.data",
+            params![name, written_at, data.to_string()],
+        )
+        .map_err(|e| format!("Unable to write {} snapshot: {}", name, e))?;
+    }
+
+    for (at, series, id, counts) in windows.iter() {
+        tx.execute(
+            "INSERT INTO history (at, series, id, up, down) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (at, series, id) DO UPDATE SET up = excluded.up, down = excluded.down",
+            params![at, series.name(), id, counts.up, counts.down],
+        )
+        .map_err(|e| format!("Unable to save history: {}", e))?;
+    }
+    tx.execute("DELETE FROM history WHERE at < ?1", params![oldest])
+        .map_err(|e| format!("Unable to prune history: {}", e))?;
+
+    tx.commit()
+        .map_err(|e| format!("Unable to save state: {}", e))
+}
+
+fn write_session(
+    conn: &Connection,
+    session: &SessionRecord,
+    ended_at: &DateTime<Utc>,
+    reason: &str,
+) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO sessions (receiver, band, freqs, started_at, ended_at, frames, reason)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            session.receiver,
+            session.band,
+            serde_json::to_string(&session.freqs).unwrap(),
+            session.started_at,
+            ended_at,
+            session.frames,
+            reason
+        ],
+    )
+    .map(|_| ())
+    .map_err(|e| format!("Unable to record session: {}", e))
+}
+
+fn write_activity(
+    conn: &Connection,
+    heard_at: &DateTime<Utc>,
+    gs_id: u8,
+    bands: &[u32],
+) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO gs_activity (heard_at, gs_id, bands) VALUES (?1, ?2, ?3)",
+        params![heard_at, gs_id, serde_json::to_string(bands).unwrap()],
+    )
+    .map(|_| ())
+    .map_err(|e| format!("Unable to record ground station activity: {}", e))
+}
+
+/// Applies queued writes until the store is dropped
+fn run_writer(conn: Arc<Mutex<Connection>>, rx: Receiver<Write>) {
+    for write in rx {
+        let mut conn = conn.lock().unwrap();
+
+        let result = match write {
+            Write::Snapshots {
+                snapshots,
+                windows,
+                oldest,
+            } => write_snapshots(&mut conn, &snapshots, &windows, oldest),
+            Write::Session {
+                session,
+                ended_at,
+                reason,
+            } => write_session(&conn, &session, &ended_at, &reason),
+            Write::Activity {
+                heard_at,
+                gs_id,
+                bands,
+            } => write_activity(&conn, &heard_at, gs_id, &bands),
+            Write::Flush(ack) => {
+                let _ = ack.send(());
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            error!("STORE: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn count(store: &Store, table: &str) -> i64 {
+        store
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn writes_in_the_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("state.db")).unwrap();
+
+        let session = SessionRecord {
+            receiver: "default".to_string(),
+            band: 13,
+            freqs: vec![13270, 13276],
+            started_at: offset::Utc::now(),
+            frames: 42,
+        };
+        store.record_session(&session, "timeout").unwrap();
+        for gs_id in 1..=3 {
+            store.record_activity(gs_id, &[13, 17]).unwrap();
+        }
+        store.flush();
+
+        assert_eq!(count(&store, "sessions"), 1);
+        assert_eq!(count(&store, "gs_activity"), 3);
+    }
+
+    #[test]
+    fn drops_snapshots_when_the_queue_is_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("state.db")).unwrap();
+        let state = SharedState::new(&config::test_config(&[]), "default");
+        state.history.record(Some(8), 8927, Some(1), true);
+
+        // Hold the writer up until the queue fills. It may take one write off the queue before blocking, so fill
+        // it again once it had the time to
+        let conn = store.conn.lock().unwrap();
+        let mut queued = 0;
+        for _ in 0..2 {
+            while store.record_activity(1, &[8]).is_ok() {
+                queued += 1;
+                assert!(queued <= WRITE_QUEUE + 1);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(
+            store.save(&state, 3600),
+            Err("Write queue full, dropping snapshot".to_string())
+        );
+        drop(conn);
+        store.flush();
+        assert_eq!(count(&store, "history"), 0);
+
+        // The next snapshot carries the frame counts the dropped one held
+        store.save(&state, 3600).unwrap();
+        store.flush();
+        assert_eq!(count(&store, "snapshots"), 4);
+        assert!(count(&store, "history") > 0);
+    }
+
+    #[test]
+    fn restores_saved_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.db");
+        let config = config::test_config(&[]);
+
+        let state = SharedState::new(&config, "default");
+        state.freq_stats.insert(8927, 12);
+        let store = Store::open(&path).unwrap();
+        store.save(&state, 3600).unwrap();
+        store.flush();
+
+        let state = SharedState::new(&config, "default");
+        Store::open(&path)
+            .unwrap()
+            .restore(&state, 900, 3600)
+            .unwrap();
+        assert_eq!(state.freq_stats.get(&8927).as_deref(), Some(&12));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::hfdl::{Frame, FrameKind};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_json::json;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before the slowest ones start missing events
+const CAPACITY: usize = 256;
+
+pub enum Event {
+    Frame {
+        kind: Option<FrameKind>,
+        gs_id: Option<u8>,
+        freq: u32,
+        callsign: Option<String>,
+        raw: String,
+    },
+    Session {
+        receiver: String,
+        band: u32,
+        freqs: Vec<u32>,
+    },
+
+    /// Sent once when shutting down so open streams end
+    Shutdown,
+}
+
+impl Event {
+    fn session_json(receiver: &str, band: u32, freqs: &[u32]) -> String {
+        json!({ "receiver": receiver, "band": band, "freqs": freqs }).to_string()
+    }
+
+    /// Server-Sent Events encoding, None for events that aren't forwarded
+    pub fn to_sse(&self) -> Option<String> {
+        match self {
+            Event::Frame { raw, .. } => Some(format!("event: frame\ndata: {}\n\n", raw)),
+            Event::Session {
+                receiver,
+                band,
+                freqs,
+            } => Some(format!(
+                "event: session\ndata: {}\n\n",
+                Event::session_json(receiver, *band, freqs)
+            )),
+            Event::Shutdown => None,
+        }
+    }
+
+    /// WebSocket encoding: frames are forwarded as is, session changes are wrapped in a "session" object
+    pub fn to_ws(&self) -> Option<String> {
+        match self {
+            Event::Frame { raw, .. } => Some(raw.clone()),
+            Event::Session {
+                receiver,
+                band,
+                freqs,
+            } => Some(format!(
+                "{{\"session\":{}}}",
+                Event::session_json(receiver, *band, freqs)
+            )),
+            Event::Shutdown => None,
+        }
+    }
+}
+
+/// Publishes decoded frames and session changes to streaming web API clients
+pub struct FrameStream {
+    tx: broadcast::Sender<Arc<Event>>,
+}
+
+impl FrameStream {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+
+        FrameStream { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Event>> {
+        self.tx.subscribe()
+    }
+
+    pub fn on_frame(&self, raw: &str, frame: &Frame) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+
+        let _ = self.tx.send(Arc::new(Event::Frame {
+            kind: frame.kind(),
+            gs_id: frame.ground_station(),
+            freq: frame.hfdl.freq / 1000,
+            callsign: frame.callsign().map(|x| x.to_string()),
+            raw: raw.to_string(),
+        }));
+    }
+
+    pub fn on_session(&self, receiver: &str, band: u32, freqs: &[u32]) {
+        let _ = self.tx.send(Arc::new(Event::Session {
+            receiver: receiver.to_string(),
+            band,
+            freqs: freqs.to_vec(),
+        }));
+    }
+
+    pub fn close(&self) {
+        let _ = self.tx.send(Arc::new(Event::Shutdown));
+    }
+}
+
+/// Frame filter built from the query string of a stream request. Each criteria takes a comma separated list of
+/// values, frames must match one value of every criteria given. Session changes are always delivered
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamFilter {
+    #[serde(rename = "kind", deserialize_with = "comma_list")]
+    kinds: Vec<FrameKind>,
+
+    #[serde(rename = "gs", deserialize_with = "comma_list")]
+    gs_ids: Vec<u8>,
+
+    #[serde(rename = "freq", deserialize_with = "comma_list")]
+    freqs: Vec<u32>,
+
+    #[serde(rename = "callsign", deserialize_with = "comma_list")]
+    callsigns: Vec<String>,
+}
+
+fn comma_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    let val = String::deserialize(deserializer)?;
+
+    val.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            x.parse::<T>()
+                .map_err(|_| de::Error::custom(format!("invalid filter value: {}", x)))
+        })
+        .collect()
+}
+
+impl StreamFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        match event {
+            Event::Frame {
+                kind,
+                gs_id,
+                freq,
+                callsign,
+                ..
+            } => {
+                (self.kinds.is_empty() || kind.is_some_and(|x| self.kinds.contains(&x)))
+                    && (self.gs_ids.is_empty() || gs_id.is_some_and(|x| self.gs_ids.contains(&x)))
+                    && (self.freqs.is_empty() || self.freqs.contains(freq))
+                    && (self.callsigns.is_empty()
+                        || callsign.as_ref().is_some_and(|x| {
+                            self.callsigns.iter().any(|y| y.eq_ignore_ascii_case(x))
+                        }))
+            }
+            Event::Session { .. } => true,
+            Event::Shutdown => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Query;
+
+    fn filter(query: &str) -> Result<StreamFilter, String> {
+        Query::<StreamFilter>::from_query(query)
+            .map(|x| x.into_inner())
+            .map_err(|e| e.to_string())
+    }
+
+    fn frame(kind: FrameKind, gs_id: u8, freq: u32, callsign: &str) -> Event {
+        Event::Frame {
+            kind: Some(kind),
+            gs_id: Some(gs_id),
+            freq,
+            callsign: Some(callsign.to_string()),
+            raw: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_comma_separated_lists() {
+        let filter = filter("kind=acars,SPDU&gs=11%2C7&freq=8927&callsign=+ual1+").unwrap();
+
+        assert_eq!(filter.kinds, vec![FrameKind::ACARS, FrameKind::SPDU]);
+        assert_eq!(filter.gs_ids, vec![11, 7]);
+        assert_eq!(filter.freqs, vec![8927]);
+        assert_eq!(filter.callsigns, vec!["ual1"]);
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        assert!(filter("gs=shannon").is_err());
+        assert!(filter("kind=acars,mpdu").is_err());
+        assert!(filter("station=7").is_err());
+    }
+
+    #[test]
+    fn matches_every_given_criteria() {
+        let filter = filter("kind=acars&gs=7,11&callsign=UAL1").unwrap();
+
+        assert!(filter.matches(&frame(FrameKind::ACARS, 7, 8927, "ual1")));
+        assert!(!filter.matches(&frame(FrameKind::ACARS, 1, 8927, "UAL1")));
+        assert!(!filter.matches(&frame(FrameKind::SPDU, 7, 8927, "UAL1")));
+        assert!(!filter.matches(&frame(FrameKind::ACARS, 11, 8927, "DAL2")));
+        assert!(filter.matches(&Event::Session {
+            receiver: "main".to_string(),
+            band: 8,
+            freqs: vec![8927],
+        }));
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert!(filter("")
+            .unwrap()
+            .matches(&frame(FrameKind::LPDU, 1, 5508, "X")));
+    }
+}
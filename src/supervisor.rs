@@ -0,0 +1,195 @@
+use crate::config::Config;
+use rand::rngs::ThreadRng;
+use rand::Rng;
+use std::fmt;
+use std::time::Duration;
+
+/// Reasons a dumphfdl session ended without the chooser asking for it
+#[derive(Debug)]
+pub enum ChildExit {
+    SpawnFailure(String),
+    ImmediateExit,
+    EndOfStream(u64),
+    ReadError(String),
+}
+
+impl fmt::Display for ChildExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChildExit::SpawnFailure(e) => write!(f, "spawn failure: {}", e),
+            ChildExit::ImmediateExit => write!(f, "exited before producing any frames"),
+            ChildExit::EndOfStream(frames) => write!(f, "end of stream after {} frame(s)", frames),
+            ChildExit::ReadError(e) => write!(f, "read error: {}", e),
+        }
+    }
+}
+
+pub struct Supervisor {
+    rng: ThreadRng,
+
+    max_failures: u32,
+    backoff_min: Duration,
+    backoff_max: Duration,
+    backoff_jitter: f64,
+    healthy_period: Duration,
+
+    failures: u32,
+    restarts: u32,
+}
+
+impl Supervisor {
+    pub fn new(config: &Config) -> Self {
+        Supervisor {
+            rng: rand::thread_rng(),
+
+            max_failures: config.max_bad_child_reads,
+            backoff_min: Duration::from_secs(config.restart_backoff_min),
+            backoff_max: Duration::from_secs(config.restart_backoff_max),
+            backoff_jitter: config.restart_backoff_jitter,
+            healthy_period: Duration::from_secs(config.healthy_session),
+
+            failures: 0,
+            restarts: 0,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Human readable "attempt N of M" description of the current failure count
+    pub fn attempt(&self) -> String {
+        if self.max_failures == 0 {
+            format!("attempt {}", self.failures)
+        } else {
+            format!("attempt {} of {}", self.failures, self.max_failures)
+        }
+    }
+
+    /// Invoked when a session ends normally. Sessions that ran long enough reset the failure counter
+    pub fn on_session_end(&mut self, uptime: Duration) {
+        if uptime >= self.healthy_period {
+            self.failures = 0;
+        }
+    }
+
+    /// Invoked when the child fails. Returns the backoff to wait before restarting or None if the
+    /// maximum number of consecutive failures has been reached
+    pub fn on_failure(&mut self, uptime: Duration) -> Option<Duration> {
+        self.on_session_end(uptime);
+        self.failures += 1;
+
+        if self.max_failures > 0 && self.failures >= self.max_failures {
+            return None;
+        }
+
+        self.restarts += 1;
+
+        let exponent = (self.failures - 1).min(16);
+        let backoff = self
+            .backoff_min
+            .saturating_mul(1 << exponent)
+            .min(self.backoff_max);
+
+        let jitter = if self.backoff_jitter > 0.0 {
+            self.rng
+                .gen_range(-self.backoff_jitter..=self.backoff_jitter)
+        } else {
+            0.0
+        };
+
+        Some(backoff.mul_f64((1.0 + jitter).max(0.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn supervisor(extra: &[&str]) -> Supervisor {
+        let mut args = vec![
+            "--restart-backoff-min",
+            "2",
+            "--restart-backoff-max",
+            "60",
+            "--healthy-session",
+            "600",
+        ];
+        args.extend(extra);
+
+        Supervisor::new(&config::test_config(&args))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut supervisor =
+            supervisor(&["--restart-backoff-jitter", "0", "--max-child-failures", "0"]);
+
+        let backoffs: Vec<u64> = (0..8)
+            .map(|_| supervisor.on_failure(Duration::ZERO).unwrap().as_secs())
+            .collect();
+        assert_eq!(backoffs, [2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(supervisor.failures(), 8);
+        assert_eq!(supervisor.restarts(), 8);
+        assert_eq!(supervisor.attempt(), "attempt 8");
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut supervisor = supervisor(&[
+            "--restart-backoff-jitter",
+            "0.25",
+            "--max-child-failures",
+            "0",
+        ]);
+
+        for _ in 0..200 {
+            // Healthy runs keep the base backoff at the minimum
+            let backoff = supervisor.on_failure(Duration::from_secs(600)).unwrap();
+            assert!(backoff >= Duration::from_millis(1500), "{:?}", backoff);
+            assert!(backoff <= Duration::from_millis(2500), "{:?}", backoff);
+        }
+    }
+
+    #[test]
+    fn healthy_runs_reset_failures() {
+        let mut supervisor =
+            supervisor(&["--restart-backoff-jitter", "0", "--max-child-failures", "3"]);
+
+        assert_eq!(
+            supervisor.on_failure(Duration::ZERO),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            supervisor.on_failure(Duration::from_secs(599)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(supervisor.attempt(), "attempt 2 of 3");
+
+        // A short session doesn't reset anything
+        supervisor.on_session_end(Duration::from_secs(10));
+        assert_eq!(supervisor.failures(), 2);
+
+        supervisor.on_session_end(Duration::from_secs(600));
+        assert_eq!(supervisor.failures(), 0);
+        assert_eq!(
+            supervisor.on_failure(Duration::ZERO),
+            Some(Duration::from_secs(2))
+        );
+
+        // Restarts keep counting up across resets
+        assert_eq!(supervisor.restarts(), 3);
+
+        assert_eq!(
+            supervisor.on_failure(Duration::ZERO),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(supervisor.on_failure(Duration::ZERO), None);
+        assert_eq!(supervisor.restarts(), 4);
+    }
+}
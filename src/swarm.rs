@@ -0,0 +1,764 @@
+use crate::config::{Config, FrequencyBandMap, ReceiverSpec, DEFAULT_RECEIVER};
+use crate::control::{Command, ControlHandle};
+use crate::state::{GroundStationMap, PositionReport, PositionReports, PositionReportsByFlightMap};
+use crate::{client, http, shutdown, utils};
+use actix_web::web::{self, Data};
+use chrono::{offset, DateTime, Utc};
+use log::*;
+use reqwest::Client;
+use serde::ser;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time;
+
+/// Heartbeats a member may miss before its lease is reclaimed
+const MISSED_HEARTBEATS: u64 = 3;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Lease {
+    pub band: u32,
+
+    /// Set when the requested band was leased to another member and a free band was handed out instead
+    pub reassigned: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroundStationReport {
+    pub id: u8,
+    pub active_bands: Vec<u32>,
+    pub systable_version: Option<u32>,
+    pub age_in_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlightReport {
+    pub id: String,
+    pub positions: Vec<PositionReport>,
+    pub age_in_secs: u64,
+}
+
+/// State a member shares with the leader on every heartbeat
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Report {
+    #[serde(default)]
+    pub ground_stations: Vec<GroundStationReport>,
+
+    #[serde(default)]
+    pub flights: Vec<FlightReport>,
+}
+
+impl Report {
+    /// Collects ground station state along with the flights heard in the last `since`
+    pub fn collect(
+        gs_info: &GroundStationMap,
+        flights: &PositionReportsByFlightMap,
+        since: Duration,
+    ) -> Self {
+        Report {
+            ground_stations: gs_info
+                .iter()
+                .filter_map(|x| {
+                    x.last_heard.map(|last_heard| GroundStationReport {
+                        id: *x.key(),
+                        active_bands: x.active_bands.clone(),
+                        systable_version: x.systable_version,
+                        age_in_secs: last_heard.elapsed().as_secs(),
+                    })
+                })
+                .collect(),
+            flights: flights
+                .iter()
+                .filter(|x| x.last_heard.elapsed() <= since)
+                .map(|x| FlightReport {
+                    id: x.key().to_string(),
+                    positions: x.positions.clone(),
+                    age_in_secs: x.last_heard.elapsed().as_secs(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Folds a member's state into the leader's, keeping whichever is the most recent
+    pub fn merge(self, gs_info: &GroundStationMap, flights: &PositionReportsByFlightMap) {
+        for report in self.ground_stations {
+            let last_heard =
+                match Instant::now().checked_sub(Duration::from_secs(report.age_in_secs)) {
+                    Some(val) => val,
+                    None => continue,
+                };
+
+            if let Some(mut entry) = gs_info.get_mut(&report.id) {
+                if entry.last_heard.is_none_or(|x| x < last_heard) {
+                    entry.active_bands = report.active_bands;
+                    entry.last_heard = Some(last_heard);
+                }
+                if report.systable_version.is_some() {
+                    entry.systable_version = report.systable_version;
+                }
+            }
+        }
+
+        for report in self.flights {
+            let last_heard = Instant::now()
+                .checked_sub(Duration::from_secs(report.age_in_secs))
+                .unwrap_or_else(Instant::now);
+
+            match flights.get_mut(&report.id) {
+                Some(mut entry) => {
+                    for position in report.positions {
+                        if !entry
+                            .positions
+                            .iter()
+                            .any(|x| x.position == position.position)
+                        {
+                            entry.positions.push(position);
+                        }
+                    }
+                    entry.last_heard = entry.last_heard.max(last_heard);
+                }
+                None => {
+                    flights.insert(
+                        report.id,
+                        PositionReports {
+                            last_heard,
+                            positions: report.positions,
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Member {
+    pub name: String,
+    pub band: Option<u32>,
+    pub joined_at: DateTime<Utc>,
+    pub heartbeats: u64,
+    pub ground_stations: usize,
+    pub flights: usize,
+
+    /// Receivers managed by this process, which never time out
+    local: bool,
+    last_seen: Instant,
+}
+
+impl Member {
+    fn new(name: &str, local: bool) -> Self {
+        Member {
+            name: name.to_string(),
+            band: None,
+            joined_at: offset::Utc::now(),
+            heartbeats: 0,
+            ground_stations: 0,
+            flights: 0,
+
+            local,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+/// Receivers of this process and swarm members, along with the band each one holds a lease on
+pub struct Registry {
+    token: Option<String>,
+    heartbeat: Duration,
+
+    bands: RwLock<FrequencyBandMap>,
+    members: Mutex<HashMap<String, Member>>,
+    next_id: AtomicU64,
+}
+
+impl Registry {
+    pub fn new(config: &Config) -> Self {
+        Registry {
+            token: config.api_token.clone(),
+            heartbeat: Duration::from_secs(config.swarm_heartbeat),
+
+            bands: RwLock::new(config.info.bands.clone()),
+            members: Mutex::new(
+                config
+                    .receivers
+                    .iter()
+                    .map(|x| (x.name.clone(), Member::new(&x.name, true)))
+                    .collect(),
+            ),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Invoked after a new System Table has been loaded so reassignments only hand out known bands
+    pub fn reload(&self, config: &Config) {
+        *self.bands.write().unwrap() = config.info.bands.clone();
+    }
+
+    /// Members must present the API token. Always fails when no token is configured
+    pub fn authorize(&self, token: &str) -> bool {
+        utils::token_matches(&self.token, token)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat
+    }
+
+    fn lease_timeout(&self) -> Duration {
+        self.heartbeat * MISSED_HEARTBEATS as u32
+    }
+
+    pub fn join(&self, name: Option<&str>) -> String {
+        let name: String = name
+            .unwrap_or("member")
+            .chars()
+            .filter(|x| x.is_ascii_alphanumeric() || *x == '-' || *x == '_')
+            .take(32)
+            .collect();
+        let name = if name.is_empty() {
+            "member".to_string()
+        } else {
+            name
+        };
+
+        let mut members = self.members.lock().unwrap();
+        let id = loop {
+            let id = format!("{}-{}", name, self.next_id.fetch_add(1, Ordering::Relaxed));
+            if !members.contains_key(&id) {
+                break id;
+            }
+        };
+
+        info!("SWARM: {} joined", id);
+        members.insert(id.clone(), Member::new(&name, false));
+
+        id
+    }
+
+    pub fn is_member(&self, id: &str) -> bool {
+        self.members.lock().unwrap().contains_key(id)
+    }
+
+    pub fn leave(&self, id: &str) {
+        let mut members = self.members.lock().unwrap();
+        if members.get(id).is_some_and(|x| !x.local) {
+            let member = members.remove(id).unwrap();
+            info!("SWARM: {} left, releasing band {:?}", id, member.band);
+        }
+    }
+
+    /// Leases a band to a member. When another member already holds it, or a band sharing frequencies with it, the
+    /// closest free band is leased instead
+    pub fn lease(&self, id: &str, band: u32) -> Result<Lease, String> {
+        let mut members = self.members.lock().unwrap();
+        if !members.contains_key(id) {
+            return Err(format!("Unknown swarm member: {}", id));
+        }
+
+        let leased: Vec<u32> = members
+            .iter()
+            .filter(|(key, _)| key.as_str() != id)
+            .filter_map(|(_, x)| x.band)
+            .collect();
+        let bands = self.bands.read().unwrap();
+        let is_free = |band: u32| {
+            !leased.contains(&band)
+                && bands.get(&band).is_none_or(|freqs| {
+                    !leased
+                        .iter()
+                        .filter_map(|x| bands.get(x))
+                        .any(|x| x.iter().any(|y| freqs.contains(y)))
+                })
+        };
+        let lowest_freq = |band: u32| {
+            bands
+                .get(&band)
+                .and_then(|x| x.iter().min().copied())
+                .unwrap_or(band * 1000)
+        };
+
+        let lease = if is_free(band) {
+            Lease {
+                band,
+                reassigned: false,
+            }
+        } else {
+            let free = bands
+                .keys()
+                .copied()
+                .filter(|x| is_free(*x))
+                .min_by_key(|x| (lowest_freq(*x).abs_diff(lowest_freq(band)), *x));
+
+            match free {
+                Some(val) => {
+                    info!(
+                        "LEASE: band {} requested by {} is leased, reassigned to band {}",
+                        band, id, val
+                    );
+                    Lease {
+                        band: val,
+                        reassigned: true,
+                    }
+                }
+                None => return Err(format!("Band {} is unavailable and no band is free", band)),
+            }
+        };
+
+        let member = members.get_mut(id).unwrap();
+        if member.band != Some(lease.band) {
+            info!("LEASE: band {} leased to {}", lease.band, id);
+        }
+        member.band = Some(lease.band);
+        member.last_seen = Instant::now();
+
+        Ok(lease)
+    }
+
+    /// Renews a member's lease. Returns the band to move to when the one it is listening to belongs to another member
+    pub fn heartbeat(
+        &self,
+        id: &str,
+        band: Option<u32>,
+        report: &Report,
+    ) -> Result<Option<u32>, String> {
+        let held = {
+            let mut members = self.members.lock().unwrap();
+            let member = match members.get_mut(id) {
+                Some(val) => val,
+                None => return Err(format!("Unknown swarm member: {}", id)),
+            };
+
+            member.last_seen = Instant::now();
+            member.heartbeats += 1;
+            member.ground_stations = report.ground_stations.len();
+            member.flights = report.flights.len();
+
+            member.band
+        };
+
+        match band {
+            Some(band) if held != Some(band) => {
+                let lease = self.lease(id, band)?;
+                Ok(if lease.reassigned {
+                    Some(lease.band)
+                } else {
+                    None
+                })
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Drops members that missed too many heartbeats so their bands can be leased again
+    pub fn reap(&self) {
+        let timeout = self.lease_timeout();
+
+        self.members.lock().unwrap().retain(|id, member| {
+            if member.local || member.last_seen.elapsed() < timeout {
+                return true;
+            }
+
+            warn!(
+                "SWARM: {} missed {} heartbeats, reclaiming band {:?}",
+                id, MISSED_HEARTBEATS, member.band
+            );
+            false
+        });
+    }
+}
+
+impl ser::Serialize for Registry {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let members = self.members.lock().unwrap();
+        let mut ids: Vec<&String> = members.keys().collect();
+        ids.sort();
+
+        let list: Vec<Value> = ids
+            .into_iter()
+            .map(|id| {
+                let member = &members[id];
+                json!({
+                    "id": id,
+                    "name": member.name,
+                    "band": member.band,
+                    "joined_at": member.joined_at,
+                    "heartbeats": member.heartbeats,
+                    "ground_stations": member.ground_stations,
+                    "flights": member.flights,
+                    "local": member.local,
+                    "age_in_secs": if member.local { 0 } else { member.last_seen.elapsed().as_secs() },
+                })
+            })
+            .collect();
+
+        let mut state = serializer.serialize_struct("Registry", 2)?;
+        state.serialize_field("heartbeat_secs", &self.heartbeat.as_secs())?;
+        state.serialize_field("members", &list)?;
+        state.end()
+    }
+}
+
+/// Client side of the swarm protocol, spoken to the leader's web server
+pub struct Follower {
+    leader: String,
+    client: Client,
+    token: Option<String>,
+    name: Option<String>,
+
+    /// Held while joining so concurrent requests don't register the same member twice
+    id: AsyncMutex<Option<String>>,
+    band: AtomicU32,
+    heartbeat: AtomicU64,
+}
+
+impl Follower {
+    /// Member for one receiver. Receivers other than the default one are told apart by their name
+    pub fn new(config: &Config, receiver: &ReceiverSpec) -> Self {
+        let name = match (config.swarm_name.as_deref(), receiver.name.as_str()) {
+            (name, DEFAULT_RECEIVER) => name.map(|x| x.to_string()),
+            (Some(name), receiver) => Some(format!("{}-{}", name, receiver)),
+            (None, receiver) => Some(receiver.to_string()),
+        };
+
+        let leader = if config.host.contains(':') {
+            format!("[{}]:{}", config.host, config.port)
+        } else {
+            format!("{}:{}", config.host, config.port)
+        };
+
+        Follower {
+            leader,
+            client: client::new(),
+            token: config.api_token.clone(),
+            name,
+
+            id: AsyncMutex::new(None),
+            band: AtomicU32::new(0),
+            heartbeat: AtomicU64::new(config.swarm_heartbeat),
+        }
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat.load(Ordering::Relaxed))
+    }
+
+    /// Sends a JSON request to the leader, returning the status code and body
+    async fn post(&self, path: &str, body: &Value) -> Result<(u16, String), String> {
+        let url = format!("http://{}{}", self.leader, path);
+
+        client::post_json(
+            &self.client,
+            &url,
+            self.token.as_deref(),
+            body,
+            REQUEST_TIMEOUT,
+        )
+        .await
+        .map_err(|e| format!("Leader {} unreachable: {}", self.leader, e))
+    }
+
+    async fn join(&self) -> Result<String, String> {
+        let (status, body) = self
+            .post("/api/swarm/join", &json!({ "name": self.name }))
+            .await?;
+        if status != 200 {
+            return Err(format!("Leader refused to join ({}): {}", status, body));
+        }
+
+        let reply: Value =
+            serde_json::from_str(&body).map_err(|e| format!("Invalid join reply: {}", e))?;
+        let id = reply["id"]
+            .as_str()
+            .ok_or("Join reply is missing an ID".to_string())?
+            .to_string();
+        if let Some(secs) = reply["heartbeat_secs"].as_u64().filter(|x| *x > 0) {
+            self.heartbeat.store(secs, Ordering::Relaxed);
+        }
+
+        info!("SWARM: joined {} as {}", self.leader, id);
+
+        Ok(id)
+    }
+
+    /// Sends a member request, joining first when needed and again if the leader no longer knows this member
+    async fn call(&self, path: &str, mut body: Value) -> Result<Value, String> {
+        for attempt in 0..2 {
+            let id = {
+                let mut current = self.id.lock().await;
+                match *current {
+                    Some(ref val) => val.clone(),
+                    None => current.insert(self.join().await?).clone(),
+                }
+            };
+
+            body["id"] = Value::String(id.clone());
+            let (status, reply) = self.post(path, &body).await?;
+
+            match status {
+                200 => {
+                    return serde_json::from_str(&reply)
+                        .map_err(|e| format!("Invalid reply from leader: {}", e))
+                }
+                404 if attempt == 0 => {
+                    warn!("SWARM: leader no longer knows this member, joining again");
+                    let mut current = self.id.lock().await;
+                    if current.as_ref() == Some(&id) {
+                        *current = None;
+                    }
+                }
+                _ => return Err(format!("Leader replied {}: {}", status, reply)),
+            }
+        }
+
+        Err("Unable to join the swarm".to_string())
+    }
+
+    /// Asks the leader for a band. When the leader can't be reached the band is used anyway and claimed on the next
+    /// heartbeat
+    pub async fn lease(&self, band: u32) -> Result<Lease, String> {
+        self.band.store(band, Ordering::Relaxed);
+
+        let reply = self
+            .call("/api/swarm/lease", json!({ "band": band }))
+            .await?;
+        let lease: Lease =
+            serde_json::from_value(reply).map_err(|e| format!("Invalid lease reply: {}", e))?;
+        self.band.store(lease.band, Ordering::Relaxed);
+
+        Ok(lease)
+    }
+
+    /// Renews the current lease and shares state with the leader. Returns a band to move to, if any
+    pub async fn heartbeat(&self, report: Report) -> Result<Option<u32>, String> {
+        let band = self.band.load(Ordering::Relaxed);
+        let mut body = serde_json::to_value(report).unwrap();
+        body["band"] = if band == 0 { Value::Null } else { json!(band) };
+
+        let reply = self.call("/api/swarm/heartbeat", body).await?;
+        let reassign = reply["reassign"].as_u64().map(|x| x as u32);
+        if let Some(band) = reassign {
+            self.band.store(band, Ordering::Relaxed);
+        }
+
+        Ok(reassign)
+    }
+
+    pub async fn leave(&self) {
+        let id = match self.id.lock().await.take() {
+            Some(val) => val,
+            None => return,
+        };
+
+        match self.post("/api/swarm/leave", &json!({ "id": id })).await {
+            Ok(_) => info!("SWARM: left {}", self.leader),
+            Err(e) => warn!("SWARM: failed to leave: {}", e),
+        }
+    }
+}
+
+/// Role this instance plays in a swarm: the leader arbitrates leases locally, members ask the leader
+pub enum Coordinator {
+    /// Leases are arbitrated by this process for one of its receivers
+    Leader {
+        registry: Data<Registry>,
+        receiver: String,
+    },
+    Follower(Arc<Follower>),
+}
+
+impl Coordinator {
+    pub async fn lease(&self, band: u32) -> Result<Lease, String> {
+        match self {
+            Coordinator::Leader { registry, receiver } => registry.lease(receiver, band),
+            Coordinator::Follower(follower) => follower.lease(band).await,
+        }
+    }
+}
+
+/// Endpoints members talk to, only mounted by a swarm leader
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/api/swarm/join", web::post().to(http::api_swarm_join))
+        .route("/api/swarm/lease", web::post().to(http::api_swarm_lease))
+        .route(
+            "/api/swarm/heartbeat",
+            web::post().to(http::api_swarm_heartbeat),
+        )
+        .route("/api/swarm/leave", web::post().to(http::api_swarm_leave));
+}
+
+/// Reclaims the leases of members that stopped sending heartbeats
+pub async fn reap(registry: Data<Registry>, mut shutdown_rx: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = shutdown::requested(&mut shutdown_rx) => break,
+            _ = time::sleep(registry.heartbeat_interval()) => registry.reap(),
+        }
+    }
+}
+
+/// Keeps a member's lease alive until shutdown, shares its state with the leader and moves to another band when told to
+pub async fn heartbeat(
+    follower: Arc<Follower>,
+    receiver: String,
+    gs_info: Data<GroundStationMap>,
+    flights: Data<PositionReportsByFlightMap>,
+    control: Data<ControlHandle>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    loop {
+        let interval = follower.heartbeat_interval();
+        let report = Report::collect(&gs_info, &flights, interval * 2);
+        match follower.heartbeat(report).await {
+            Ok(Some(band)) => {
+                warn!(
+                    "SWARM: current band is leased to another member, moving to band {}",
+                    band
+                );
+                if let Err(e) = control
+                    .submit(Some(&receiver), Command::Reassign(band))
+                    .await
+                {
+                    warn!("SWARM: unable to move to band {}: {}", band, e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("SWARM: heartbeat failed: {}", e),
+        }
+
+        tokio::select! {
+            _ = shutdown::requested(&mut shutdown_rx) => break,
+            _ = time::sleep(follower.heartbeat_interval()) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Args;
+    use crate::config;
+    use actix_web::{App, HttpServer};
+    use clap::Parser;
+
+    fn config(extra: &[&str]) -> Config {
+        let mut args = vec!["--swarm-heartbeat", "1"];
+        args.extend(extra);
+
+        config::test_config(&args)
+    }
+
+    /// Starts a leader on a free port, returning its registry and port
+    fn leader() -> (Data<Registry>, u16) {
+        let registry = Data::new(Registry::new(&config(&[
+            "--swarm-leader",
+            "--api-token",
+            "secret",
+        ])));
+        let gs_info = Data::new(GroundStationMap::new());
+        let flights = Data::new(PositionReportsByFlightMap::new());
+
+        let app_registry = registry.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_registry.clone())
+                .app_data(gs_info.clone())
+                .app_data(flights.clone())
+                .configure(routes)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        tokio::spawn(server.run());
+
+        (registry, port)
+    }
+
+    fn follower(port: u16, token: &str) -> Follower {
+        let config = config(&["--swarm", "--port", &port.to_string(), "--api-token", token]);
+        Follower::new(&config, &config.receivers[0])
+    }
+
+    fn shares_frequencies(registry: &Registry, a: u32, b: u32) -> bool {
+        let bands = registry.bands.read().unwrap();
+        bands[&a].iter().any(|x| bands[&b].contains(x))
+    }
+
+    #[test]
+    fn authorization_fails_closed() {
+        let registry = Registry::new(&config(&[]));
+
+        assert!(!registry.authorize(""));
+        assert!(!registry.authorize("secret"));
+        assert!(Config::from_args(&Args::parse_from([
+            "hfdl-autopilot",
+            "--bin",
+            "testing/dumphfdl",
+            "--sys-table",
+            "testing/systable.json",
+            "--swarm-leader",
+        ]))
+        .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_members_with_the_wrong_token() {
+        let (_, port) = leader();
+
+        let err = follower(port, "guess").lease(13).await.unwrap_err();
+        assert!(err.contains("401"), "{}", err);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn leases_bands_to_members_once() {
+        let (registry, port) = leader();
+        let band = *registry.bands.read().unwrap().keys().min().unwrap();
+
+        let first = follower(port, "secret");
+        let second = follower(port, "secret");
+
+        let lease = first.lease(band).await.unwrap();
+        assert_eq!(lease.band, band);
+        assert!(!lease.reassigned);
+
+        // The band is taken, so the second member is handed a free one instead
+        let lease = second.lease(band).await.unwrap();
+        assert!(lease.reassigned);
+        assert_ne!(lease.band, band);
+        assert!(!shares_frequencies(&registry, band, lease.band));
+
+        // Claiming the band through a heartbeat is refused the same way
+        second.band.store(band, Ordering::Relaxed);
+        let reassign = second.heartbeat(Report::default()).await.unwrap();
+        assert!(reassign.is_some_and(|x| x != band));
+
+        // The first member stops sending heartbeats and loses its lease to the second one
+        for _ in 0..MISSED_HEARTBEATS + 1 {
+            time::sleep(registry.heartbeat_interval()).await;
+            second.heartbeat(Report::default()).await.unwrap();
+            registry.reap();
+        }
+        let first_id = first.id.lock().await.clone().unwrap();
+        assert!(!registry.is_member(&first_id));
+
+        let lease = second.lease(band).await.unwrap();
+        assert_eq!(lease.band, band);
+        assert!(!lease.reassigned);
+
+        // Once back, the first member joins again and is moved off the band
+        let reassign = first.heartbeat(Report::default()).await.unwrap();
+        assert!(reassign.is_some_and(|x| x != band));
+        assert_ne!(*first.id.lock().await, Some(first_id));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::config::{info_from_libconf, Config, HFDLInfo};
+use crate::hfdl::{SystablePartial, SystableStation};
+use crate::libconf::{self, Setting};
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+struct PartialSystable {
+    parts_cnt: u8,
+    parts: BTreeMap<u8, Vec<SystableStation>>,
+}
+
+/// Follows System Table versions announced by ground stations and reassembles newer tables from Systable HFNPDUs.
+/// Shared by every receiver of the process, so parts heard by any of them count towards the same table
+pub struct SystableTracker {
+    configured: Option<u32>,
+    update_path: Option<PathBuf>,
+    names: HashMap<u8, String>,
+
+    alerted: Option<u32>,
+    partials: BTreeMap<u32, PartialSystable>,
+
+    /// Latest assembled table, and how many tables have been assembled so far
+    assembled: Option<HFDLInfo>,
+    generation: u64,
+}
+
+impl SystableTracker {
+    pub fn new(config: &Config) -> Self {
+        let mut tracker = SystableTracker {
+            configured: None,
+            update_path: config.systable_update.clone(),
+            names: HashMap::new(),
+
+            alerted: None,
+            partials: BTreeMap::new(),
+
+            assembled: None,
+            generation: 0,
+        };
+        tracker.reload(&config.info);
+
+        tracker
+    }
+
+    /// Invoked after a receiver loaded a new System Table
+    pub fn reload(&mut self, info: &HFDLInfo) {
+        self.configured = info.version;
+        self.names = info
+            .stations
+            .values()
+            .map(|x| (x.id, x.name.clone()))
+            .collect();
+        self.partials
+            .retain(|&version, _| Some(version) > info.version);
+    }
+
+    /// Invoked when a ground station announces the System Table version it uses
+    pub fn on_announce(&mut self, gs_id: u8, version: u32) {
+        let configured = match self.configured {
+            Some(val) => val,
+            None => return,
+        };
+
+        if version > configured && self.alerted.is_none_or(|x| version > x) {
+            warn!(
+                "SYSTABLE: GS #{} ({}) announces System Table version {} but version {} is configured",
+                gs_id,
+                self.names.get(&gs_id).map_or("unknown", |x| x.as_str()),
+                version,
+                configured
+            );
+            if self.update_path.is_none() {
+                warn!("SYSTABLE: set --systable-update to learn the new table from received Systable HFNPDUs");
+            }
+            self.alerted = Some(version);
+        }
+    }
+
+    /// Invoked for each Systable HFNPDU part. Once every part of a newer version carrying decoded ground station
+    /// data has been received, the updated System Table is written out and queued for loading
+    pub fn on_part(
+        &mut self,
+        version: u32,
+        partial: &SystablePartial,
+        stations: &[SystableStation],
+    ) {
+        if self.update_path.is_none()
+            || self.configured.is_some_and(|x| version <= x)
+            || self
+                .assembled
+                .as_ref()
+                .is_some_and(|x| x.version.is_some_and(|x| version <= x))
+            || stations.is_empty()
+            || partial.parts_cnt == 0
+        {
+            return;
+        }
+
+        let entry = self
+            .partials
+            .entry(version)
+            .or_insert_with(|| PartialSystable {
+                parts_cnt: partial.parts_cnt,
+                parts: BTreeMap::new(),
+            });
+        if entry.parts_cnt != partial.parts_cnt {
+            entry.parts_cnt = partial.parts_cnt;
+            entry.parts.clear();
+        }
+        entry.parts.insert(partial.part_num, stations.to_vec());
+
+        info!(
+            "SYSTABLE: received part {} of version {} ({}/{} parts)",
+            partial,
+            version,
+            entry.parts.len(),
+            entry.parts_cnt
+        );
+
+        if entry.parts.len() < entry.parts_cnt as usize {
+            return;
+        }
+
+        let entry = self.partials.remove(&version).unwrap();
+        self.partials.retain(|&x, _| x > version);
+
+        match self.assemble(version, entry) {
+            Ok(info) => {
+                info!(
+                    "SYSTABLE: version {} written to {:?}, loading it before the next session",
+                    version, info.source
+                );
+                self.assembled = Some(info);
+                self.generation += 1;
+            }
+            Err(e) => error!("SYSTABLE: failed to assemble version {}: {}", version, e),
+        }
+    }
+
+    fn assemble(&self, version: u32, partial: PartialSystable) -> Result<HFDLInfo, String> {
+        let path = self.update_path.as_ref().unwrap();
+
+        let mut stations: BTreeMap<u8, SystableStation> = BTreeMap::new();
+        for station in partial.parts.into_values().flatten() {
+            stations.insert(station.id, station);
+        }
+
+        let root = vec![
+            ("version".to_string(), Setting::Int(version as i64)),
+            (
+                "stations".to_string(),
+                Setting::List(
+                    stations
+                        .into_values()
+                        .map(|x| {
+                            let name = x
+                                .name
+                                .clone()
+                                .or(self.names.get(&x.id).cloned())
+                                .unwrap_or(format!("GS #{}", x.id));
+
+                            Setting::Group(vec![
+                                ("id".to_string(), Setting::Int(x.id as i64)),
+                                ("name".to_string(), Setting::Str(name)),
+                                ("lat".to_string(), Setting::Float(x.lat)),
+                                ("lon".to_string(), Setting::Float(x.lon)),
+                                (
+                                    "frequencies".to_string(),
+                                    Setting::List(
+                                        x.freqs.iter().map(|&f| Setting::Float(f)).collect(),
+                                    ),
+                                ),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ];
+
+        let contents = libconf::to_string(&root);
+        let mut info = info_from_libconf(&contents)?;
+
+        fs::write(path, &contents).map_err(|e| format!("Unable to write {:?}: {}", path, e))?;
+        info.source = Some(path.clone());
+
+        Ok(info)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the latest assembled System Table along with its generation, when newer than `generation`
+    pub fn update_since(&self, generation: u64) -> Option<(u64, HFDLInfo)> {
+        if self.generation <= generation {
+            return None;
+        }
+
+        self.assembled.clone().map(|x| (self.generation, x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    /// Tracker writing assembled tables into `dir`, configured with version 51 of the test System Table
+    fn tracker(dir: &tempfile::TempDir) -> SystableTracker {
+        let path = dir.path().join("systable.conf");
+        SystableTracker::new(&config::test_config(&[
+            "--systable-update",
+            path.to_str().unwrap(),
+        ]))
+    }
+
+    fn part(part_num: u8, parts_cnt: u8) -> SystablePartial {
+        SystablePartial {
+            part_num,
+            parts_cnt,
+        }
+    }
+
+    fn station(id: u8, freqs: &[f64]) -> Vec<SystableStation> {
+        vec![SystableStation {
+            id,
+            name: None,
+            lat: 10.0 * id as f64,
+            lon: -10.0 * id as f64,
+            freqs: freqs.to_vec(),
+        }]
+    }
+
+    #[test]
+    fn reassembles_parts_in_any_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tracker = tracker(&dir);
+
+        tracker.on_part(52, &part(3, 3), &station(3, &[8977.0]));
+        tracker.on_part(52, &part(1, 3), &station(1, &[8927.0, 13276.0]));
+        assert!(tracker.update_since(0).is_none());
+
+        tracker.on_part(52, &part(2, 3), &station(200, &[8936.0]));
+        let (generation, info) = tracker.update_since(0).unwrap();
+        assert_eq!(generation, 1);
+        assert!(tracker.update_since(generation).is_none());
+
+        assert_eq!(info.version, Some(52));
+        assert_eq!(info.stations.len(), 3);
+        assert_eq!(info.stations["San Francisco, California"].id, 1);
+        assert_eq!(info.stations["GS #200"].lat, 2000.0);
+
+        // What was written out loads back as the same table
+        let written = fs::read_to_string(info.source.as_ref().unwrap()).unwrap();
+        let loaded = info_from_libconf(&written).unwrap();
+        assert_eq!(loaded.version, Some(52));
+        assert_eq!(loaded.bands, info.bands);
+    }
+
+    #[test]
+    fn ignores_duplicate_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tracker = tracker(&dir);
+
+        tracker.on_part(52, &part(1, 2), &station(1, &[8927.0]));
+        tracker.on_part(52, &part(1, 2), &station(1, &[8927.0]));
+        assert!(tracker.update_since(0).is_none());
+
+        tracker.on_part(52, &part(2, 2), &station(2, &[8936.0]));
+        assert_eq!(tracker.generation(), 1);
+
+        // Parts of the assembled version keep being broadcast until every receiver loaded it
+        tracker.on_part(52, &part(1, 2), &station(1, &[8927.0]));
+        tracker.on_part(52, &part(2, 2), &station(2, &[8936.0]));
+        assert_eq!(tracker.generation(), 1);
+    }
+
+    #[test]
+    fn only_assembles_newer_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tracker = tracker(&dir);
+
+        // Not newer than the configured version
+        tracker.on_part(51, &part(1, 1), &station(1, &[8927.0]));
+        // No decoded ground station data, or no parts at all
+        tracker.on_part(52, &part(1, 1), &[]);
+        tracker.on_part(52, &part(1, 0), &station(1, &[8927.0]));
+        assert_eq!(tracker.generation(), 0);
+
+        // The part count changed, so parts of the earlier broadcast are dropped
+        tracker.on_part(52, &part(1, 2), &station(1, &[8927.0]));
+        tracker.on_part(52, &part(2, 3), &station(2, &[8936.0]));
+        tracker.on_part(52, &part(3, 3), &station(3, &[8977.0]));
+        assert_eq!(tracker.generation(), 0);
+
+        // Loading a newer table drops the parts of older versions
+        let mut info = tracker
+            .update_since(0)
+            .map(|x| x.1)
+            .unwrap_or(config::test_config(&[]).info);
+        info.version = Some(52);
+        tracker.reload(&info);
+        tracker.on_part(52, &part(1, 3), &station(1, &[8927.0]));
+        assert_eq!(tracker.generation(), 0);
+
+        // Without somewhere to write tables to, nothing is assembled
+        let mut tracker = SystableTracker::new(&config::test_config(&[]));
+        tracker.on_part(52, &part(1, 1), &station(1, &[8927.0]));
+        assert_eq!(tracker.generation(), 0);
+    }
+}
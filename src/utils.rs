@@ -1,6 +1,6 @@
 use chrono::Timelike;
 
-pub fn parse_time(raw_time: &Vec<&str>) -> Option<(u8, u8)> {
+pub fn parse_time(raw_time: &[&str]) -> Option<(u8, u8)> {
     let h: u8 = raw_time[0].parse().unwrap_or(255);
     let m: u8 = raw_time[1].parse().unwrap_or(255);
 
@@ -11,7 +11,7 @@ pub fn parse_time(raw_time: &Vec<&str>) -> Option<(u8, u8)> {
     Some((h, m))
 }
 
-pub fn get_band(triggers: &Vec<(u8, u8, u32)>) -> Option<u32> {
+pub fn get_band(triggers: &[(u8, u8, u32)]) -> Option<u32> {
     let current_time = chrono::offset::Local::now();
 
     for (h, m, band) in triggers.iter() {
@@ -22,3 +22,18 @@ pub fn get_band(triggers: &Vec<(u8, u8, u32)>) -> Option<u32> {
 
     triggers.last().map(|x| x.2)
 }
+
+/// Compares a secret against a configured value in constant time. Never matches when nothing is configured
+pub fn token_matches(expected: &Option<String>, token: &str) -> bool {
+    let expected = match expected {
+        Some(ref val) => val.as_bytes(),
+        None => return false,
+    };
+
+    expected.len() == token.len()
+        && expected
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
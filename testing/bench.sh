@@ -0,0 +1,16 @@
+#!/bin/sh
+# Measures frame processing throughput by replaying a recorded dumphfdl capture as fast as possible
+#
+# usage: testing/bench.sh [CAPTURE] [RUNS] [CHOOSER]
+
+CAPTURE=${1:-testing/capture.json.gz}
+RUNS=${2:-3}
+CHOOSER=${3:-tracker:target=Albrook}
+BIN=target/release/hfdl-autopilot
+
+cargo build --release --quiet || exit 1
+
+for i in $(seq "$RUNS"); do
+    "$BIN" --sys-table testing/systable.json --replay "$CAPTURE" --replay-speed max --chooser "$CHOOSER" -v 2>&1 >/dev/null \
+        | grep -o "REPLAY: [0-9]* frame.*"
+done
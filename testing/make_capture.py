@@ -0,0 +1,142 @@
+#!/usr/bin/env python3
+# Writes a synthetic dumphfdl JSON capture for replay benchmarks, using the ground stations and frequencies of
+# testing/systable.json. The output is deterministic for a given seed
+#
+# usage: testing/make_capture.py [--frames COUNT] [--seed SEED] OUTPUT.json.gz
+
+import argparse
+import gzip
+import json
+import random
+import re
+
+SYSTABLE = json.load(open("testing/systable.json"))
+STATIONS = SYSTABLE["stations"]
+FREQS = {
+    int(id): [float(x) for x in freqs.split(",")]
+    for id, freqs in re.findall(r"id = (\d+);[^}]*?frequencies = \(([^)]*)\)", SYSTABLE["raw"])
+}
+
+
+def entity_gs(gs):
+    return {"type": "Ground station", "id": gs["id"], "name": gs["name"]}
+
+
+def entity_ac(ac_id, icao):
+    return {"type": "Aircraft", "id": ac_id, "ac_info": {"icao": icao}}
+
+
+def spdu(rng, gs):
+    others = rng.sample(list(STATIONS.values()), 3)
+    return {
+        "err": False,
+        "src": entity_gs(gs),
+        "spdu_version": 0,
+        "rls": True,
+        "iso": False,
+        "change_note": "None",
+        "frame_index": rng.randrange(0, 256),
+        "frame_offset": 0,
+        "min_priority": 0,
+        "systable_version": 51,
+        "gs_status": [
+            {
+                "gs": entity_gs(x),
+                "utc_sync": True,
+                "freqs": [{"id": 0, "freq": rng.choice(FREQS[x["id"]])}],
+            }
+            for x in [gs] + others
+        ],
+    }
+
+
+def lpdu(rng, gs, flight):
+    ac_id, icao, callsign, lat, lon = flight
+    uplink = rng.random() < 0.4
+    hfnpdu = None
+    kind = rng.random()
+
+    if kind < 0.45:
+        hfnpdu = {
+            "err": False,
+            "type": {"id": 209, "name": "Performance data"},
+            "flight_id": callsign,
+            "pos": {"lat": lat, "lon": lon},
+            "frequency": {"id": rng.randrange(0, 6), "freq": None},
+        }
+    elif kind < 0.8:
+        hfnpdu = {
+            "err": False,
+            "type": {"id": 255, "name": "Enveloped data"},
+            "acars": {
+                "err": False,
+                "crc_ok": True,
+                "more": False,
+                "reg": ".N{}".format(rng.randrange(100, 999)),
+                "mode": "2",
+                "label": rng.choice(["H1", "_d", "5U", "SA", "Q0"]),
+                "blk_id": "5",
+                "ack": "!",
+                "flight": callsign,
+                "msg_num": "M{:02}".format(rng.randrange(0, 100)),
+                "msg_num_seq": "A",
+                "msg_text": " ".join("{:X}".format(rng.getrandbits(32)) for _ in range(rng.randrange(2, 16))),
+            },
+        }
+
+    src, dst = (entity_gs(gs), entity_ac(ac_id, icao)) if uplink else (entity_ac(ac_id, icao), entity_gs(gs))
+    frame = {
+        "err": False,
+        "src": src,
+        "dst": dst,
+        "type": {"id": 48 if hfnpdu else 16, "name": "Unnumbered data" if hfnpdu else "Unnumbered ack"},
+        "ac_info": {"icao": icao},
+    }
+    if hfnpdu:
+        frame["hfnpdu"] = hfnpdu
+
+    return frame
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--frames", type=int, default=20000)
+    parser.add_argument("--seed", type=int, default=1)
+    parser.add_argument("output")
+    args = parser.parse_args()
+
+    rng = random.Random(args.seed)
+    stations = list(STATIONS.values())
+    flights = [
+        (i + 1, "{:06X}".format(rng.getrandbits(24)), "XX{:04}".format(rng.randrange(1, 9999)),
+         round(rng.uniform(-60, 70), 4), round(rng.uniform(-180, 180), 4))
+        for i in range(200)
+    ]
+
+    t = 1700000000.0
+    with gzip.open(args.output, "wt", compresslevel=9) as out:
+        for _ in range(args.frames):
+            t += rng.expovariate(1 / 0.5)
+            gs = rng.choice(stations)
+            freq = rng.choice(FREQS[gs["id"]])
+
+            frame = {
+                "hfdl": {
+                    "app": {"name": "dumphfdl", "ver": "1.4.1"},
+                    "station": "bench",
+                    "t": {"sec": int(t), "usec": int((t % 1) * 1000000)},
+                    "freq": int(freq * 1000),
+                    "bit_rate": rng.choice([300, 600, 1200, 1800]),
+                    "sig_level": round(rng.uniform(-30, -5), 6),
+                    "noise_level": round(rng.uniform(-50, -40), 6),
+                    "freq_skew": round(rng.uniform(-5, 5), 6),
+                    "slot": rng.choice(["S", "D"]),
+                }
+            }
+            if rng.random() < 0.3:
+                frame["hfdl"]["spdu"] = spdu(rng, gs)
+            else:
+                frame["hfdl"]["lpdu"] = lpdu(rng, gs, rng.choice(flights))
+
+            out.write(json.dumps(frame))
+            out.write("\n")
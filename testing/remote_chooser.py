@@ -0,0 +1,80 @@
+#!/usr/bin/env python3
+# Stub peer for the remote chooser. Answers JSON-RPC choose/on_timeout requests over HTTP or a Unix socket
+#
+# usage: testing/remote_chooser.py [--port PORT | --socket PATH] [--band BAND] [--delay SECONDS] [--fail]
+
+import argparse
+import http.server
+import json
+import os
+import socketserver
+import sys
+import time
+
+args = None
+
+
+def reply(request):
+    params = request.get("params", {})
+    session = params.get("session", {})
+    sys.stderr.write("{} band={} frames={} ground_stations={}\n".format(
+        request.get("method"), session.get("band"), session.get("frames"), len(params.get("ground_stations", []))))
+
+    time.sleep(args.delay)
+
+    if args.fail:
+        return {"jsonrpc": "2.0", "id": request.get("id"), "error": {"code": -32000, "message": "stub failure"}}
+
+    if request.get("method") == "choose":
+        if args.band:
+            band = args.band
+        else:
+            bands = sorted(int(x) for x in params.get("bands", {}))
+            current = session.get("band") or 0
+            band = next((x for x in bands if x > current), bands[0])
+        result = {"band": band, "reason": "stub"}
+    else:
+        result = {"switch": True}
+
+    return {"jsonrpc": "2.0", "id": request.get("id"), "result": result}
+
+
+class HTTPHandler(http.server.BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers["Content-Length"]))
+        data = json.dumps(reply(json.loads(body))).encode("utf-8")
+
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def log_message(self, format, *args):
+        pass
+
+
+class UnixHandler(socketserver.StreamRequestHandler):
+    def handle(self):
+        line = self.rfile.readline()
+        if len(line) > 0:
+            self.wfile.write("{}\n".format(json.dumps(reply(json.loads(line)))).encode("utf-8"))
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--port", type=int, default=7280)
+    parser.add_argument("--socket")
+    parser.add_argument("--band", type=int)
+    parser.add_argument("--delay", type=float, default=0.0)
+    parser.add_argument("--fail", action="store_true")
+    args = parser.parse_args()
+
+    if args.socket:
+        if os.path.exists(args.socket):
+            os.unlink(args.socket)
+        with socketserver.UnixStreamServer(args.socket, UnixHandler) as server:
+            server.serve_forever()
+    else:
+        with http.server.HTTPServer(("localhost", args.port), HTTPHandler) as server:
+            server.serve_forever()